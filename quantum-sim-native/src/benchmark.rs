@@ -0,0 +1,51 @@
+use std::time::Instant;
+
+use crate::circuit::{apply_gate_op, random_circuit};
+use crate::error::QuantumError;
+use crate::quantum_state::QuantumState;
+
+pub const BENCHMARK_SHOTS: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct BenchmarkReport {
+    pub setup_ms: f64,
+    pub circuit_ms: f64,
+    pub measure_ms: f64,
+    pub gates_per_second: f64,
+}
+
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+/// Time state allocation, a seeded random circuit and a fixed-shot measurement.
+pub fn run_benchmark(num_qubits: usize, gate_count: usize, seed: u64) -> Result<BenchmarkReport, QuantumError> {
+    let circuit = random_circuit(num_qubits, gate_count, seed);
+    
+    let start = Instant::now();
+    let mut state = QuantumState::new(num_qubits)?;
+    let setup_ms = elapsed_ms(start);
+    
+    let start = Instant::now();
+    for op in &circuit {
+        apply_gate_op(&mut state, op).expect("random circuits stay within the register");
+    }
+    let circuit_ms = elapsed_ms(start);
+    
+    let start = Instant::now();
+    state.measure(BENCHMARK_SHOTS);
+    let measure_ms = elapsed_ms(start);
+    
+    let gates_per_second = if circuit_ms > 0.0 {
+        gate_count as f64 / (circuit_ms / 1000.0)
+    } else {
+        0.0
+    };
+    
+    Ok(BenchmarkReport {
+        setup_ms,
+        circuit_ms,
+        measure_ms,
+        gates_per_second,
+    })
+}
@@ -0,0 +1,949 @@
+use num_complex::Complex64;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::sync::{LazyLock, Mutex};
+
+use crate::error::QuantumError;
+use crate::gates::{dagger, fuse_single_qubit, is_unitary, Gates};
+use crate::quantum_state::{check_probability, QuantumState};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GateOp {
+    pub name: String,
+    pub qubits: Vec<usize>,
+    pub params: Vec<f64>,
+}
+
+impl GateOp {
+    pub fn new(name: &str, qubits: Vec<usize>, params: Vec<f64>) -> Self {
+        Self {
+            name: name.to_string(),
+            qubits,
+            params,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum GateMatrix {
+    Single([Complex64; 4]),
+    Two([Complex64; 16]),
+    Three(Box<[Complex64; 64]>),
+}
+
+impl GateMatrix {
+    pub fn arity(&self) -> usize {
+        match self {
+            GateMatrix::Single(_) => 1,
+            GateMatrix::Two(_) => 2,
+            GateMatrix::Three(_) => 3,
+        }
+    }
+    
+    /// The adjoint gate, undoing this one.
+    pub fn dagger(&self) -> GateMatrix {
+        match self {
+            GateMatrix::Single(gate) => GateMatrix::Single(dagger(gate)),
+            GateMatrix::Two(gate) => GateMatrix::Two(dagger(gate)),
+            GateMatrix::Three(gate) => GateMatrix::Three(Box::new(dagger(gate))),
+        }
+    }
+}
+
+/// A gate matrix paired with the qubits it acts on, ready to apply.
+pub type ResolvedGate = (GateMatrix, Vec<usize>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GateInfo {
+    pub name: &'static str,
+    pub num_qubits: usize,
+    pub num_params: usize,
+    pub is_diagonal: bool,
+}
+
+impl GateInfo {
+    const fn new(name: &'static str, num_qubits: usize, num_params: usize, is_diagonal: bool) -> Self {
+        Self { name, num_qubits, num_params, is_diagonal }
+    }
+    
+    pub fn is_parameterized(&self) -> bool {
+        self.num_params > 0
+    }
+}
+
+/// Metadata for every built-in gate; `gate_matrix` must resolve each entry.
+/// An entry's position is its opcode in the binary circuit encoding, so new
+/// gates are appended rather than inserted.
+pub const BUILTIN_GATES: &[GateInfo] = &[
+    GateInfo::new("H", 1, 0, false),
+    GateInfo::new("X", 1, 0, false),
+    GateInfo::new("Y", 1, 0, false),
+    GateInfo::new("Z", 1, 0, true),
+    GateInfo::new("S", 1, 0, true),
+    GateInfo::new("T", 1, 0, true),
+    GateInfo::new("RX", 1, 1, false),
+    GateInfo::new("RY", 1, 1, false),
+    GateInfo::new("RZ", 1, 1, true),
+    GateInfo::new("CNOT", 2, 0, false),
+    GateInfo::new("CX", 2, 0, false),
+    GateInfo::new("CZ", 2, 0, true),
+    GateInfo::new("SWAP", 2, 0, false),
+    GateInfo::new("CCX", 3, 0, false),
+    GateInfo::new("TOFFOLI", 3, 0, false),
+    GateInfo::new("CP", 2, 1, true),
+    GateInfo::new("CPHASE", 2, 1, true),
+    GateInfo::new("U3", 1, 3, false),
+    GateInfo::new("U", 1, 3, false),
+    GateInfo::new("ISWAP", 2, 0, false),
+    GateInfo::new("SQRTSWAP", 2, 0, false),
+    GateInfo::new("CRX", 2, 1, false),
+    GateInfo::new("CRY", 2, 1, false),
+    GateInfo::new("CRZ", 2, 1, true),
+    GateInfo::new("CSWAP", 3, 0, false),
+    GateInfo::new("FREDKIN", 3, 0, false),
+];
+
+pub fn builtin_gate_info(name: &str) -> Option<&'static GateInfo> {
+    BUILTIN_GATES.iter().find(|info| info.name == name)
+}
+
+// Allowed deviation of U·U† from the identity for user-supplied matrices
+pub const UNITARY_TOLERANCE: f64 = 1e-8;
+
+// User-registered gates, looked up after the built-in names
+static CUSTOM_GATES: LazyLock<Mutex<HashMap<String, GateMatrix>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Register a named 1- or 2-qubit unitary (row-major) for later dispatch.
+pub fn register_gate(name: &str, matrix: &[Complex64], arity: usize) -> Result<(), QuantumError> {
+    if builtin_gate_info(name).is_some() {
+        return Err(QuantumError::InvalidArgument {
+            reason: format!("gate name {} collides with a built-in gate", name),
+        });
+    }
+    if !(1..=2).contains(&arity) {
+        return Err(QuantumError::InvalidArgument {
+            reason: format!("custom gates must act on 1 or 2 qubits, got {}", arity),
+        });
+    }
+    
+    let dim = 1 << arity;
+    if matrix.len() != dim * dim {
+        return Err(QuantumError::LengthMismatch { expected: dim * dim, found: matrix.len() });
+    }
+    if !is_unitary(matrix, dim, UNITARY_TOLERANCE) {
+        return Err(QuantumError::InvalidArgument {
+            reason: format!("matrix for gate {} is not unitary", name),
+        });
+    }
+    
+    let gate = if arity == 1 {
+        GateMatrix::Single(matrix.try_into().unwrap())
+    } else {
+        GateMatrix::Two(matrix.try_into().unwrap())
+    };
+    
+    CUSTOM_GATES.lock().unwrap().insert(name.to_string(), gate);
+    Ok(())
+}
+
+pub fn unregister_gate(name: &str) -> bool {
+    CUSTOM_GATES.lock().unwrap().remove(name).is_some()
+}
+
+/// Gates dispatched by name rather than through a `GateMatrix`: RESET is
+/// non-unitary and GPHASE, MCX and MCZ have no fixed arity.
+pub const PSEUDO_GATES: &[&str] = &["RESET", "GPHASE", "MCX", "MCZ"];
+
+pub fn is_pseudo_gate(name: &str) -> bool {
+    PSEUDO_GATES.contains(&name)
+}
+
+/// Every name `apply_named_gate` accepts: the built-ins, the pseudo-gates it
+/// dispatches specially, then registered custom gates in sorted order.
+pub fn supported_gate_names() -> Vec<String> {
+    let mut custom: Vec<String> = CUSTOM_GATES.lock().unwrap().keys().cloned().collect();
+    custom.sort();
+    BUILTIN_GATES
+        .iter()
+        .map(|info| info.name)
+        .chain(PSEUDO_GATES.iter().copied())
+        .map(str::to_string)
+        .chain(custom)
+        .collect()
+}
+
+/// Resolve a gate name and its parameters to a matrix, or None if unknown.
+pub fn gate_matrix(name: &str, params: &[f64]) -> Option<GateMatrix> {
+    let matrix = match name {
+        "H" => GateMatrix::Single(Gates::hadamard()),
+        "X" => GateMatrix::Single(Gates::pauli_x()),
+        "Y" => GateMatrix::Single(Gates::pauli_y()),
+        "Z" => GateMatrix::Single(Gates::pauli_z()),
+        "S" => GateMatrix::Single(Gates::s_gate()),
+        "T" => GateMatrix::Single(Gates::t_gate()),
+        "RX" => GateMatrix::Single(Gates::rotation_x(params[0])),
+        "RY" => GateMatrix::Single(Gates::rotation_y(params[0])),
+        "RZ" => GateMatrix::Single(Gates::rotation_z(params[0])),
+        "CNOT" | "CX" => GateMatrix::Two(Gates::cnot()),
+        "CZ" => GateMatrix::Two(Gates::cz()),
+        "SWAP" => GateMatrix::Two(Gates::swap()),
+        "ISWAP" => GateMatrix::Two(Gates::iswap()),
+        "SQRTSWAP" => GateMatrix::Two(Gates::sqrt_swap()),
+        "CP" | "CPHASE" => GateMatrix::Two(Gates::controlled_phase(params[0])),
+        "U3" | "U" => GateMatrix::Single(Gates::u3(params[0], params[1], params[2])),
+        "CRX" => GateMatrix::Two(Gates::controlled_rx(params[0])),
+        "CRY" => GateMatrix::Two(Gates::controlled_ry(params[0])),
+        "CRZ" => GateMatrix::Two(Gates::controlled_rz(params[0])),
+        "CCX" | "TOFFOLI" => GateMatrix::Three(Box::new(Gates::toffoli())),
+        "CSWAP" | "FREDKIN" => GateMatrix::Three(Box::new(Gates::fredkin())),
+        _ => return CUSTOM_GATES.lock().unwrap().get(name).cloned(),
+    };
+    Some(matrix)
+}
+
+/// Apply a gate by name. Returns Ok(false) when the parameters are missing,
+/// and an error for an unrecognised name or bad qubit indices.
+/// "RESET" is accepted as a non-unitary pseudo-gate on one qubit, and
+/// "GPHASE" as a zero-qubit gate taking the phase φ. "MCX" and "MCZ" take
+/// any number of controls followed by the target.
+pub fn apply_named_gate(simulator: &mut QuantumState, name: &str, qubits: &[usize], params: &[f64]) -> Result<bool, QuantumError> {
+    apply_named_gate_with_inverse(simulator, name, qubits, params, false)
+}
+
+/// `apply_named_gate`, applying the gate's adjoint when `inverse` is set.
+/// RESET has no inverse and is rejected in that case.
+pub fn apply_named_gate_with_inverse(simulator: &mut QuantumState, name: &str, qubits: &[usize], params: &[f64], inverse: bool) -> Result<bool, QuantumError> {
+    if is_pseudo_gate(name) {
+        return apply_pseudo_gate(simulator, name, qubits, params, inverse);
+    }
+    // Built-in constructors index `params` directly
+    if params.len() < expected_param_count(name) {
+        return Ok(false);
+    }
+    match gate_matrix(name, params) {
+        Some(matrix) => {
+            if qubits.len() != matrix.arity() {
+                return Err(QuantumError::LengthMismatch { expected: matrix.arity(), found: qubits.len() });
+            }
+            let matrix = if inverse { matrix.dagger() } else { matrix };
+            let since = simulator.revision();
+            apply_matrix(simulator, &matrix, qubits)?;
+            let op = GateOp::new(name, qubits.to_vec(), params.to_vec());
+            simulator.record_gates(since, &[if inverse { inverse_op(&op) } else { op }]);
+            Ok(true)
+        },
+        None => Err(QuantumError::UnknownGate { name: name.to_string() }),
+    }
+}
+
+// Apply and record one of `PSEUDO_GATES`
+fn apply_pseudo_gate(simulator: &mut QuantumState, name: &str, qubits: &[usize], params: &[f64], inverse: bool) -> Result<bool, QuantumError> {
+    let since = simulator.revision();
+    if name == "RESET" {
+        if inverse {
+            return Err(QuantumError::InvalidArgument { reason: "RESET is not invertible".to_string() });
+        }
+        if qubits.len() != 1 {
+            return Err(QuantumError::LengthMismatch { expected: 1, found: qubits.len() });
+        }
+        simulator.reset_qubit(qubits[0])?;
+        simulator.record_gates(since, &[GateOp::new(name, qubits.to_vec(), Vec::new())]);
+        return Ok(true);
+    }
+    if name == "GPHASE" {
+        if !qubits.is_empty() {
+            return Err(QuantumError::LengthMismatch { expected: 0, found: qubits.len() });
+        }
+        let Some(&phi) = params.first() else {
+            return Ok(false);
+        };
+        simulator.apply_global_phase(if inverse { -phi } else { phi });
+        let op = GateOp::new(name, Vec::new(), vec![phi]);
+        simulator.record_gates(since, &[if inverse { inverse_op(&op) } else { op }]);
+        return Ok(true);
+    }
+    // MCX and MCZ are self-inverse
+    let Some((&target, controls)) = qubits.split_last() else {
+        return Err(QuantumError::LengthMismatch { expected: 1, found: 0 });
+    };
+    if name == "MCX" {
+        simulator.apply_mcx(controls, target)?;
+    } else {
+        simulator.apply_mcz(controls, target)?;
+    }
+    simulator.record_gates(since, &[GateOp::new(name, qubits.to_vec(), Vec::new())]);
+    Ok(true)
+}
+
+/// The named gate undoing `op`, for the history. Gates whose adjoint has no
+/// name of its own (iSWAP, √SWAP, custom gates) are recorded as `inv(NAME)`.
+pub fn inverse_op(op: &GateOp) -> GateOp {
+    let qubits = op.qubits.clone();
+    let negated = || op.params.iter().map(|p| -p).collect();
+    match op.name.as_str() {
+        "H" | "X" | "Y" | "Z" | "CNOT" | "CX" | "CZ" | "SWAP" | "CCX" | "TOFFOLI" | "CSWAP" | "FREDKIN" | "MCX" | "MCZ" => op.clone(),
+        "RX" | "RY" | "RZ" | "CP" | "CPHASE" | "CRX" | "CRY" | "CRZ" | "GPHASE" => GateOp { name: op.name.clone(), qubits, params: negated() },
+        // U3(θ, φ, λ)† = U3(−θ, −λ, −φ); S† and T† are phase gates U3(0, 0, −λ)
+        "U3" | "U" => GateOp::new("U3", qubits, vec![-op.params[0], -op.params[2], -op.params[1]]),
+        "S" => GateOp::new("U3", qubits, vec![0.0, 0.0, -PI / 2.0]),
+        "T" => GateOp::new("U3", qubits, vec![0.0, 0.0, -PI / 4.0]),
+        name => GateOp { name: format!("inv({})", name), qubits, params: op.params.clone() },
+    }
+}
+
+/// Apply every gate of `gates` only in the subspace where `control` is |1⟩.
+pub fn apply_controlled_subcircuit(simulator: &mut QuantumState, control: usize, gates: &[GateOp]) -> Result<(), QuantumError> {
+    // Resolve the whole subcircuit first so a bad gate leaves the state untouched
+    simulator.check_qubit(control)?;
+    let mut resolved = Vec::with_capacity(gates.len());
+    for op in gates {
+        if op.qubits.contains(&control) {
+            return Err(QuantumError::IdenticalQubits { qubit: control });
+        }
+        let matrix = resolve_gate(simulator, op)?;
+        if matrix.arity() > 2 {
+            return Err(QuantumError::InvalidArgument {
+                reason: format!("controlled {} is not supported", op.name),
+            });
+        }
+        resolved.push((matrix, op));
+    }
+    
+    for (matrix, op) in resolved {
+        match matrix {
+            GateMatrix::Single(gate) => {
+                simulator.apply_controlled_single_qubit_gate(&gate, control, op.qubits[0])?;
+            },
+            GateMatrix::Two(gate) => {
+                simulator.apply_controlled_two_qubit_gate(&gate, control, op.qubits[0], op.qubits[1])?;
+            },
+            GateMatrix::Three(_) => unreachable!("rejected while resolving"),
+        }
+    }
+    
+    Ok(())
+}
+
+pub fn apply_gate_op(simulator: &mut QuantumState, op: &GateOp) -> Result<bool, QuantumError> {
+    apply_named_gate(simulator, &op.name, &op.qubits, &op.params)
+}
+
+fn expected_param_count(name: &str) -> usize {
+    if name == "GPHASE" {
+        return 1;
+    }
+    builtin_gate_info(name).map_or(0, |info| info.num_params)
+}
+
+/// Every problem with `op` on a register of `num_qubits`: parameter count,
+/// unknown name, arity, and out-of-range or repeated qubits. Pseudo-gates
+/// are checked against their own arity rules.
+pub fn gate_problems(op: &GateOp, num_qubits: usize) -> Vec<QuantumError> {
+    let mut problems = Vec::new();
+    
+    let expected_params = expected_param_count(&op.name);
+    if op.params.len() < expected_params {
+        problems.push(QuantumError::InvalidArgument {
+            reason: format!("gate {} expects {} parameter(s), got {}", op.name, expected_params, op.params.len()),
+        });
+    }
+    
+    // Pad missing parameters so the name and arity can still be checked
+    let mut padded = op.params.clone();
+    padded.resize(op.params.len().max(expected_params), 0.0);
+    match op.name.as_str() {
+        "RESET" | "GPHASE" => {
+            let arity = if op.name == "RESET" { 1 } else { 0 };
+            if op.qubits.len() != arity {
+                problems.push(QuantumError::InvalidArgument {
+                    reason: format!("gate {} acts on {} qubit(s), got {}", op.name, arity, op.qubits.len()),
+                });
+            }
+        },
+        "MCX" | "MCZ" => {
+            if op.qubits.is_empty() {
+                problems.push(QuantumError::InvalidArgument {
+                    reason: format!("gate {} needs at least a target qubit", op.name),
+                });
+            }
+        },
+        _ => match gate_matrix(&op.name, &padded) {
+            Some(matrix) if op.qubits.len() != matrix.arity() => {
+                problems.push(QuantumError::InvalidArgument {
+                    reason: format!("gate {} acts on {} qubit(s), got {}", op.name, matrix.arity(), op.qubits.len()),
+                });
+            },
+            Some(_) => {},
+            None => problems.push(QuantumError::UnknownGate { name: op.name.clone() }),
+        },
+    }
+    
+    for (i, &qubit) in op.qubits.iter().enumerate() {
+        if qubit >= num_qubits {
+            problems.push(QuantumError::QubitOutOfRange { qubit, num_qubits });
+        } else if op.qubits[..i].contains(&qubit) {
+            problems.push(QuantumError::IdenticalQubits { qubit });
+        }
+    }
+    
+    problems
+}
+
+/// Resolve a gate against a simulator, checking its name, parameter count,
+/// arity and qubit indices without touching the state.
+pub fn resolve_gate(simulator: &QuantumState, op: &GateOp) -> Result<GateMatrix, QuantumError> {
+    if let Some(problem) = gate_problems(op, simulator.num_qubits).into_iter().next() {
+        return Err(problem);
+    }
+    gate_matrix(&op.name, &op.params).ok_or_else(|| no_matrix_error(&op.name))
+}
+
+/// The error for a validated gate that still has no `GateMatrix`: a
+/// pseudo-gate used where only matrix gates are supported.
+pub fn no_matrix_error(name: &str) -> QuantumError {
+    if is_pseudo_gate(name) {
+        QuantumError::InvalidArgument { reason: format!("gate {} has no matrix form and is not supported here", name) }
+    } else {
+        QuantumError::UnknownGate { name: name.to_string() }
+    }
+}
+
+/// Dry-run check of a whole circuit, returning every (gate index, problem).
+pub fn validate_circuit(num_qubits: usize, gates: &[GateOp]) -> Vec<(usize, QuantumError)> {
+    gates
+        .iter()
+        .enumerate()
+        .flat_map(|(index, op)| {
+            gate_problems(op, num_qubits)
+                .into_iter()
+                .map(move |problem| (index, problem))
+        })
+        .collect()
+}
+
+pub fn apply_matrix(simulator: &mut QuantumState, matrix: &GateMatrix, qubits: &[usize]) -> Result<(), QuantumError> {
+    match matrix {
+        GateMatrix::Single(gate) => simulator.apply_single_qubit_gate(gate, qubits[0]),
+        GateMatrix::Two(gate) => simulator.apply_two_qubit_gate(gate, qubits[0], qubits[1]),
+        GateMatrix::Three(gate) => simulator.apply_three_qubit_gate(gate, qubits[0], qubits[1], qubits[2]),
+    }
+}
+
+/// Validate every gate up front, then apply them all; a bad gate anywhere
+/// leaves the state untouched.
+pub fn apply_gate_ops(simulator: &mut QuantumState, gates: &[GateOp]) -> Result<(), QuantumError> {
+    apply_gate_ops_indexed(simulator, gates).map_err(|(_, problem)| problem)
+}
+
+/// `apply_gate_ops`, reporting the index of the gate at fault. Matrix gates
+/// between pseudo-gates are fused and applied as one batch; each pseudo-gate
+/// is dispatched like `apply_named_gate`.
+pub fn apply_gate_ops_indexed(simulator: &mut QuantumState, gates: &[GateOp]) -> Result<(), (usize, QuantumError)> {
+    for (index, op) in gates.iter().enumerate() {
+        if let Some(problem) = gate_problems(op, simulator.num_qubits).into_iter().next() {
+            return Err((index, problem));
+        }
+    }
+    
+    let mut offset = 0;
+    for segment in gates.split_inclusive(|op| is_pseudo_gate(&op.name)) {
+        let (unitary, pseudo) = match segment.split_last() {
+            Some((last, unitary)) if is_pseudo_gate(&last.name) => (unitary, Some(last)),
+            _ => (segment, None),
+        };
+        let resolved = resolve_gates(simulator, unitary).map_err(|(index, problem)| (offset + index, problem))?;
+        let since = simulator.revision();
+        apply_resolved(simulator, resolved).map_err(|problem| (offset, problem))?;
+        simulator.record_gates(since, unitary);
+        if let Some(op) = pseudo {
+            apply_pseudo_gate(simulator, &op.name, &op.qubits, &op.params, false)
+                .map_err(|problem| (offset + unitary.len(), problem))?;
+        }
+        offset += segment.len();
+    }
+    Ok(())
+}
+
+/// Resolve a whole circuit, reporting the index of the first bad gate.
+pub fn resolve_gates(simulator: &QuantumState, gates: &[GateOp]) -> Result<Vec<ResolvedGate>, (usize, QuantumError)> {
+    gates
+        .iter()
+        .enumerate()
+        .map(|(index, op)| {
+            resolve_gate(simulator, op)
+                .map(|matrix| (matrix, op.qubits.clone()))
+                .map_err(|problem| (index, problem))
+        })
+        .collect()
+}
+
+/// Fire-and-forget execution: build a fresh register, apply `gates` and
+/// return the seeded measurement histogram.
+pub fn run_circuit(num_qubits: usize, gates: &[GateOp], shots: usize, seed: u64) -> Result<HashMap<String, usize>, QuantumError> {
+    let mut simulator = QuantumState::new(num_qubits)?;
+    apply_gate_ops(&mut simulator, gates)?;
+    Ok(simulator.measure_with_seed(shots, seed))
+}
+
+/// Apply already-resolved gates, fusing single-qubit runs first.
+pub fn apply_resolved(simulator: &mut QuantumState, resolved: Vec<ResolvedGate>) -> Result<(), QuantumError> {
+    for (matrix, qubits) in fuse_single_qubit_runs(resolved) {
+        apply_matrix(simulator, &matrix, &qubits)?;
+    }
+    Ok(())
+}
+
+/// Merge consecutive single-qubit gates on the same wire into one matrix so
+/// each run costs a single pass over the state. A pending run is flushed
+/// just before the next multi-qubit gate touching its wire.
+pub fn fuse_single_qubit_runs(ops: Vec<ResolvedGate>) -> Vec<ResolvedGate> {
+    let mut pending: HashMap<usize, Vec<[Complex64; 4]>> = HashMap::new();
+    let mut fused = Vec::with_capacity(ops.len());
+    let flush = |pending: &mut HashMap<usize, Vec<[Complex64; 4]>>, fused: &mut Vec<ResolvedGate>, qubit: usize| {
+        if let Some(run) = pending.remove(&qubit) {
+            fused.push((GateMatrix::Single(fuse_single_qubit(&run)), vec![qubit]));
+        }
+    };
+    
+    for (matrix, qubits) in ops {
+        match matrix {
+            GateMatrix::Single(gate) => pending.entry(qubits[0]).or_default().push(gate),
+            other => {
+                for &qubit in &qubits {
+                    flush(&mut pending, &mut fused, qubit);
+                }
+                fused.push((other, qubits));
+            }
+        }
+    }
+    
+    let mut remaining: Vec<usize> = pending.keys().copied().collect();
+    remaining.sort_unstable();
+    for qubit in remaining {
+        flush(&mut pending, &mut fused, qubit);
+    }
+    
+    fused
+}
+
+/// Quantum Fourier transform on `qubits`, with `qubits[0]` the least
+/// significant bit: |x⟩ → 2^(-n/2) Σ_y e^(2πi·xy/2ⁿ) |y⟩. Hadamard and
+/// controlled-phase ladder from the top bit down, then bit-reversal swaps.
+pub fn qft(qubits: &[usize]) -> Vec<GateOp> {
+    let n = qubits.len();
+    let mut gates = Vec::new();
+    for j in (0..n).rev() {
+        gates.push(GateOp::new("H", vec![qubits[j]], vec![]));
+        for k in (0..j).rev() {
+            let angle = PI / (1u64 << (j - k)) as f64;
+            gates.push(GateOp::new("CP", vec![qubits[k], qubits[j]], vec![angle]));
+        }
+    }
+    for i in 0..n / 2 {
+        gates.push(GateOp::new("SWAP", vec![qubits[i], qubits[n - 1 - i]], vec![]));
+    }
+    gates
+}
+
+/// `qft` reversed with every gate inverted.
+pub fn inverse_qft(qubits: &[usize]) -> Vec<GateOp> {
+    qft(qubits).iter().rev().map(inverse_op).collect()
+}
+
+/// Expand a per-qubit layer (index = qubit, None = leave idle) into gate ops.
+/// Applying the result through `apply_gate_ops` rejects multi-qubit names.
+pub fn single_qubit_layer(specs: &[Option<(String, Vec<f64>)>]) -> Vec<GateOp> {
+    specs
+        .iter()
+        .enumerate()
+        .filter_map(|(qubit, spec)| {
+            spec.as_ref().map(|(name, params)| GateOp::new(name, vec![qubit], params.clone()))
+        })
+        .collect()
+}
+
+/// A gate whose first parameter is bound from a shared parameter vector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterizedGate {
+    pub op: GateOp,
+    pub param_index: Option<usize>,
+}
+
+pub fn bind_parameters(structure: &[ParameterizedGate], params: &[f64]) -> Result<Vec<GateOp>, QuantumError> {
+    structure
+        .iter()
+        .map(|gate| {
+            let mut op = gate.op.clone();
+            if let Some(index) = gate.param_index {
+                let value = *params.get(index).ok_or_else(|| QuantumError::InvalidArgument {
+                    reason: format!("parameter index {} out of range for {} parameters", index, params.len()),
+                })?;
+                match op.params.first_mut() {
+                    Some(first) => *first = value,
+                    None => op.params.push(value),
+                }
+            }
+            Ok(op)
+        })
+        .collect()
+}
+
+/// Apply one layer of gates, then depolarize every qubit no gate touched.
+/// Returns the idle qubits that received the channel.
+pub fn apply_layer_with_idle_noise(simulator: &mut QuantumState, gates: &[GateOp], idle_p: f64, seed: u64) -> Result<Vec<usize>, QuantumError> {
+    check_probability(idle_p)?;
+    apply_gate_ops(simulator, gates)?;
+    
+    let mut active = vec![false; simulator.num_qubits];
+    for &q in gates.iter().flat_map(|op| op.qubits.iter()) {
+        active[q] = true;
+    }
+    
+    let mut rng = StdRng::seed_from_u64(seed);
+    let idle: Vec<usize> = (0..simulator.num_qubits).filter(|&q| !active[q]).collect();
+    for &qubit in &idle {
+        simulator.apply_depolarizing(qubit, idle_p, &mut rng)?;
+    }
+    
+    Ok(idle)
+}
+
+/// Apply the two-qubit gate `name` to every pair (i, j) with i < j, in
+/// lexicographic order. Returns the number of gates applied.
+pub fn apply_all_to_all_layer(simulator: &mut QuantumState, name: &str, params: &[f64]) -> Result<usize, QuantumError> {
+    // Validate against a nominal pair so registers under two qubits still
+    // reject one-qubit or unknown gates
+    let probe = GateOp::new(name, vec![0, 1], params.to_vec());
+    if let Some(problem) = gate_problems(&probe, 2).into_iter().next() {
+        return Err(problem);
+    }
+    let matrix = gate_matrix(name, params).ok_or_else(|| QuantumError::UnknownGate { name: name.to_string() })?;
+    
+    let n = simulator.num_qubits;
+    let ops: Vec<GateOp> = (0..n)
+        .flat_map(|i| (i + 1..n).map(move |j| GateOp::new(name, vec![i, j], params.to_vec())))
+        .collect();
+    let since = simulator.revision();
+    for op in &ops {
+        apply_matrix(simulator, &matrix, &op.qubits)?;
+    }
+    simulator.record_gates(since, &ops);
+    
+    Ok(ops.len())
+}
+
+/// Apply the single-qubit gate `name` to every qubit whose P(1) marginal lies
+/// in [min_prob, max_prob]. Targets are chosen from the marginals before any
+/// gate is applied; returns them in ascending order.
+pub fn apply_gate_where_marginal(simulator: &mut QuantumState, name: &str, min_prob: f64, max_prob: f64, params: &[f64]) -> Result<Vec<usize>, QuantumError> {
+    // Validate against a nominal qubit so an empty register still rejects
+    // two-qubit or unknown gates and returns no targets otherwise
+    let probe = GateOp::new(name, vec![0], params.to_vec());
+    if let Some(problem) = gate_problems(&probe, 1).into_iter().next() {
+        return Err(problem);
+    }
+    let matrix = gate_matrix(name, params).ok_or_else(|| no_matrix_error(name))?;
+    
+    let targets: Vec<usize> = simulator
+        .single_qubit_marginals()
+        .iter()
+        .enumerate()
+        .filter(|(_, &p)| p >= min_prob && p <= max_prob)
+        .map(|(qubit, _)| qubit)
+        .collect();
+    
+    let since = simulator.revision();
+    for &qubit in &targets {
+        apply_matrix(simulator, &matrix, &[qubit])?;
+    }
+    let ops: Vec<GateOp> = targets.iter().map(|&qubit| GateOp::new(name, vec![qubit], params.to_vec())).collect();
+    simulator.record_gates(since, &ops);
+    
+    Ok(targets)
+}
+
+/// Measure `qubit` (collapsing the state), then apply the correction gates
+/// listed for the observed outcome: `corrections[0]` after reading 0,
+/// `corrections[1]` after reading 1. Returns the outcome.
+pub fn measure_with_correction(simulator: &mut QuantumState, qubit: usize, corrections: &[Vec<GateOp>; 2], seed: u64) -> Result<bool, QuantumError> {
+    simulator.check_qubit(qubit)?;
+    for op in corrections.iter().flatten() {
+        if let Some(problem) = gate_problems(op, simulator.num_qubits).into_iter().next() {
+            return Err(problem);
+        }
+    }
+    
+    let mut rng = StdRng::seed_from_u64(seed);
+    let outcome = simulator.measure_qubit_with_rng(qubit, &mut rng)?;
+    apply_gate_ops(simulator, &corrections[usize::from(outcome)])?;
+    
+    Ok(outcome)
+}
+
+/// Indices (in circuit order) of the gates that can influence `target`'s final
+/// reduced state, found by walking the gate list backward from the output.
+pub fn light_cone(gates: &[GateOp], target: usize, num_qubits: usize) -> Result<Vec<usize>, QuantumError> {
+    for &qubit in gates.iter().flat_map(|op| op.qubits.iter()).chain(std::iter::once(&target)) {
+        if qubit >= num_qubits {
+            return Err(QuantumError::QubitOutOfRange { qubit, num_qubits });
+        }
+    }
+    
+    let mut in_cone = vec![false; num_qubits];
+    in_cone[target] = true;
+    
+    let mut cone = Vec::new();
+    for (index, op) in gates.iter().enumerate().rev() {
+        if op.qubits.iter().any(|&q| in_cone[q]) {
+            for &q in &op.qubits {
+                in_cone[q] = true;
+            }
+            cone.push(index);
+        }
+    }
+    
+    cone.reverse();
+    Ok(cone)
+}
+
+/// Route `gates` onto hardware whose two-qubit gates may only act on the
+/// pairs in `coupling` (undirected). Distant operands are brought together by
+/// SWAP chains along a shortest path. Returns the routed gate list on physical
+/// qubits and the final layout, where `layout[logical]` is its physical qubit.
+pub fn transpile_for_coupling(gates: &[GateOp], num_qubits: usize, coupling: &[(usize, usize)]) -> Result<(Vec<GateOp>, Vec<usize>), QuantumError> {
+    let mut neighbours = vec![Vec::new(); num_qubits];
+    for &(a, b) in coupling {
+        for qubit in [a, b] {
+            if qubit >= num_qubits {
+                return Err(QuantumError::QubitOutOfRange { qubit, num_qubits });
+            }
+        }
+        neighbours[a].push(b);
+        neighbours[b].push(a);
+    }
+    
+    let mut layout: Vec<usize> = (0..num_qubits).collect();
+    let mut routed = Vec::with_capacity(gates.len());
+    
+    for op in gates {
+        if let Some(problem) = gate_problems(op, num_qubits).into_iter().next() {
+            return Err(problem);
+        }
+        
+        if op.qubits.len() > 2 {
+            return Err(QuantumError::InvalidArgument {
+                reason: format!("routing {} on {} qubits is not supported", op.name, op.qubits.len()),
+            });
+        }
+        if op.qubits.len() == 2 {
+            let target = layout[op.qubits[1]];
+            let path = shortest_path(&neighbours, layout[op.qubits[0]], target).ok_or_else(|| QuantumError::InvalidArgument {
+                reason: format!("qubits {} and {} are not connected by the coupling map", op.qubits[0], op.qubits[1]),
+            })?;
+            
+            // Walk the first operand along the path until it neighbours the second
+            for step in path.windows(2).take(path.len().saturating_sub(2)) {
+                routed.push(GateOp::new("SWAP", vec![step[0], step[1]], vec![]));
+                for physical in layout.iter_mut() {
+                    if *physical == step[0] {
+                        *physical = step[1];
+                    } else if *physical == step[1] {
+                        *physical = step[0];
+                    }
+                }
+            }
+        }
+        
+        let qubits = op.qubits.iter().map(|&q| layout[q]).collect();
+        routed.push(GateOp::new(&op.name, qubits, op.params.clone()));
+    }
+    
+    Ok((routed, layout))
+}
+
+// Breadth-first search over the coupling graph, inclusive of both endpoints
+fn shortest_path(neighbours: &[Vec<usize>], from: usize, to: usize) -> Option<Vec<usize>> {
+    let mut previous = vec![None; neighbours.len()];
+    let mut visited = vec![false; neighbours.len()];
+    let mut queue = std::collections::VecDeque::from([from]);
+    visited[from] = true;
+    
+    while let Some(node) = queue.pop_front() {
+        if node == to {
+            let mut path = vec![to];
+            while let Some(prev) = previous[*path.last().unwrap()] {
+                path.push(prev);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        for &next in &neighbours[node] {
+            if !visited[next] {
+                visited[next] = true;
+                previous[next] = Some(node);
+                queue.push_back(next);
+            }
+        }
+    }
+    
+    None
+}
+
+/// Compact binary form of a gate list. Each gate is its opcode byte (the
+/// index into `BUILTIN_GATES`), one byte per qubit, then its parameters as
+/// little-endian f64s; arity and parameter count come from the table.
+/// Registered custom gates have no opcode and cannot be encoded.
+pub fn encode_circuit(gates: &[GateOp]) -> Result<Vec<u8>, QuantumError> {
+    let mut bytes = Vec::with_capacity(gates.len() * 3);
+    
+    for op in gates {
+        let opcode = BUILTIN_GATES
+            .iter()
+            .position(|info| info.name == op.name)
+            .ok_or_else(|| QuantumError::UnknownGate { name: op.name.clone() })?;
+        let info = &BUILTIN_GATES[opcode];
+        if op.qubits.len() != info.num_qubits {
+            return Err(QuantumError::LengthMismatch { expected: info.num_qubits, found: op.qubits.len() });
+        }
+        if op.params.len() != info.num_params {
+            return Err(QuantumError::LengthMismatch { expected: info.num_params, found: op.params.len() });
+        }
+        
+        bytes.push(opcode as u8);
+        for &qubit in &op.qubits {
+            let byte = u8::try_from(qubit).map_err(|_| QuantumError::InvalidArgument {
+                reason: format!("qubit {} does not fit the binary encoding", qubit),
+            })?;
+            bytes.push(byte);
+        }
+        for &param in &op.params {
+            bytes.extend_from_slice(&param.to_le_bytes());
+        }
+    }
+    
+    Ok(bytes)
+}
+
+/// Inverse of `encode_circuit`.
+pub fn decode_circuit(bytes: &[u8]) -> Result<Vec<GateOp>, QuantumError> {
+    let truncated = |offset: usize| QuantumError::InvalidArgument {
+        reason: format!("circuit encoding truncated at byte {}", offset),
+    };
+    
+    let mut gates = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let info = BUILTIN_GATES.get(bytes[offset] as usize).ok_or_else(|| QuantumError::InvalidArgument {
+            reason: format!("unknown opcode {} at byte {}", bytes[offset], offset),
+        })?;
+        offset += 1;
+        
+        let qubits = bytes
+            .get(offset..offset + info.num_qubits)
+            .ok_or_else(|| truncated(offset))?
+            .iter()
+            .map(|&q| q as usize)
+            .collect();
+        offset += info.num_qubits;
+        
+        let mut params = Vec::with_capacity(info.num_params);
+        for _ in 0..info.num_params {
+            let chunk = bytes.get(offset..offset + 8).ok_or_else(|| truncated(offset))?;
+            params.push(f64::from_le_bytes(chunk.try_into().unwrap()));
+            offset += 8;
+        }
+        
+        gates.push(GateOp::new(info.name, qubits, params));
+    }
+    
+    Ok(gates)
+}
+
+/// Randomized compiling: surround every CNOT/CX, CZ and SWAP with a random
+/// two-qubit Pauli before and its conjugate G·P·G† after, so the ideal
+/// circuit is unchanged up to global phase. Other gates pass through.
+pub fn pauli_twirl(gates: &[GateOp], seed: u64) -> Vec<GateOp> {
+    // Paulis as (x, z) bits: I = (0,0), X = (1,0), Y = (1,1), Z = (0,1)
+    const LETTERS: [&str; 4] = ["I", "X", "Y", "Z"];
+    let to_bits = |letter: usize| (letter == 1 || letter == 2, letter >= 2);
+    let from_bits = |(x, z): (bool, bool)| match (x, z) {
+        (false, false) => 0,
+        (true, false) => 1,
+        (true, true) => 2,
+        (false, true) => 3,
+    };
+    
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut twirled = Vec::with_capacity(gates.len() * 3);
+    
+    for op in gates {
+        if !matches!(op.name.as_str(), "CNOT" | "CX" | "CZ" | "SWAP") || op.qubits.len() != 2 {
+            twirled.push(op.clone());
+            continue;
+        }
+        
+        let before = [rng.gen_range(0..4), rng.gen_range(0..4)];
+        let (mut a, mut b) = (to_bits(before[0]), to_bits(before[1]));
+        match op.name.as_str() {
+            // X on the control spreads to the target, Z on the target to the control
+            "CNOT" | "CX" => {
+                b.0 ^= a.0;
+                a.1 ^= b.1;
+            },
+            "CZ" => {
+                let (xa, xb) = (a.0, b.0);
+                a.1 ^= xb;
+                b.1 ^= xa;
+            },
+            _ => std::mem::swap(&mut a, &mut b),
+        }
+        let after = [from_bits(a), from_bits(b)];
+        
+        for (letter, &qubit) in before.iter().zip(&op.qubits) {
+            if *letter != 0 {
+                twirled.push(GateOp::new(LETTERS[*letter], vec![qubit], vec![]));
+            }
+        }
+        twirled.push(op.clone());
+        for (letter, &qubit) in after.iter().zip(&op.qubits) {
+            if *letter != 0 {
+                twirled.push(GateOp::new(LETTERS[*letter], vec![qubit], vec![]));
+            }
+        }
+    }
+    
+    twirled
+}
+
+/// Deterministic random circuit: the same seed always yields the same gate list.
+pub fn random_circuit(num_qubits: usize, gate_count: usize, seed: u64) -> Vec<GateOp> {
+    const SINGLE: [&str; 6] = ["H", "X", "Y", "Z", "S", "T"];
+    const ROTATIONS: [&str; 3] = ["RX", "RY", "RZ"];
+    const TWO: [&str; 2] = ["CNOT", "CZ"];
+    
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut gates = Vec::with_capacity(gate_count);
+    
+    for _ in 0..gate_count {
+        let kind = if num_qubits > 1 { rng.gen_range(0..3) } else { rng.gen_range(0..2) };
+        let q0 = rng.gen_range(0..num_qubits);
+        
+        let op = match kind {
+            0 => GateOp::new(SINGLE[rng.gen_range(0..SINGLE.len())], vec![q0], vec![]),
+            1 => {
+                let name = ROTATIONS[rng.gen_range(0..ROTATIONS.len())];
+                GateOp::new(name, vec![q0], vec![rng.gen_range(0.0..2.0 * PI)])
+            },
+            _ => {
+                // Pick a distinct second wire by offsetting from the first
+                let q1 = (q0 + rng.gen_range(1..num_qubits)) % num_qubits;
+                GateOp::new(TWO[rng.gen_range(0..TWO.len())], vec![q0, q1], vec![])
+            },
+        };
+        gates.push(op);
+    }
+    
+    gates
+}
@@ -0,0 +1,237 @@
+use nalgebra::DMatrix;
+use num_complex::Complex64;
+use std::collections::HashMap;
+
+use crate::circuit::{gate_matrix, gate_problems, no_matrix_error, GateMatrix, GateOp};
+use crate::error::QuantumError;
+use crate::gates::Gates;
+use crate::quantum_state::{check_probability, sample_from_cumulative, QuantumState};
+
+// Allowed deviation of Σ K†K from the identity for a Kraus set
+const KRAUS_TOLERANCE: f64 = 1e-8;
+
+// Largest density matrix allocated; 2^13 × 2^13 entries already take 1 GiB
+pub const MAX_DENSITY_QUBITS: usize = 13;
+
+/// Exact mixed-state simulation on a dense 2ⁿ×2ⁿ matrix. Basis indices and
+/// bitstrings follow `QuantumState` (qubit 0 is the least significant bit).
+#[derive(Clone, Debug)]
+pub struct DensityMatrix {
+    pub rho: DMatrix<Complex64>,
+    pub num_qubits: usize,
+}
+
+impl DensityMatrix {
+    /// |0…0⟩⟨0…0|, refusing registers beyond `MAX_DENSITY_QUBITS`.
+    pub fn new(num_qubits: usize) -> Result<Self, QuantumError> {
+        check_num_qubits(num_qubits)?;
+        let size = 1 << num_qubits;
+        let mut rho = DMatrix::zeros(size, size);
+        rho[(0, 0)] = Complex64::new(1.0, 0.0);
+        Ok(Self { rho, num_qubits })
+    }
+    
+    /// |ψ⟩⟨ψ| for a pure state of at most `MAX_DENSITY_QUBITS` qubits.
+    pub fn from_state(state: &QuantumState) -> Result<Self, QuantumError> {
+        check_num_qubits(state.num_qubits)?;
+        Ok(Self {
+            rho: &state.amplitudes * state.amplitudes.adjoint(),
+            num_qubits: state.num_qubits,
+        })
+    }
+    
+    /// ρ → UρU†.
+    pub fn apply_single_qubit_gate(&mut self, gate: &[Complex64; 4], qubit: usize) -> Result<(), QuantumError> {
+        self.check_qubits(&[qubit])?;
+        self.rho = self.conjugated(gate, &[qubit]);
+        Ok(())
+    }
+    
+    /// ρ → UρU† with the (control, target) basis order of
+    /// `QuantumState::apply_two_qubit_gate`.
+    pub fn apply_two_qubit_gate(&mut self, gate: &[Complex64; 16], control: usize, target: usize) -> Result<(), QuantumError> {
+        self.check_qubits(&[control, target])?;
+        self.rho = self.conjugated(gate, &[control, target]);
+        Ok(())
+    }
+    
+    /// Apply a named built-in or registered gate, validated like the
+    /// statevector dispatch.
+    pub fn apply_gate(&mut self, op: &GateOp) -> Result<(), QuantumError> {
+        if let Some(problem) = gate_problems(op, self.num_qubits).into_iter().next() {
+            return Err(problem);
+        }
+        match gate_matrix(&op.name, &op.params) {
+            Some(GateMatrix::Single(gate)) => self.rho = self.conjugated(&gate, &op.qubits),
+            Some(GateMatrix::Two(gate)) => self.rho = self.conjugated(&gate, &op.qubits),
+            Some(GateMatrix::Three(gate)) => self.rho = self.conjugated(gate.as_slice(), &op.qubits),
+            None => return Err(no_matrix_error(&op.name)),
+        }
+        Ok(())
+    }
+    
+    /// ρ → Σ K ρ K† for a single-qubit channel. The operators must satisfy
+    /// Σ K†K = I so the trace is preserved.
+    pub fn apply_kraus(&mut self, ops: &[[Complex64; 4]], qubit: usize) -> Result<(), QuantumError> {
+        self.check_qubits(&[qubit])?;
+        let completeness: Vec<Complex64> = (0..4)
+            .map(|k| {
+                let (row, col) = (k / 2, k % 2);
+                ops.iter().map(|op| op[row].conj() * op[col] + op[2 + row].conj() * op[2 + col]).sum()
+            })
+            .collect();
+        let identity = [1.0, 0.0, 0.0, 1.0];
+        if completeness.iter().zip(identity).any(|(c, i)| (c - Complex64::new(i, 0.0)).norm() > KRAUS_TOLERANCE) {
+            return Err(QuantumError::InvalidArgument { reason: "Kraus operators do not satisfy Σ K†K = I".to_string() });
+        }
+    
+        let mut sum = DMatrix::zeros(self.rho.nrows(), self.rho.ncols());
+        for op in ops {
+            sum += self.conjugated(op, &[qubit]);
+        }
+        self.rho = sum;
+        Ok(())
+    }
+    
+    /// Exact counterpart of `QuantumState::apply_noise`, averaging over
+    /// every trajectory instead of sampling one.
+    pub fn apply_noise(&mut self, channel: &str, qubit: usize, p: f64) -> Result<(), QuantumError> {
+        check_probability(p)?;
+        let ops = match channel {
+            "depolarizing" => depolarizing_kraus(p),
+            "amplitude_damping" => amplitude_damping_kraus(p),
+            _ => return Err(QuantumError::InvalidArgument { reason: format!("unknown noise channel '{}'", channel) }),
+        };
+        self.apply_kraus(&ops, qubit)
+    }
+    
+    /// Diagonal of ρ in basis-index order.
+    pub fn probabilities(&self) -> Vec<f64> {
+        self.rho.diagonal().iter().map(|p| p.re).collect()
+    }
+    
+    /// Tr(ρ²): 1 for a pure state, down to 1/2ⁿ when maximally mixed. For a
+    /// Hermitian ρ this is the sum of |ρᵢⱼ|².
+    pub fn purity(&self) -> f64 {
+        self.rho.iter().map(|entry| entry.norm_sqr()).sum()
+    }
+    
+    pub fn is_pure(&self, tol: f64) -> bool {
+        (self.purity() - 1.0).abs() <= tol
+    }
+    
+    /// ½·Σ|λᵢ| over the eigenvalues of the Hermitian ρ − σ: 0 for identical
+    /// states, 1 for perfectly distinguishable ones.
+    pub fn trace_distance(&self, other: &DensityMatrix) -> Result<f64, QuantumError> {
+        if self.num_qubits != other.num_qubits {
+            return Err(QuantumError::DimensionMismatch {
+                expected: self.num_qubits,
+                found: other.num_qubits,
+            });
+        }
+        let difference = &self.rho - &other.rho;
+        Ok(0.5 * difference.symmetric_eigenvalues().iter().map(|lambda| lambda.abs()).sum::<f64>())
+    }
+    
+    /// Sample `shots` bitstrings from the diagonal without disturbing ρ.
+    pub fn measure_with_seed(&self, shots: usize, seed: u64) -> HashMap<String, usize> {
+        use rand::{Rng, SeedableRng};
+    
+        let cumulative: Vec<f64> = self.probabilities()
+            .iter()
+            .scan(0.0, |sum, p| {
+                *sum += p;
+                Some(*sum)
+            })
+            .collect();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let mut results = HashMap::new();
+        for _ in 0..shots {
+            let state = sample_from_cumulative(&cumulative, rng.gen());
+            let bitstring = format!("{:0width$b}", state, width = self.num_qubits);
+            *results.entry(bitstring).or_insert(0) += 1;
+        }
+        results
+    }
+    
+    fn check_qubits(&self, qubits: &[usize]) -> Result<(), QuantumError> {
+        for (i, &qubit) in qubits.iter().enumerate() {
+            if qubit >= self.num_qubits {
+                return Err(QuantumError::QubitOutOfRange { qubit, num_qubits: self.num_qubits });
+            }
+            if qubits[..i].contains(&qubit) {
+                return Err(QuantumError::IdenticalQubits { qubit });
+            }
+        }
+        Ok(())
+    }
+    
+    // U ρ U† for a dense gate on `qubits` (first listed most significant),
+    // applied to the rows and then the columns of each affected block
+    fn conjugated(&self, gate: &[Complex64], qubits: &[usize]) -> DMatrix<Complex64> {
+        let k = qubits.len();
+        let dim = 1 << k;
+        let mask: usize = qubits.iter().map(|&q| 1 << q).sum();
+        let offsets: Vec<usize> = (0..dim)
+            .map(|local| {
+                (0..k)
+                    .filter(|j| local & (1 << (k - 1 - j)) != 0)
+                    .map(|j| 1 << qubits[j])
+                    .sum()
+            })
+            .collect();
+        let size = self.rho.nrows();
+        let mut rho = self.rho.clone();
+        let mut group = vec![Complex64::new(0.0, 0.0); dim];
+    
+        for base in (0..size).filter(|i| i & mask == 0) {
+            for col in 0..size {
+                for (slot, &offset) in group.iter_mut().zip(&offsets) {
+                    *slot = rho[(base + offset, col)];
+                }
+                for (row, &offset) in offsets.iter().enumerate() {
+                    rho[(base + offset, col)] = (0..dim).map(|c| gate[row * dim + c] * group[c]).sum();
+                }
+            }
+        }
+        for base in (0..size).filter(|i| i & mask == 0) {
+            for row in 0..size {
+                for (slot, &offset) in group.iter_mut().zip(&offsets) {
+                    *slot = rho[(row, base + offset)];
+                }
+                for (col, &offset) in offsets.iter().enumerate() {
+                    rho[(row, base + offset)] = (0..dim).map(|c| group[c] * gate[col * dim + c].conj()).sum();
+                }
+            }
+        }
+        rho
+    }
+}
+
+fn check_num_qubits(num_qubits: usize) -> Result<(), QuantumError> {
+    if num_qubits > MAX_DENSITY_QUBITS {
+        return Err(QuantumError::TooManyQubits { requested: num_qubits, max: MAX_DENSITY_QUBITS });
+    }
+    Ok(())
+}
+
+/// Kraus operators of the depolarizing channel, matching the trajectory
+/// step: X, Y and Z each with probability p/3.
+pub fn depolarizing_kraus(p: f64) -> Vec<[Complex64; 4]> {
+    let scale = |gate: [Complex64; 4], weight: f64| gate.map(|entry| entry * weight.sqrt());
+    vec![
+        scale(Gates::identity(), 1.0 - p),
+        scale(Gates::pauli_x(), p / 3.0),
+        scale(Gates::pauli_y(), p / 3.0),
+        scale(Gates::pauli_z(), p / 3.0),
+    ]
+}
+
+/// Kraus operators K0 = [[1, 0], [0, √(1−γ)]] and K1 = [[0, √γ], [0, 0]].
+pub fn amplitude_damping_kraus(gamma: f64) -> Vec<[Complex64; 4]> {
+    let zero = Complex64::new(0.0, 0.0);
+    vec![
+        [Complex64::new(1.0, 0.0), zero, zero, Complex64::new((1.0 - gamma).sqrt(), 0.0)],
+        [zero, Complex64::new(gamma.sqrt(), 0.0), zero, zero],
+    ]
+}
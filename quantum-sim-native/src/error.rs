@@ -0,0 +1,54 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuantumError {
+    DimensionMismatch { expected: usize, found: usize },
+    BasisIndexOutOfRange { index: usize, dimension: usize },
+    IdenticalIndices { index: usize },
+    IdenticalQubits { qubit: usize },
+    LengthMismatch { expected: usize, found: usize },
+    UnknownGate { name: String },
+    QubitOutOfRange { qubit: usize, num_qubits: usize },
+    InvalidArgument { reason: String },
+    Qasm { line: usize, reason: String },
+    TooManyQubits { requested: usize, max: usize },
+}
+
+impl fmt::Display for QuantumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuantumError::DimensionMismatch { expected, found } => {
+                write!(f, "Dimension mismatch: expected {} qubits, found {}", expected, found)
+            }
+            QuantumError::BasisIndexOutOfRange { index, dimension } => {
+                write!(f, "Basis index {} out of range for dimension {}", index, dimension)
+            }
+            QuantumError::IdenticalIndices { index } => {
+                write!(f, "Indices must be distinct, got {} twice", index)
+            }
+            QuantumError::IdenticalQubits { qubit } => {
+                write!(f, "Qubits must be distinct, got {} twice", qubit)
+            }
+            QuantumError::LengthMismatch { expected, found } => {
+                write!(f, "Length mismatch: expected {} elements, found {}", expected, found)
+            }
+            QuantumError::UnknownGate { name } => {
+                write!(f, "Unknown gate: {}", name)
+            }
+            QuantumError::QubitOutOfRange { qubit, num_qubits } => {
+                write!(f, "Qubit {} out of range for {} qubits", qubit, num_qubits)
+            }
+            QuantumError::InvalidArgument { reason } => {
+                write!(f, "Invalid argument: {}", reason)
+            }
+            QuantumError::Qasm { line, reason } => {
+                write!(f, "QASM error on line {}: {}", line, reason)
+            }
+            QuantumError::TooManyQubits { requested, max } => {
+                write!(f, "Cannot allocate {} qubits: the limit is {}", requested, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for QuantumError {}
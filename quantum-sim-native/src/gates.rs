@@ -88,6 +88,12 @@ impl Gates {
         ]
     }
     
+    /// e^(iφ)·I. Unobservable on its own, but not once the gate is controlled.
+    pub fn global_phase(phi: f64) -> [Complex64; 4] {
+        let phase = Complex64::new(phi.cos(), phi.sin());
+        [phase, Complex64::new(0.0, 0.0), Complex64::new(0.0, 0.0), phase]
+    }
+    
     pub fn cnot() -> [Complex64; 16] {
         let mut gate = [Complex64::new(0.0, 0.0); 16];
         // |00⟩ → |00⟩ (index 0 -> 0)
@@ -119,6 +125,26 @@ impl Gates {
         gate
     }
     
+    pub fn iswap() -> [Complex64; 16] {
+        let mut gate = [Complex64::new(0.0, 0.0); 16];
+        gate[0] = Complex64::new(1.0, 0.0);   // |00⟩ → |00⟩
+        gate[6] = Complex64::new(0.0, 1.0);   // |01⟩ → i|10⟩
+        gate[9] = Complex64::new(0.0, 1.0);   // |10⟩ → i|01⟩
+        gate[15] = Complex64::new(1.0, 0.0);  // |11⟩ → |11⟩
+        gate
+    }
+    
+    pub fn sqrt_swap() -> [Complex64; 16] {
+        let mut gate = [Complex64::new(0.0, 0.0); 16];
+        gate[0] = Complex64::new(1.0, 0.0);
+        gate[5] = Complex64::new(0.5, 0.5);
+        gate[6] = Complex64::new(0.5, -0.5);
+        gate[9] = Complex64::new(0.5, -0.5);
+        gate[10] = Complex64::new(0.5, 0.5);
+        gate[15] = Complex64::new(1.0, 0.0);
+        gate
+    }
+    
     pub fn toffoli() -> [Complex64; 64] {
         let mut gate = [Complex64::new(0.0, 0.0); 64];
         // Identity for all states except |110⟩ and |111⟩
@@ -131,6 +157,17 @@ impl Gates {
         gate
     }
     
+    pub fn fredkin() -> [Complex64; 64] {
+        let mut gate = [Complex64::new(0.0, 0.0); 64];
+        // Identity except the control-1 block, where |101⟩ ↔ |110⟩
+        for i in [0, 1, 2, 3, 4, 7] {
+            gate[i * 8 + i] = Complex64::new(1.0, 0.0);
+        }
+        gate[5 * 8 + 6] = Complex64::new(1.0, 0.0); // |110⟩ → |101⟩
+        gate[6 * 8 + 5] = Complex64::new(1.0, 0.0); // |101⟩ → |110⟩
+        gate
+    }
+    
     pub fn controlled_phase(phi: f64) -> [Complex64; 16] {
         let mut gate = [Complex64::new(0.0, 0.0); 16];
         gate[0] = Complex64::new(1.0, 0.0);   // |00⟩ → |00⟩
@@ -140,6 +177,30 @@ impl Gates {
         gate
     }
     
+    pub fn controlled_rx(theta: f64) -> [Complex64; 16] {
+        Self::controlled(&Self::rotation_x(theta))
+    }
+    
+    pub fn controlled_ry(theta: f64) -> [Complex64; 16] {
+        Self::controlled(&Self::rotation_y(theta))
+    }
+    
+    pub fn controlled_rz(theta: f64) -> [Complex64; 16] {
+        Self::controlled(&Self::rotation_z(theta))
+    }
+    
+    // Identity on the control-0 block, `gate` on the control-1 block
+    fn controlled(gate: &[Complex64; 4]) -> [Complex64; 16] {
+        let mut controlled = [Complex64::new(0.0, 0.0); 16];
+        controlled[0] = Complex64::new(1.0, 0.0);
+        controlled[5] = Complex64::new(1.0, 0.0);
+        controlled[10] = gate[0];
+        controlled[11] = gate[1];
+        controlled[14] = gate[2];
+        controlled[15] = gate[3];
+        controlled
+    }
+    
     // Specialized gates for quantum algorithms
     pub fn qft_rotation(k: usize) -> [Complex64; 4] {
         let angle = 2.0 * PI / (1 << k) as f64;
@@ -177,4 +238,47 @@ impl Gates {
             ]
         }
     }
-}
\ No newline at end of file
+}
+
+/// Conjugate transpose of a row-major square gate of any size (4, 16 or 64
+/// entries for one, two or three qubits).
+pub fn dagger<const N: usize>(gate: &[Complex64; N]) -> [Complex64; N] {
+    let dim = (N as f64).sqrt().round() as usize;
+    std::array::from_fn(|k| gate[(k % dim) * dim + k / dim].conj())
+}
+
+/// Collapse a sequence of single-qubit gates into one matrix. Gates are
+/// listed in application order, so later gates multiply from the left.
+pub fn fuse_single_qubit(gates: &[[Complex64; 4]]) -> [Complex64; 4] {
+    let one = Complex64::new(1.0, 0.0);
+    let zero = Complex64::new(0.0, 0.0);
+    gates.iter().fold([one, zero, zero, one], |acc, g| {
+        [
+            g[0] * acc[0] + g[1] * acc[2],
+            g[0] * acc[1] + g[1] * acc[3],
+            g[2] * acc[0] + g[3] * acc[2],
+            g[2] * acc[1] + g[3] * acc[3],
+        ]
+    })
+}
+
+/// Check U·U† ≈ I for a row-major `dim`×`dim` matrix.
+pub fn is_unitary(matrix: &[Complex64], dim: usize, tol: f64) -> bool {
+    if matrix.len() != dim * dim {
+        return false;
+    }
+    
+    for row in 0..dim {
+        for col in 0..dim {
+            let entry: Complex64 = (0..dim)
+                .map(|k| matrix[row * dim + k] * matrix[col * dim + k].conj())
+                .sum();
+            let expected = if row == col { 1.0 } else { 0.0 };
+            if (entry - Complex64::new(expected, 0.0)).norm() > tol {
+                return false;
+            }
+        }
+    }
+    
+    true
+}
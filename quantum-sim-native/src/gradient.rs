@@ -0,0 +1,128 @@
+use num_complex::Complex64;
+use std::f64::consts::FRAC_PI_2;
+
+use crate::circuit::{apply_gate_ops, bind_parameters, GateOp, ParameterizedGate};
+use crate::error::QuantumError;
+use crate::pauli::{apply_terms, PauliMasks, PauliTerm};
+use crate::quantum_state::QuantumState;
+
+fn is_rotation(name: &str) -> bool {
+    matches!(name, "RX" | "RY" | "RZ")
+}
+
+fn fidelity_after(initial: &QuantumState, gates: &[GateOp], target: &[Complex64]) -> Result<f64, QuantumError> {
+    let mut state = initial.clone();
+    apply_gate_ops(&mut state, gates)?;
+    state.projector_expectation(target)
+}
+
+/// d/dθₖ |⟨target|U(θ)|ψ₀⟩|² by the parameter-shift rule. Every rotation
+/// bound to parameter `param_index` is shifted by ±π/2 in turn and the
+/// contributions summed, so a parameter may appear in several gates.
+pub fn fidelity_gradient(
+    initial: &QuantumState,
+    structure: &[ParameterizedGate],
+    params: &[f64],
+    param_index: usize,
+    target: &[Complex64],
+) -> Result<f64, QuantumError> {
+    if param_index >= params.len() {
+        return Err(QuantumError::InvalidArgument {
+            reason: format!("parameter index {} out of range for {} parameters", param_index, params.len()),
+        });
+    }
+    
+    let gates = bind_parameters(structure, params)?;
+    let mut gradient = 0.0;
+    
+    for (position, gate) in structure.iter().enumerate() {
+        if gate.param_index != Some(param_index) {
+            continue;
+        }
+        if !is_rotation(&gate.op.name) {
+            return Err(QuantumError::InvalidArgument {
+                reason: format!("parameter shift needs a rotation gate, got {}", gate.op.name),
+            });
+        }
+        
+        let mut shifted = gates.clone();
+        shifted[position].params[0] += FRAC_PI_2;
+        let plus = fidelity_after(initial, &shifted, target)?;
+        shifted[position].params[0] -= 2.0 * FRAC_PI_2;
+        let minus = fidelity_after(initial, &shifted, target)?;
+        
+        gradient += (plus - minus) / 2.0;
+    }
+    
+    Ok(gradient)
+}
+
+/// The full parameter-shift gradient in one sweep. The state just before
+/// each bound rotation is carried forward incrementally, so every shift only
+/// re-simulates the gates from that rotation onwards.
+pub fn fidelity_gradient_all(
+    initial: &QuantumState,
+    structure: &[ParameterizedGate],
+    params: &[f64],
+    target: &[Complex64],
+) -> Result<Vec<f64>, QuantumError> {
+    let gates = bind_parameters(structure, params)?;
+    let mut gradient = vec![0.0; params.len()];
+    let mut prefix = initial.clone();
+    
+    for (position, gate) in structure.iter().enumerate() {
+        if let Some(param_index) = gate.param_index {
+            if !is_rotation(&gate.op.name) {
+                return Err(QuantumError::InvalidArgument {
+                    reason: format!("parameter shift needs a rotation gate, got {}", gate.op.name),
+                });
+            }
+            
+            let mut shifted = gates[position..].to_vec();
+            shifted[0].params[0] += FRAC_PI_2;
+            let plus = fidelity_after(&prefix, &shifted, target)?;
+            shifted[0].params[0] -= 2.0 * FRAC_PI_2;
+            let minus = fidelity_after(&prefix, &shifted, target)?;
+            
+            gradient[param_index] += (plus - minus) / 2.0;
+        }
+        apply_gate_ops(&mut prefix, &gates[position..=position])?;
+    }
+    
+    Ok(gradient)
+}
+
+/// dE/dθ for E = ⟨O⟩ when the state was just produced by R_P(θ) = exp(−iθP/2).
+/// Differentiating the rotation gives dE/dθ = (i/2)⟨[P, O]⟩, and since
+/// (PO)† = OP that is −Im⟨Pψ|Oψ⟩: one pass over the state, no re-simulation.
+pub fn rotation_gradient(state: &QuantumState, generator: &[(usize, char)], observable: &[PauliTerm]) -> Result<f64, QuantumError> {
+    let amplitudes = state.amplitudes.as_slice();
+    let p_psi = PauliMasks::from_paulis(generator, state.num_qubits)?.apply_to(amplitudes);
+    let o_psi = apply_terms(observable, amplitudes, state.num_qubits)?;
+    
+    let inner: Complex64 = p_psi.iter().zip(&o_psi).map(|(a, b)| a.conj() * b).sum();
+    Ok(-inner.im)
+}
+
+/// `rotation_gradient` for the RX/RY/RZ at `gate_index` in the state's
+/// recorded history, which must be the last change to the state: a rotation
+/// followed by other gates, recorded or not, has a different derivative.
+pub fn history_rotation_gradient(state: &QuantumState, gate_index: usize, observable: &[PauliTerm]) -> Result<f64, QuantumError> {
+    let history = state.history();
+    let op = history.get(gate_index).ok_or_else(|| QuantumError::InvalidArgument {
+        reason: format!("gate index {} out of range for {} recorded gates", gate_index, history.len()),
+    })?;
+    if gate_index + 1 != history.len() || !state.history_is_current() {
+        return Err(QuantumError::InvalidArgument {
+            reason: format!("gate {} is not the last change to the state", gate_index),
+        });
+    }
+    if !is_rotation(&op.name) || op.qubits.len() != 1 {
+        return Err(QuantumError::InvalidArgument {
+            reason: format!("gate {} is {}, not a rotation", gate_index, op.name),
+        });
+    }
+    
+    let axis = op.name.chars().nth(1).unwrap();
+    rotation_gradient(state, &[(op.qubits[0], axis)], observable)
+}
@@ -1,33 +1,342 @@
 use neon::prelude::*;
+use neon::types::buffer::TypedArray;
 use std::collections::HashMap;
-use std::sync::{Mutex, LazyLock};
+use std::fmt;
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use num_complex::Complex64;
 
-mod quantum_state;
-mod gates;
+pub mod quantum_state;
+pub mod gates;
+pub mod error;
+pub mod circuit;
+pub mod benchmark;
+pub mod gradient;
+pub mod shots;
+pub mod pauli;
+pub mod qasm;
+pub mod density_matrix;
 
-use quantum_state::QuantumState;
-use gates::Gates;
+use quantum_state::{QuantumState, Sampler};
+use circuit::{apply_named_gate_with_inverse, GateOp, ParameterizedGate};
+use pauli::PauliTerm;
+use density_matrix::DensityMatrix;
 
 // Global storage for simulator instances
-static SIMULATORS: LazyLock<Mutex<HashMap<u32, QuantumState>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+// Each simulator has its own lock; the map lock is held only long enough to
+// look up or change the set of ids, so independent simulators run in parallel
+type SharedSimulator = Arc<Mutex<QuantumState>>;
+static SIMULATORS: LazyLock<RwLock<HashMap<u32, SharedSimulator>>> = LazyLock::new(|| RwLock::new(HashMap::new()));
 static NEXT_ID: LazyLock<Mutex<u32>> = LazyLock::new(|| Mutex::new(0));
 
-fn create_simulator(mut cx: FunctionContext) -> JsResult<JsNumber> {
-    let num_qubits = cx.argument::<JsNumber>(0)?.value(&mut cx) as usize;
-    
-    let simulator = QuantumState::new(num_qubits);
-    let mut simulators = SIMULATORS.lock().unwrap();
+// Samplers snapshot a simulator's distribution and outlive later mutations
+static SAMPLERS: LazyLock<Mutex<HashMap<u32, Sampler>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+static NEXT_SAMPLER_ID: LazyLock<Mutex<u32>> = LazyLock::new(|| Mutex::new(0));
+
+// Density matrices live in their own id space, parallel to the statevectors
+static DENSITY_MATRICES: LazyLock<Mutex<HashMap<u32, DensityMatrix>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+static NEXT_DENSITY_ID: LazyLock<Mutex<u32>> = LazyLock::new(|| Mutex::new(0));
+
+fn js_array_to_f64s(cx: &mut FunctionContext, array: Handle<JsArray>) -> NeonResult<Vec<f64>> {
+    let length = array.len(cx);
+    let mut values = Vec::with_capacity(length as usize);
+    for i in 0..length {
+        let value: Handle<JsNumber> = array.get(cx, i)?;
+        values.push(value.value(cx));
+    }
+    Ok(values)
+}
+
+// Statevectors cross the FFI boundary as interleaved [re0, im0, re1, im1, ...]
+fn complex_from_flat(cx: &mut FunctionContext, flat: &[f64]) -> NeonResult<Vec<Complex64>> {
+    if !flat.len().is_multiple_of(2) {
+        return cx.throw_error("Flat statevector must contain (re, im) pairs");
+    }
+    Ok(flat
+        .chunks_exact(2)
+        .map(|pair| Complex64::new(pair[0], pair[1]))
+        .collect())
+}
+
+// Single complex numbers cross as {re, im} objects
+fn js_complex_objects(cx: &mut FunctionContext, array: Handle<JsArray>) -> NeonResult<Vec<Complex64>> {
+    let length = array.len(cx);
+    let mut values = Vec::with_capacity(length as usize);
+    for i in 0..length {
+        let value: Handle<JsObject> = array.get(cx, i)?;
+        let re = value.get::<JsNumber, _, _>(cx, "re")?.value(cx);
+        let im = value.get::<JsNumber, _, _>(cx, "im")?.value(cx);
+        values.push(Complex64::new(re, im));
+    }
+    Ok(values)
+}
+
+fn js_gate_op(cx: &mut FunctionContext, gate_js: Handle<JsObject>) -> NeonResult<GateOp> {
+    let name = gate_js.get::<JsString, _, _>(cx, "gate")?.value(cx);
+    let qubits_js = gate_js.get::<JsArray, _, _>(cx, "qubits")?;
+    let qubits = js_array_to_f64s(cx, qubits_js)?
+        .into_iter()
+        .map(|q| q as usize)
+        .collect();
+    let params = match gate_js.get_opt::<JsArray, _, _>(cx, "params")? {
+        Some(params_js) => js_array_to_f64s(cx, params_js)?,
+        None => Vec::new(),
+    };
+    Ok(GateOp { name, qubits, params })
+}
+
+// Gate lists cross the FFI boundary as arrays of {gate, qubits, params}
+fn js_gate_ops(cx: &mut FunctionContext, array: Handle<JsArray>) -> NeonResult<Vec<GateOp>> {
+    let length = array.len(cx);
+    let mut ops = Vec::with_capacity(length as usize);
+    for i in 0..length {
+        let gate_js: Handle<JsObject> = array.get(cx, i)?;
+        ops.push(js_gate_op(cx, gate_js)?);
+    }
+    Ok(ops)
+}
+
+fn gate_ops_to_js<'a>(cx: &mut FunctionContext<'a>, ops: &[GateOp]) -> JsResult<'a, JsArray> {
+    let array = cx.empty_array();
+    for (i, op) in ops.iter().enumerate() {
+        let gate_obj = cx.empty_object();
+        let name = cx.string(&op.name);
+        gate_obj.set(cx, "gate", name)?;
+        let qubits = cx.empty_array();
+        for (j, &q) in op.qubits.iter().enumerate() {
+            let value = cx.number(q as f64);
+            qubits.set(cx, j as u32, value)?;
+        }
+        gate_obj.set(cx, "qubits", qubits)?;
+        let params = cx.empty_array();
+        for (j, &p) in op.params.iter().enumerate() {
+            let value = cx.number(p);
+            params.set(cx, j as u32, value)?;
+        }
+        gate_obj.set(cx, "params", params)?;
+        array.set(cx, i as u32, gate_obj)?;
+    }
+    Ok(array)
+}
+
+// Circuit structures add an optional `paramIndex` binding a gate's angle
+fn js_parameterized_gates(cx: &mut FunctionContext, array: Handle<JsArray>) -> NeonResult<Vec<ParameterizedGate>> {
+    let length = array.len(cx);
+    let mut gates = Vec::with_capacity(length as usize);
+    for i in 0..length {
+        let gate_js: Handle<JsObject> = array.get(cx, i)?;
+        let op = js_gate_op(cx, gate_js)?;
+        let param_index = gate_js
+            .get_opt::<JsNumber, _, _>(cx, "paramIndex")?
+            .map(|index| index.value(cx) as usize);
+        gates.push(ParameterizedGate { op, param_index });
+    }
+    Ok(gates)
+}
+
+// Hamiltonians cross the FFI boundary as arrays of {coefficient, paulis}
+// where paulis is a string whose k-th character acts on qubit k
+fn js_pauli_terms(cx: &mut FunctionContext, array: Handle<JsArray>) -> NeonResult<Vec<PauliTerm>> {
+    let length = array.len(cx);
+    let mut terms = Vec::with_capacity(length as usize);
+    for i in 0..length {
+        let term_js: Handle<JsObject> = array.get(cx, i)?;
+        let coefficient = term_js.get::<JsNumber, _, _>(cx, "coefficient")?.value(cx);
+        let paulis = term_js.get::<JsString, _, _>(cx, "paulis")?.value(cx);
+        terms.push(PauliTerm {
+            coefficient,
+            paulis: pauli::parse_pauli_string(&paulis),
+        });
+    }
+    Ok(terms)
+}
+
+// The SIMULATORS lock is always taken before NEXT_ID
+fn insert_simulator(simulator: QuantumState) -> u32 {
+    let mut simulators = SIMULATORS.write().unwrap();
     let mut next_id = NEXT_ID.lock().unwrap();
     
     let id = *next_id;
     *next_id += 1;
-    simulators.insert(id, simulator);
+    simulators.insert(id, Arc::new(Mutex::new(simulator)));
+    
+    id
+}
+
+fn simulator_handle(sim_id: u32) -> Option<SharedSimulator> {
+    SIMULATORS.read().unwrap().get(&sim_id).cloned()
+}
+
+/// A simulator owned by its JS handle rather than the global store; it is
+/// freed when the handle is garbage-collected, so no destroy call is needed.
+///
+/// ```js
+/// let sim = native.createBoxedSimulator(2);
+/// native.applyGate(sim, "H", [0], []);
+/// native.getStateProbabilities(sim); // [0.5, 0.5, 0, 0]
+/// sim = null; // the state is released on the next GC
+/// ```
+pub struct BoxedSimulator(SharedSimulator);
+
+impl Finalize for BoxedSimulator {}
+
+// Every export's simulator argument: a numeric id into SIMULATORS or a
+// `BoxedSimulator` handle
+enum SimulatorRef {
+    Id(u32),
+    Boxed(SharedSimulator),
+}
+
+impl SimulatorRef {
+    fn handle(&self) -> Option<SharedSimulator> {
+        match self {
+            SimulatorRef::Id(id) => simulator_handle(*id),
+            SimulatorRef::Boxed(handle) => Some(Arc::clone(handle)),
+        }
+    }
+}
+
+impl fmt::Display for SimulatorRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimulatorRef::Id(id) => write!(f, "{}", id),
+            SimulatorRef::Boxed(_) => write!(f, "<boxed>"),
+        }
+    }
+}
+
+fn simulator_arg(cx: &mut FunctionContext, index: usize) -> NeonResult<SimulatorRef> {
+    let arg = cx.argument::<JsValue>(index)?;
+    if let Ok(boxed) = arg.downcast::<JsBox<BoxedSimulator>, _>(cx) {
+        return Ok(SimulatorRef::Boxed(Arc::clone(&boxed.0)));
+    }
+    let id = arg.downcast_or_throw::<JsNumber, _>(cx)?.value(cx) as u32;
+    Ok(SimulatorRef::Id(id))
+}
+
+// Lock two simulators for a read-only comparison. Distinct simulators are
+// locked in address order so concurrent callers cannot deadlock; the same
+// simulator is locked once and passed as both arguments.
+fn with_simulator_pair<T>(sim_id1: &SimulatorRef, sim_id2: &SimulatorRef, f: impl FnOnce(&QuantumState, &QuantumState) -> T) -> Option<T> {
+    let handle1 = sim_id1.handle()?;
+    let handle2 = sim_id2.handle()?;
+    if Arc::ptr_eq(&handle1, &handle2) {
+        let simulator = handle1.lock().unwrap();
+        return Some(f(&simulator, &simulator));
+    }
+    
+    let in_order = Arc::as_ptr(&handle1) < Arc::as_ptr(&handle2);
+    let (first, second) = if in_order { (&handle1, &handle2) } else { (&handle2, &handle1) };
+    let first = first.lock().unwrap();
+    let second = second.lock().unwrap();
+    if in_order {
+        Some(f(&first, &second))
+    } else {
+        Some(f(&second, &first))
+    }
+}
+
+fn counts_to_js<'a, C: Context<'a>>(cx: &mut C, counts: HashMap<String, usize>) -> JsResult<'a, JsObject> {
+    let js_results = cx.empty_object();
+    for (bitstring, count) in counts {
+        let js_key = cx.string(bitstring);
+        let js_value = cx.number(count as f64);
+        js_results.set(cx, js_key, js_value)?;
+    }
+    Ok(js_results)
+}
+
+fn create_simulator(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let num_qubits = cx.argument::<JsNumber>(0)?.value(&mut cx) as usize;
+    
+    let simulator = match QuantumState::new(num_qubits) {
+        Ok(simulator) => simulator,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    let id = insert_simulator(simulator);
+    
+    Ok(cx.number(id as f64))
+}
+
+fn create_boxed_simulator(mut cx: FunctionContext) -> JsResult<JsBox<BoxedSimulator>> {
+    let num_qubits = cx.argument::<JsNumber>(0)?.value(&mut cx) as usize;
+    
+    let simulator = match QuantumState::new(num_qubits) {
+        Ok(simulator) => simulator,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    
+    Ok(cx.boxed(BoxedSimulator(Arc::new(Mutex::new(simulator)))))
+}
+
+// One [{re, im}, {re, im}] pair per qubit, qubit 0 first
+fn create_product_state(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let states_js = cx.argument::<JsArray>(0)?;
+    
+    let length = states_js.len(&mut cx);
+    let mut states = Vec::with_capacity(length as usize);
+    for i in 0..length {
+        let state_js: Handle<JsArray> = states_js.get(&mut cx, i)?;
+        let entries = js_complex_objects(&mut cx, state_js)?;
+        match <[Complex64; 2]>::try_from(entries) {
+            Ok(state) => states.push(state),
+            Err(entries) => {
+                let e = error::QuantumError::LengthMismatch { expected: 2, found: entries.len() };
+                return cx.throw_error(e.to_string());
+            },
+        }
+    }
+    
+    let simulator = match QuantumState::from_product(&states) {
+        Ok(simulator) => simulator,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    let id = insert_simulator(simulator);
+    
+    Ok(cx.number(id as f64))
+}
+
+fn create_ghz(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let num_qubits = cx.argument::<JsNumber>(0)?.value(&mut cx) as usize;
+    
+    let simulator = match QuantumState::ghz(num_qubits) {
+        Ok(simulator) => simulator,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    let id = insert_simulator(simulator);
+    
+    Ok(cx.number(id as f64))
+}
+
+fn create_w(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let num_qubits = cx.argument::<JsNumber>(0)?.value(&mut cx) as usize;
+    
+    let simulator = match QuantumState::w_state(num_qubits) {
+        Ok(simulator) => simulator,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    let id = insert_simulator(simulator);
+    
+    Ok(cx.number(id as f64))
+}
+
+// The state arrives as an interleaved flat [re0, im0, re1, im1, ...] array
+fn create_simulator_from_state(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let state_js = cx.argument::<JsArray>(0)?;
+    
+    let flat = js_array_to_f64s(&mut cx, state_js)?;
+    let amplitudes = complex_from_flat(&mut cx, &flat)?;
+    let simulator = match QuantumState::from_amplitudes(amplitudes) {
+        Ok(simulator) => simulator,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    
+    let id = insert_simulator(simulator);
     
     Ok(cx.number(id as f64))
 }
 
 fn apply_gate(mut cx: FunctionContext) -> JsResult<JsBoolean> {
-    let sim_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    let sim_id = simulator_arg(&mut cx, 0)?;
     let gate_name = cx.argument::<JsString>(1)?.value(&mut cx);
     let qubits_js = cx.argument::<JsArray>(2)?;
     let params_js = cx.argument::<JsArray>(3)?;
@@ -48,174 +357,4237 @@ fn apply_gate(mut cx: FunctionContext) -> JsResult<JsBoolean> {
         params.push(param.value(&mut cx));
     }
     
-    let mut simulators = SIMULATORS.lock().unwrap();
-    if let Some(simulator) = simulators.get_mut(&sim_id) {
-        match gate_name.as_str() {
-            "H" => {
-                let gate = Gates::hadamard();
-                simulator.apply_single_qubit_gate(&gate, qubits[0]);
-            },
-            "X" => {
-                let gate = Gates::pauli_x();
-                simulator.apply_single_qubit_gate(&gate, qubits[0]);
-            },
-            "Y" => {
-                let gate = Gates::pauli_y();
-                simulator.apply_single_qubit_gate(&gate, qubits[0]);
-            },
-            "Z" => {
-                let gate = Gates::pauli_z();
-                simulator.apply_single_qubit_gate(&gate, qubits[0]);
-            },
-            "S" => {
-                let gate = Gates::s_gate();
-                simulator.apply_single_qubit_gate(&gate, qubits[0]);
-            },
-            "T" => {
-                let gate = Gates::t_gate();
-                simulator.apply_single_qubit_gate(&gate, qubits[0]);
-            },
-            "RX" => {
-                let gate = Gates::rotation_x(params[0]);
-                simulator.apply_single_qubit_gate(&gate, qubits[0]);
-            },
-            "RY" => {
-                let gate = Gates::rotation_y(params[0]);
-                simulator.apply_single_qubit_gate(&gate, qubits[0]);
-            },
-            "RZ" => {
-                let gate = Gates::rotation_z(params[0]);
-                simulator.apply_single_qubit_gate(&gate, qubits[0]);
-            },
-            "CNOT" | "CX" => {
-                let gate = Gates::cnot();
-                simulator.apply_two_qubit_gate(&gate, qubits[0], qubits[1]);
-            },
-            "CZ" => {
-                let gate = Gates::cz();
-                simulator.apply_two_qubit_gate(&gate, qubits[0], qubits[1]);
-            },
-            "SWAP" => {
-                let gate = Gates::swap();
-                simulator.apply_two_qubit_gate(&gate, qubits[0], qubits[1]);
+    let inverse = match cx.argument_opt(4) {
+        Some(arg) if arg.is_a::<JsBoolean, _>(&mut cx) => arg.downcast_or_throw::<JsBoolean, _>(&mut cx)?.value(&mut cx),
+        _ => false,
+    };
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        let qubits = simulator.physical_qubits(&qubits);
+        match apply_named_gate_with_inverse(&mut simulator, &gate_name, &qubits, &params, inverse) {
+            Ok(applied) => Ok(cx.boolean(applied)),
+            Err(e @ error::QuantumError::UnknownGate { .. }) => {
+                cx.throw_error(format!("{}. Supported gates: {}", e, circuit::supported_gate_names().join(", ")))
             },
-            _ => return Ok(cx.boolean(false)),
+            Err(e) => cx.throw_error(e.to_string()),
         }
-        Ok(cx.boolean(true))
     } else {
         Ok(cx.boolean(false))
     }
 }
 
-fn measure_qubits(mut cx: FunctionContext) -> JsResult<JsObject> {
-    let sim_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
-    let shots = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+// The matrix is four {re, im} entries in row-major order
+fn apply_unitary(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let matrix_js = cx.argument::<JsArray>(1)?;
+    let qubit = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
     
-    let simulators = SIMULATORS.lock().unwrap();
-    if let Some(simulator) = simulators.get(&sim_id) {
-        let results = simulator.measure(shots);
-        
-        let js_results = cx.empty_object();
-        for (bitstring, count) in results {
-            let js_key = cx.string(bitstring);
-            let js_value = cx.number(count as f64);
-            js_results.set(&mut cx, js_key, js_value)?;
+    let entries = js_complex_objects(&mut cx, matrix_js)?;
+    let matrix: [Complex64; 4] = match entries.try_into() {
+        Ok(matrix) => matrix,
+        Err(entries) => return cx.throw_error(error::QuantumError::LengthMismatch { expected: 4, found: entries.len() }.to_string()),
+    };
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        let qubit = simulator.physical_qubits(&[qubit])[0];
+        match simulator.apply_unitary(&matrix, qubit) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
         }
-        
-        Ok(js_results)
     } else {
-        Ok(cx.empty_object())
+        Ok(cx.boolean(false))
     }
 }
 
-fn get_state_probabilities(mut cx: FunctionContext) -> JsResult<JsArray> {
-    let sim_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+fn apply_circuit(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let gates_js = cx.argument::<JsArray>(1)?;
     
-    let simulators = SIMULATORS.lock().unwrap();
-    if let Some(simulator) = simulators.get(&sim_id) {
-        let probabilities = simulator.get_probabilities();
-        
-        let js_array = cx.empty_array();
-        for (i, prob) in probabilities.iter().enumerate() {
-            let js_value = cx.number(*prob);
-            js_array.set(&mut cx, i as u32, js_value)?;
+    let gates = js_gate_ops(&mut cx, gates_js)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match circuit::apply_gate_ops(&mut simulator, &gates) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
         }
-        
-        Ok(js_array)
     } else {
-        Ok(cx.empty_array())
+        Ok(cx.boolean(false))
     }
 }
 
-fn get_fidelity(mut cx: FunctionContext) -> JsResult<JsNumber> {
-    let sim_id1 = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
-    let sim_id2 = cx.argument::<JsNumber>(1)?.value(&mut cx) as u32;
+/// Batched `applyGate`: one lock and one validation pass for the whole list,
+/// with qubits remapped through the layout like single gates.
+fn apply_gates(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let gates_js = cx.argument::<JsArray>(1)?;
+    
+    let mut gates = js_gate_ops(&mut cx, gates_js)?;
     
-    let simulators = SIMULATORS.lock().unwrap();
-    if let (Some(sim1), Some(sim2)) = (simulators.get(&sim_id1), simulators.get(&sim_id2)) {
-        let fidelity = sim1.get_fidelity(sim2);
-        Ok(cx.number(fidelity))
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        for op in gates.iter_mut() {
+            op.qubits = simulator.physical_qubits(&op.qubits);
+        }
+        match circuit::apply_gate_ops_indexed(&mut simulator, &gates) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err((index, problem)) => cx.throw_error(format!("Gate {}: {}", index, problem)),
+        }
     } else {
-        Ok(cx.number(0.0))
+        Ok(cx.boolean(false))
     }
 }
 
-fn destroy_simulator(mut cx: FunctionContext) -> JsResult<JsBoolean> {
-    let sim_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+// Takes {numQubits, gates, shots, seed?}; no simulator is left behind
+fn run_circuit(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let spec = cx.argument::<JsObject>(0)?;
+    let num_qubits = spec.get::<JsNumber, _, _>(&mut cx, "numQubits")?.value(&mut cx) as usize;
+    let gates_js = spec.get::<JsArray, _, _>(&mut cx, "gates")?;
+    let shots = spec.get::<JsNumber, _, _>(&mut cx, "shots")?.value(&mut cx) as usize;
+    let seed = match spec.get_opt::<JsNumber, _, _>(&mut cx, "seed")? {
+        Some(seed) => seed.value(&mut cx) as u64,
+        None => {
+            use rand::Rng;
+            rand::thread_rng().gen()
+        }
+    };
     
-    let mut simulators = SIMULATORS.lock().unwrap();
-    let removed = simulators.remove(&sim_id).is_some();
+    let gates = js_gate_ops(&mut cx, gates_js)?;
+    match circuit::run_circuit(num_qubits, &gates, shots, seed) {
+        Ok(counts) => counts_to_js(&mut cx, counts),
+        Err(e) => cx.throw_error(e.to_string()),
+    }
+}
+
+fn export_qasm(mut cx: FunctionContext) -> JsResult<JsString> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
     
-    Ok(cx.boolean(removed))
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    match simulator.as_deref().map(|simulator| simulator.to_qasm2()) {
+        Some(Ok(program)) => Ok(cx.string(program)),
+        Some(Err(e)) => cx.throw_error(e.to_string()),
+        None => cx.throw_error(format!("Unknown simulator id {}", sim_id)),
+    }
 }
 
-#[neon::main]
-fn main(mut cx: ModuleContext) -> NeonResult<()> {
-    cx.export_function("createSimulator", create_simulator)?;
-    cx.export_function("applyGate", apply_gate)?;
-    cx.export_function("measureQubits", measure_qubits)?;
-    cx.export_function("getStateProbabilities", get_state_probabilities)?;
-    cx.export_function("getFidelity", get_fidelity)?;
-    cx.export_function("destroySimulator", destroy_simulator)?;
-    Ok(())
+// Gate recording is off by default; `exportQasm` and
+// `analyticRotationGradient` need it switched on first
+fn set_record_history(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let enabled = cx.argument::<JsBoolean>(1)?.value(&mut cx);
+    
+    if let Some(handle) = sim_id.handle() {
+        handle.lock().unwrap().set_record_history(enabled);
+        Ok(cx.boolean(true))
+    } else {
+        Ok(cx.boolean(false))
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn clear_history(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
     
-    #[test]
-    fn test_simulator_creation() {
-        let sim = QuantumState::new(2);
-        assert_eq!(sim.num_qubits, 2);
-        assert_eq!(sim.amplitudes.len(), 4);
+    if let Some(handle) = sim_id.handle() {
+        handle.lock().unwrap().clear_history();
+        Ok(cx.boolean(true))
+    } else {
+        Ok(cx.boolean(false))
     }
+}
+
+fn run_qasm(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let source = cx.argument::<JsString>(0)?.value(&mut cx);
+    let shots = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let seed = optional_seed(&mut cx, 2)?.unwrap_or_else(|| {
+        use rand::Rng;
+        rand::thread_rng().gen()
+    });
     
-    #[test]
-    fn test_hadamard_gate() {
-        let mut sim = QuantumState::new(1);
-        let h_gate = Gates::hadamard();
-        sim.apply_single_qubit_gate(&h_gate, 0);
-        
-        let probs = sim.get_probabilities();
-        assert!((probs[0] - 0.5).abs() < 1e-10);
-        assert!((probs[1] - 0.5).abs() < 1e-10);
+    match qasm::parse_qasm2(&source).and_then(|program| program.run(shots, seed)) {
+        Ok(counts) => counts_to_js(&mut cx, counts),
+        Err(e) => cx.throw_error(e.to_string()),
     }
+}
+
+fn apply_circuit_binary(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let buffer = cx.argument::<JsBuffer>(1)?;
     
-    #[test]
-    fn test_cnot_bell_state() {
-        let mut sim = QuantumState::new(2);
-        let h_gate = Gates::hadamard();
-        let cnot_gate = Gates::cnot();
-        
-        sim.apply_single_qubit_gate(&h_gate, 0);
-        sim.apply_two_qubit_gate(&cnot_gate, 0, 1);
-        
-        let probs = sim.get_probabilities();
-        // Should create bell state: |00⟩ + |11⟩
-        assert!((probs[0] - 0.5).abs() < 1e-10); // |00⟩
-        assert!(probs[1] < 1e-10); // |01⟩ should be ~0
-        assert!(probs[2] < 1e-10); // |10⟩ should be ~0
-        assert!((probs[3] - 0.5).abs() < 1e-10); // |11⟩
+    let gates = match circuit::decode_circuit(buffer.as_slice(&cx)) {
+        Ok(gates) => gates,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match circuit::apply_gate_ops(&mut simulator, &gates) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn encode_circuit(mut cx: FunctionContext) -> JsResult<JsBuffer> {
+    let gates_js = cx.argument::<JsArray>(0)?;
+    let gates = js_gate_ops(&mut cx, gates_js)?;
+    
+    match circuit::encode_circuit(&gates) {
+        Ok(bytes) => JsBuffer::from_slice(&mut cx, &bytes),
+        Err(e) => cx.throw_error(e.to_string()),
+    }
+}
+
+fn apply_circuit_async(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let gates_js = cx.argument::<JsArray>(1)?;
+    
+    let gates = js_gate_ops(&mut cx, gates_js)?;
+    
+    let promise = cx
+        .task(move || {
+            match sim_id.handle() {
+                Some(handle) => circuit::apply_gate_ops(&mut handle.lock().unwrap(), &gates).map(|()| true),
+                None => Ok(false),
+            }
+        })
+        .promise(|mut cx, result| match result {
+            Ok(applied) => Ok(cx.boolean(applied)),
+            Err(e) => cx.throw_error(e.to_string()),
+        });
+    
+    Ok(promise)
+}
+
+fn register_gate(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let name = cx.argument::<JsString>(0)?.value(&mut cx);
+    let matrix_js = cx.argument::<JsArray>(1)?;
+    let arity = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    
+    let flat = js_array_to_f64s(&mut cx, matrix_js)?;
+    let matrix = complex_from_flat(&mut cx, &flat)?;
+    
+    match circuit::register_gate(&name, &matrix, arity) {
+        Ok(()) => Ok(cx.undefined()),
+        Err(e) => cx.throw_error(e.to_string()),
+    }
+}
+
+fn unregister_gate(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let name = cx.argument::<JsString>(0)?.value(&mut cx);
+    Ok(cx.boolean(circuit::unregister_gate(&name)))
+}
+
+fn list_supported_gates(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let js_array = cx.empty_array();
+    for (i, info) in circuit::BUILTIN_GATES.iter().enumerate() {
+        let js_info = cx.empty_object();
+        let name = cx.string(info.name);
+        js_info.set(&mut cx, "name", name)?;
+        let num_qubits = cx.number(info.num_qubits as f64);
+        js_info.set(&mut cx, "numQubits", num_qubits)?;
+        let num_params = cx.number(info.num_params as f64);
+        js_info.set(&mut cx, "numParams", num_params)?;
+        let is_parameterized = cx.boolean(info.is_parameterized());
+        js_info.set(&mut cx, "isParameterized", is_parameterized)?;
+        let is_diagonal = cx.boolean(info.is_diagonal);
+        js_info.set(&mut cx, "isDiagonal", is_diagonal)?;
+        js_array.set(&mut cx, i as u32, js_info)?;
+    }
+    
+    Ok(js_array)
+}
+
+fn validate_circuit(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let num_qubits = cx.argument::<JsNumber>(0)?.value(&mut cx) as usize;
+    let gates_js = cx.argument::<JsArray>(1)?;
+    
+    let gates = js_gate_ops(&mut cx, gates_js)?;
+    let problems = circuit::validate_circuit(num_qubits, &gates);
+    
+    let js_array = cx.empty_array();
+    for (i, (gate_index, problem)) in problems.iter().enumerate() {
+        let js_problem = cx.empty_object();
+        let js_index = cx.number(*gate_index as f64);
+        js_problem.set(&mut cx, "gateIndex", js_index)?;
+        let js_message = cx.string(problem.to_string());
+        js_problem.set(&mut cx, "message", js_message)?;
+        js_array.set(&mut cx, i as u32, js_problem)?;
+    }
+    
+    Ok(js_array)
+}
+
+fn light_cone(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let gates_js = cx.argument::<JsArray>(0)?;
+    let target = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let num_qubits = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    
+    let gates = js_gate_ops(&mut cx, gates_js)?;
+    let cone = match circuit::light_cone(&gates, target, num_qubits) {
+        Ok(cone) => cone,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    
+    let js_array = cx.empty_array();
+    for (i, index) in cone.iter().enumerate() {
+        let js_value = cx.number(*index as f64);
+        js_array.set(&mut cx, i as u32, js_value)?;
+    }
+    
+    Ok(js_array)
+}
+
+fn transpile_for_coupling(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let gates_js = cx.argument::<JsArray>(0)?;
+    let num_qubits = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let coupling_js = cx.argument::<JsArray>(2)?;
+    
+    let gates = js_gate_ops(&mut cx, gates_js)?;
+    
+    // couplingMap is an array of [a, b] pairs
+    let mut coupling = Vec::new();
+    let length = coupling_js.len(&mut cx);
+    for i in 0..length {
+        let pair_js: Handle<JsArray> = coupling_js.get(&mut cx, i)?;
+        let pair = js_array_to_f64s(&mut cx, pair_js)?;
+        if pair.len() != 2 {
+            return cx.throw_error(format!("Coupling entry {} must be a pair of qubits", i));
+        }
+        coupling.push((pair[0] as usize, pair[1] as usize));
+    }
+    
+    let (routed, layout) = match circuit::transpile_for_coupling(&gates, num_qubits, &coupling) {
+        Ok(result) => result,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    
+    let gates_out = gate_ops_to_js(&mut cx, &routed)?;
+    
+    let layout_out = cx.empty_array();
+    for (i, &physical) in layout.iter().enumerate() {
+        let value = cx.number(physical as f64);
+        layout_out.set(&mut cx, i as u32, value)?;
+    }
+    
+    let result = cx.empty_object();
+    result.set(&mut cx, "gates", gates_out)?;
+    result.set(&mut cx, "layout", layout_out)?;
+    Ok(result)
+}
+
+fn pauli_twirl(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let gates_js = cx.argument::<JsArray>(0)?;
+    let seed = cx.argument::<JsNumber>(1)?.value(&mut cx) as u64;
+    
+    let gates = js_gate_ops(&mut cx, gates_js)?;
+    let twirled = circuit::pauli_twirl(&gates, seed);
+    
+    gate_ops_to_js(&mut cx, &twirled)
+}
+
+fn apply_single_qubit_layer(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let specs_js = cx.argument::<JsArray>(1)?;
+    
+    // specs[q] is {name, params} or null/undefined to skip qubit q
+    let mut specs = Vec::new();
+    let length = specs_js.len(&mut cx);
+    for i in 0..length {
+        let spec_js: Handle<JsValue> = specs_js.get(&mut cx, i)?;
+        let spec = match spec_js.downcast::<JsObject, _>(&mut cx) {
+            Ok(spec_js) => {
+                let name = spec_js.get::<JsString, _, _>(&mut cx, "name")?.value(&mut cx);
+                let params = match spec_js.get_opt::<JsArray, _, _>(&mut cx, "params")? {
+                    Some(params_js) => js_array_to_f64s(&mut cx, params_js)?,
+                    None => Vec::new(),
+                };
+                Some((name, params))
+            },
+            Err(_) => None,
+        };
+        specs.push(spec);
     }
-}
\ No newline at end of file
+    
+    let gates = circuit::single_qubit_layer(&specs);
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match circuit::apply_gate_ops(&mut simulator, &gates) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn apply_controlled_subcircuit(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let control = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let gates_js = cx.argument::<JsArray>(2)?;
+    
+    let gates = js_gate_ops(&mut cx, gates_js)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match circuit::apply_controlled_subcircuit(&mut simulator, control, &gates) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn apply_all_to_all_layer(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let gate_name = cx.argument::<JsString>(1)?.value(&mut cx);
+    let params = match cx.argument_opt(2) {
+        Some(arg) if arg.is_a::<JsArray, _>(&mut cx) => {
+            let params_js = arg.downcast_or_throw::<JsArray, _>(&mut cx)?;
+            js_array_to_f64s(&mut cx, params_js)?
+        },
+        _ => Vec::new(),
+    };
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match circuit::apply_all_to_all_layer(&mut simulator, &gate_name, &params) {
+            Ok(_) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn apply_gate_where_marginal(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let gate_name = cx.argument::<JsString>(1)?.value(&mut cx);
+    let min_prob = cx.argument::<JsNumber>(2)?.value(&mut cx);
+    let max_prob = cx.argument::<JsNumber>(3)?.value(&mut cx);
+    let params = match cx.argument_opt(4) {
+        Some(arg) if arg.is_a::<JsArray, _>(&mut cx) => {
+            let params_js = arg.downcast_or_throw::<JsArray, _>(&mut cx)?;
+            js_array_to_f64s(&mut cx, params_js)?
+        },
+        _ => Vec::new(),
+    };
+    
+    let handle = sim_id.handle();
+    let mut simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let targets = match simulator.as_deref_mut() {
+        Some(simulator) => match circuit::apply_gate_where_marginal(simulator, &gate_name, min_prob, max_prob, &params) {
+            Ok(targets) => targets,
+            Err(e) => return cx.throw_error(e.to_string()),
+        },
+        None => Vec::new(),
+    };
+    drop(simulator);
+    
+    let js_array = cx.empty_array();
+    for (i, qubit) in targets.iter().enumerate() {
+        let js_value = cx.number(*qubit as f64);
+        js_array.set(&mut cx, i as u32, js_value)?;
+    }
+    Ok(js_array)
+}
+
+fn apply_layer_with_idle_noise(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let gates_js = cx.argument::<JsArray>(1)?;
+    let idle_p = cx.argument::<JsNumber>(2)?.value(&mut cx);
+    let seed = cx.argument::<JsNumber>(3)?.value(&mut cx) as u64;
+    
+    let gates = js_gate_ops(&mut cx, gates_js)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match circuit::apply_layer_with_idle_noise(&mut simulator, &gates, idle_p, seed) {
+            Ok(_) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn apply_uniform_depolarizing(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let p = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    let seed = cx.argument::<JsNumber>(2)?.value(&mut cx) as u64;
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match simulator.apply_uniform_depolarizing(p, seed) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn apply_noise(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let channel = cx.argument::<JsString>(1)?.value(&mut cx);
+    let qubit = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    let p = cx.argument::<JsNumber>(3)?.value(&mut cx);
+    let seed = optional_seed(&mut cx, 4)?.unwrap_or_else(|| {
+        use rand::Rng;
+        rand::thread_rng().gen()
+    });
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match simulator.apply_noise(&channel, qubit, p, seed) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn apply_trotter_step(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let terms_js = cx.argument::<JsArray>(1)?;
+    let dt = cx.argument::<JsNumber>(2)?.value(&mut cx);
+    let order = cx.argument::<JsNumber>(3)?.value(&mut cx) as usize;
+    
+    let terms = js_pauli_terms(&mut cx, terms_js)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        if let Err(e) = simulator.apply_trotter_step(&terms, dt, order) {
+            return cx.throw_error(e.to_string());
+        }
+    }
+    
+    Ok(cx.undefined())
+}
+
+fn apply_givens_rotation(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let index1 = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let index2 = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    let theta = cx.argument::<JsNumber>(3)?.value(&mut cx);
+    let phi = cx.argument::<JsNumber>(4)?.value(&mut cx);
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match simulator.apply_givens_rotation(index1, index2, theta, phi) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn apply_qft(cx: FunctionContext) -> JsResult<JsBoolean> {
+    apply_qft_direction(cx, false)
+}
+
+fn apply_inverse_qft(cx: FunctionContext) -> JsResult<JsBoolean> {
+    apply_qft_direction(cx, true)
+}
+
+fn apply_qft_direction(mut cx: FunctionContext, inverse: bool) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let qubits_js = cx.argument::<JsArray>(1)?;
+    
+    let qubits: Vec<usize> = js_array_to_f64s(&mut cx, qubits_js)?
+        .into_iter()
+        .map(|q| q as usize)
+        .collect();
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        let result = if inverse {
+            simulator.apply_inverse_qft(&qubits)
+        } else {
+            simulator.apply_qft(&qubits)
+        };
+        match result {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn apply_grover_diffusion(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let qubits_js = cx.argument::<JsArray>(1)?;
+    
+    let qubits: Vec<usize> = js_array_to_f64s(&mut cx, qubits_js)?
+        .into_iter()
+        .map(|q| q as usize)
+        .collect();
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match simulator.apply_grover_diffusion(&qubits) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+// Optional seed after the shot count; omitted means a fresh random stream
+fn optional_seed(cx: &mut FunctionContext, index: usize) -> NeonResult<Option<u64>> {
+    match cx.argument_opt(index) {
+        Some(arg) if arg.is_a::<JsNumber, _>(cx) => {
+            let seed = arg.downcast_or_throw::<JsNumber, _>(cx)?;
+            Ok(Some(seed.value(cx) as u64))
+        },
+        _ => Ok(None),
+    }
+}
+
+// measureQubits(simId, shots, seed?, qubits?): with a qubit array only those
+// wires are reported, in the order listed
+fn measure_qubits(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let shots = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let seed = optional_seed(&mut cx, 2)?;
+    let qubits = match cx.argument_opt(3) {
+        Some(arg) if arg.is_a::<JsArray, _>(&mut cx) => {
+            let qubits_js = arg.downcast_or_throw::<JsArray, _>(&mut cx)?;
+            let qubits: Vec<usize> = js_array_to_f64s(&mut cx, qubits_js)?
+                .into_iter()
+                .map(|q| q as usize)
+                .collect();
+            Some(qubits)
+        },
+        _ => None,
+    };
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        let results = match (qubits, seed) {
+            (Some(qubits), Some(seed)) => simulator.measure_subset_with_seed(&qubits, shots, seed),
+            (Some(qubits), None) => simulator.measure_subset(&qubits, shots),
+            (None, Some(seed)) => Ok(simulator.measure_with_seed(shots, seed)),
+            (None, None) => Ok(simulator.measure(shots)),
+        };
+        match results {
+            Ok(results) => counts_to_js(&mut cx, results),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.empty_object())
+    }
+}
+
+// Async variants run on the libuv thread pool and resolve a Promise. The
+// worker holds that simulator's lock while it runs, so other calls touching
+// the same simulator wait for it to finish.
+fn measure_qubits_async(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let shots = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let seed = optional_seed(&mut cx, 2)?;
+    
+    let promise = cx
+        .task(move || {
+            sim_id.handle()
+                .map(|handle| {
+                    let simulator = handle.lock().unwrap();
+                    match seed {
+                        Some(seed) => simulator.measure_with_seed(shots, seed),
+                        None => simulator.measure(shots),
+                    }
+                })
+                .unwrap_or_default()
+        })
+        .promise(|mut cx, results| counts_to_js(&mut cx, results));
+    
+    Ok(promise)
+}
+
+// Returns one u32 basis index per shot, so memory grows as 4 bytes × shots
+// (a million shots is ~4 MB) regardless of how few distinct outcomes occur.
+fn sample_bitstrings(mut cx: FunctionContext) -> JsResult<JsUint32Array> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let shots = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let seed = cx.argument::<JsNumber>(2)?.value(&mut cx) as u64;
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let samples = match simulator.as_deref() {
+        Some(simulator) => simulator.sample_raw_with_seed(shots, seed),
+        None => Vec::new(),
+    };
+    drop(simulator);
+    
+    JsUint32Array::from_slice(&mut cx, &samples)
+}
+
+// Like sampleBitstrings, but the seed is optional
+fn sample_raw(mut cx: FunctionContext) -> JsResult<JsUint32Array> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let shots = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let seed = optional_seed(&mut cx, 2)?;
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let samples = match (simulator.as_deref(), seed) {
+        (Some(simulator), Some(seed)) => simulator.sample_raw_with_seed(shots, seed),
+        (Some(simulator), None) => simulator.sample_raw(shots),
+        (None, _) => Vec::new(),
+    };
+    drop(simulator);
+    
+    JsUint32Array::from_slice(&mut cx, &samples)
+}
+
+fn create_sampler(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let seed = cx.argument::<JsNumber>(1)?.value(&mut cx) as u64;
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let sampler = match simulator.as_deref() {
+        Some(simulator) => simulator.sampler(seed),
+        None => return cx.throw_error(format!("Unknown simulator id {}", sim_id)),
+    };
+    drop(simulator);
+    
+    let mut samplers = SAMPLERS.lock().unwrap();
+    let mut next_id = NEXT_SAMPLER_ID.lock().unwrap();
+    let id = *next_id;
+    *next_id += 1;
+    samplers.insert(id, sampler);
+    
+    Ok(cx.number(id as f64))
+}
+
+fn sample_next(mut cx: FunctionContext) -> JsResult<JsUint32Array> {
+    let sampler_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    let count = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    
+    let mut samplers = SAMPLERS.lock().unwrap();
+    let samples: Vec<u32> = match samplers.get_mut(&sampler_id) {
+        Some(sampler) => sampler.sample(count).into_iter().map(|state| state as u32).collect(),
+        None => Vec::new(),
+    };
+    drop(samplers);
+    
+    JsUint32Array::from_slice(&mut cx, &samples)
+}
+
+fn destroy_sampler(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sampler_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    
+    let mut samplers = SAMPLERS.lock().unwrap();
+    let removed = samplers.remove(&sampler_id).is_some();
+    
+    Ok(cx.boolean(removed))
+}
+
+fn dm_create(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let num_qubits = cx.argument::<JsNumber>(0)?.value(&mut cx) as usize;
+    
+    let density_matrix = match DensityMatrix::new(num_qubits) {
+        Ok(density_matrix) => density_matrix,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    
+    let mut density_matrices = DENSITY_MATRICES.lock().unwrap();
+    let mut next_id = NEXT_DENSITY_ID.lock().unwrap();
+    let id = *next_id;
+    *next_id += 1;
+    density_matrices.insert(id, density_matrix);
+    
+    Ok(cx.number(id as f64))
+}
+
+fn dm_apply_gate(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let dm_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    let name = cx.argument::<JsString>(1)?.value(&mut cx);
+    let qubits_js = cx.argument::<JsArray>(2)?;
+    let params_js = cx.argument::<JsArray>(3)?;
+    
+    let qubits = js_array_to_f64s(&mut cx, qubits_js)?.into_iter().map(|q| q as usize).collect();
+    let params = js_array_to_f64s(&mut cx, params_js)?;
+    let op = GateOp { name, qubits, params };
+    
+    let mut density_matrices = DENSITY_MATRICES.lock().unwrap();
+    if let Some(density_matrix) = density_matrices.get_mut(&dm_id) {
+        match density_matrix.apply_gate(&op) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn dm_apply_noise(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let dm_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    let channel = cx.argument::<JsString>(1)?.value(&mut cx);
+    let qubit = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    let p = cx.argument::<JsNumber>(3)?.value(&mut cx);
+    
+    let mut density_matrices = DENSITY_MATRICES.lock().unwrap();
+    if let Some(density_matrix) = density_matrices.get_mut(&dm_id) {
+        match density_matrix.apply_noise(&channel, qubit, p) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn dm_get_probabilities(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let dm_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    
+    let density_matrices = DENSITY_MATRICES.lock().unwrap();
+    let probabilities = match density_matrices.get(&dm_id) {
+        Some(density_matrix) => density_matrix.probabilities(),
+        None => Vec::new(),
+    };
+    drop(density_matrices);
+    
+    let js_array = cx.empty_array();
+    for (i, prob) in probabilities.iter().enumerate() {
+        let js_value = cx.number(*prob);
+        js_array.set(&mut cx, i as u32, js_value)?;
+    }
+    Ok(js_array)
+}
+
+fn dm_get_purity(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let dm_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    
+    let density_matrices = DENSITY_MATRICES.lock().unwrap();
+    if let Some(density_matrix) = density_matrices.get(&dm_id) {
+        Ok(cx.number(density_matrix.purity()))
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn dm_trace_distance(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let dm_id1 = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    let dm_id2 = cx.argument::<JsNumber>(1)?.value(&mut cx) as u32;
+    
+    let density_matrices = DENSITY_MATRICES.lock().unwrap();
+    if let (Some(rho), Some(sigma)) = (density_matrices.get(&dm_id1), density_matrices.get(&dm_id2)) {
+        match rho.trace_distance(sigma) {
+            Ok(distance) => Ok(cx.number(distance)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.number(1.0))
+    }
+}
+
+fn dm_measure(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let dm_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    let shots = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let seed = optional_seed(&mut cx, 2)?.unwrap_or_else(|| {
+        use rand::Rng;
+        rand::thread_rng().gen()
+    });
+    
+    let density_matrices = DENSITY_MATRICES.lock().unwrap();
+    let counts = match density_matrices.get(&dm_id) {
+        Some(density_matrix) => density_matrix.measure_with_seed(shots, seed),
+        None => HashMap::new(),
+    };
+    drop(density_matrices);
+    
+    counts_to_js(&mut cx, counts)
+}
+
+fn dm_destroy(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let dm_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    
+    let mut density_matrices = DENSITY_MATRICES.lock().unwrap();
+    let removed = density_matrices.remove(&dm_id).is_some();
+    
+    Ok(cx.boolean(removed))
+}
+
+// The cost is a dense array indexed by basis state or a JS function of the
+// basis index. Samples are drawn under the lock; the callback runs after it
+// is released, once per distinct sampled state.
+fn cost_expectation(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let cost_js = cx.argument::<JsValue>(1)?;
+    let shots = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    let seed = cx.argument::<JsNumber>(3)?.value(&mut cx) as u64;
+    
+    let table = match cost_js.downcast::<JsArray, _>(&mut cx) {
+        Ok(table_js) => Some(js_array_to_f64s(&mut cx, table_js)?),
+        Err(_) => None,
+    };
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let samples = match simulator.as_deref() {
+        Some(simulator) => {
+            if let Some(table) = &table {
+                if table.len() != simulator.amplitudes.len() {
+                    let e = error::QuantumError::LengthMismatch { expected: simulator.amplitudes.len(), found: table.len() };
+                    return cx.throw_error(e.to_string());
+                }
+            }
+            simulator.sample_with_seed(shots, seed)
+        },
+        None => return cx.throw_error(format!("Unknown simulator id {}", sim_id)),
+    };
+    drop(simulator);
+    
+    let mut costs: HashMap<usize, f64> = HashMap::new();
+    for &state in &samples {
+        if costs.contains_key(&state) {
+            continue;
+        }
+        let cost = match &table {
+            Some(table) => table[state],
+            None => {
+                let callback = cost_js.downcast_or_throw::<JsFunction, _>(&mut cx)?;
+                let arg = cx.number(state as f64);
+                callback.call_with(&cx).arg(arg).apply::<JsNumber, _>(&mut cx)?.value(&mut cx)
+            },
+        };
+        costs.insert(state, cost);
+    }
+    
+    let mut histogram = HashMap::new();
+    let mut total = 0.0;
+    for state in &samples {
+        let cost = costs[state];
+        total += cost;
+        *histogram.entry(cost.to_string()).or_insert(0) += 1;
+    }
+    let expectation = if samples.is_empty() { 0.0 } else { total / samples.len() as f64 };
+    
+    let result = cx.empty_object();
+    let js_expectation = cx.number(expectation);
+    result.set(&mut cx, "expectation", js_expectation)?;
+    let js_histogram = counts_to_js(&mut cx, histogram)?;
+    result.set(&mut cx, "histogram", js_histogram)?;
+    Ok(result)
+}
+
+fn exact_cost_expectation(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let costs_js = cx.argument::<JsArray>(1)?;
+    
+    let costs = js_array_to_f64s(&mut cx, costs_js)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        match simulator.exact_cost_expectation(&costs) {
+            Ok(value) => Ok(cx.number(simulator.present(value))),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn expected_hamming_weight(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        Ok(cx.number(simulator.present(simulator.expected_hamming_weight())))
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn get_most_likely(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        let (bitstring, probability) = simulator.most_likely();
+        let result = cx.empty_object();
+        let js_bitstring = cx.string(bitstring);
+        result.set(&mut cx, "bitstring", js_bitstring)?;
+        let js_probability = cx.number(simulator.present(probability));
+        result.set(&mut cx, "probability", js_probability)?;
+        Ok(result)
+    } else {
+        Ok(cx.empty_object())
+    }
+}
+
+fn get_probability_of(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let bitstring = cx.argument::<JsString>(1)?.value(&mut cx);
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        match simulator.probability_of(&bitstring) {
+            Ok(probability) => Ok(cx.number(simulator.present(probability))),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn measure_importance(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let bias_js = cx.argument::<JsArray>(1)?;
+    let shots = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    let seed = cx.argument::<JsNumber>(3)?.value(&mut cx) as u64;
+    
+    let bias = js_array_to_f64s(&mut cx, bias_js)?;
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let weighted = match simulator.as_deref() {
+        Some(simulator) => match simulator.measure_importance(&bias, shots, seed) {
+            Ok(weighted) => weighted,
+            Err(e) => return cx.throw_error(e.to_string()),
+        },
+        None => HashMap::new(),
+    };
+    drop(simulator);
+    
+    let js_results = cx.empty_object();
+    for (bitstring, weight) in weighted {
+        let js_key = cx.string(bitstring);
+        let js_value = cx.number(weight);
+        js_results.set(&mut cx, js_key, js_value)?;
+    }
+    Ok(js_results)
+}
+
+fn measure_with_correction(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let qubit = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let corrections_js = cx.argument::<JsObject>(2)?;
+    let seed = cx.argument::<JsNumber>(3)?.value(&mut cx) as u64;
+    
+    // corrections maps "0"/"1" to arrays of {gate, qubit, params?}
+    let mut corrections: [Vec<GateOp>; 2] = [Vec::new(), Vec::new()];
+    for (outcome, ops) in corrections.iter_mut().enumerate() {
+        let key = outcome.to_string();
+        if let Some(list_js) = corrections_js.get_opt::<JsArray, _, _>(&mut cx, key.as_str())? {
+            let length = list_js.len(&mut cx);
+            for i in 0..length {
+                let entry_js: Handle<JsObject> = list_js.get(&mut cx, i)?;
+                let name = entry_js.get::<JsString, _, _>(&mut cx, "gate")?.value(&mut cx);
+                let target = entry_js.get::<JsNumber, _, _>(&mut cx, "qubit")?.value(&mut cx) as usize;
+                let params = match entry_js.get_opt::<JsArray, _, _>(&mut cx, "params")? {
+                    Some(params_js) => js_array_to_f64s(&mut cx, params_js)?,
+                    None => Vec::new(),
+                };
+                ops.push(GateOp { name, qubits: vec![target], params });
+            }
+        }
+    }
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match circuit::measure_with_correction(&mut simulator, qubit, &corrections, seed) {
+            Ok(outcome) => Ok(cx.number(u8::from(outcome) as f64)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        cx.throw_error(format!("Unknown simulator id {}", sim_id))
+    }
+}
+
+fn measure_qubit(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let qubit = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let seed = optional_seed(&mut cx, 2)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        let outcome = match seed {
+            Some(seed) => {
+                use rand::SeedableRng;
+                simulator.measure_qubit_with_rng(qubit, &mut rand::rngs::StdRng::seed_from_u64(seed))
+            },
+            None => simulator.measure_qubit(qubit),
+        };
+        match outcome {
+            Ok(outcome) => Ok(cx.number(u8::from(outcome) as f64)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        cx.throw_error(format!("Unknown simulator id {}", sim_id))
+    }
+}
+
+fn conditional_reset(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let qubit = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let seed = cx.argument::<JsNumber>(2)?.value(&mut cx) as u64;
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match simulator.conditional_reset(qubit, seed) {
+            Ok(outcome) => Ok(cx.number(u8::from(outcome) as f64)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        cx.throw_error(format!("Unknown simulator id {}", sim_id))
+    }
+}
+
+fn measure_subset_branch(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let qubits_js = cx.argument::<JsArray>(1)?;
+    let seed = cx.argument::<JsNumber>(2)?.value(&mut cx) as u64;
+    
+    let qubits: Vec<usize> = js_array_to_f64s(&mut cx, qubits_js)?
+        .into_iter()
+        .map(|q| q as usize)
+        .collect();
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let mut branch = match simulator.as_deref() {
+        Some(simulator) => simulator.clone(),
+        None => return cx.throw_error(format!("Unknown simulator id {}", sim_id)),
+    };
+    drop(simulator);
+    
+    let outcomes = match branch.measure_subset_collapse(&qubits, seed) {
+        Ok(outcomes) => outcomes,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    let new_id = insert_simulator(branch);
+    
+    let outcome: String = outcomes.iter().map(|&bit| if bit { '1' } else { '0' }).collect();
+    
+    let js_result = cx.empty_object();
+    let js_id = cx.number(new_id as f64);
+    js_result.set(&mut cx, "newSimId", js_id)?;
+    let js_outcome = cx.string(outcome);
+    js_result.set(&mut cx, "outcome", js_outcome)?;
+    
+    Ok(js_result)
+}
+
+fn measure_majority(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let qubits_js = cx.argument::<JsArray>(1)?;
+    let shots = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    let seed = cx.argument::<JsNumber>(3)?.value(&mut cx) as u64;
+    let expected = match cx.argument_opt(4) {
+        Some(value) if value.is_a::<JsNumber, _>(&mut cx) => {
+            let value = value.downcast_or_throw::<JsNumber, _>(&mut cx)?.value(&mut cx);
+            Some(value as usize)
+        },
+        _ => None,
+    };
+    
+    let qubits: Vec<usize> = js_array_to_f64s(&mut cx, qubits_js)?
+        .into_iter()
+        .map(|q| q as usize)
+        .collect();
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        let counts = match simulator.measure_majority(&qubits, shots, seed) {
+            Ok(counts) => counts,
+            Err(e) => return cx.throw_error(e.to_string()),
+        };
+        
+        let js_result = cx.empty_object();
+        let js_counts = cx.empty_object();
+        for (logical, count) in counts.iter().enumerate() {
+            let js_count = cx.number(*count as f64);
+            js_counts.set(&mut cx, logical.to_string().as_str(), js_count)?;
+        }
+        js_result.set(&mut cx, "counts", js_counts)?;
+        
+        if let Some(expected) = expected {
+            let errors = if expected == 0 { counts[1] } else { counts[0] };
+            let rate = if shots > 0 { errors as f64 / shots as f64 } else { 0.0 };
+            let js_rate = cx.number(rate);
+            js_result.set(&mut cx, "logicalErrorRate", js_rate)?;
+        }
+        
+        Ok(js_result)
+    } else {
+        Ok(cx.empty_object())
+    }
+}
+
+fn set_output_precision(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    // A missing or negative value restores full precision
+    let decimals = match cx.argument_opt(1) {
+        Some(value) if value.is_a::<JsNumber, _>(&mut cx) => {
+            let value = value.downcast_or_throw::<JsNumber, _>(&mut cx)?.value(&mut cx);
+            (value >= 0.0).then_some(value as u32)
+        },
+        _ => None,
+    };
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match simulator.set_output_precision(decimals) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+// {re, im} as parallel Float64Arrays indexed like getStateProbabilities
+fn get_state_vector(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let (re, im) = match simulator.as_deref() {
+        Some(simulator) => simulator.state_vector_parts(),
+        None => return Ok(cx.empty_object()),
+    };
+    drop(simulator);
+    
+    let result = cx.empty_object();
+    let js_re = JsFloat64Array::from_slice(&mut cx, &re)?;
+    result.set(&mut cx, "re", js_re)?;
+    let js_im = JsFloat64Array::from_slice(&mut cx, &im)?;
+    result.set(&mut cx, "im", js_im)?;
+    Ok(result)
+}
+
+// {dims, shape, type, data} mirroring a Qobj ket: dims [[2, ...], [1, ...]]
+// lists qubit 0 first, data is interleaved [re, im, ...] in QuTiP order
+fn export_qutip_json(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let simulator = match simulator.as_deref() {
+        Some(simulator) => simulator,
+        None => return Ok(cx.empty_object()),
+    };
+    
+    let num_qubits = simulator.num_qubits;
+    let ket_dims = cx.empty_array();
+    let bra_dims = cx.empty_array();
+    for i in 0..num_qubits {
+        let two = cx.number(2);
+        ket_dims.set(&mut cx, i as u32, two)?;
+        let one = cx.number(1);
+        bra_dims.set(&mut cx, i as u32, one)?;
+    }
+    let dims = cx.empty_array();
+    dims.set(&mut cx, 0, ket_dims)?;
+    dims.set(&mut cx, 1, bra_dims)?;
+    
+    let shape = cx.empty_array();
+    let rows = cx.number(simulator.amplitudes.len() as f64);
+    shape.set(&mut cx, 0, rows)?;
+    let cols = cx.number(1);
+    shape.set(&mut cx, 1, cols)?;
+    
+    let data = cx.empty_array();
+    for (i, amplitude) in simulator.qutip_ket_data().iter().enumerate() {
+        let re = cx.number(simulator.present(amplitude.re));
+        data.set(&mut cx, 2 * i as u32, re)?;
+        let im = cx.number(simulator.present(amplitude.im));
+        data.set(&mut cx, 2 * i as u32 + 1, im)?;
+    }
+    
+    let result = cx.empty_object();
+    result.set(&mut cx, "dims", dims)?;
+    result.set(&mut cx, "shape", shape)?;
+    let kind = cx.string("ket");
+    result.set(&mut cx, "type", kind)?;
+    result.set(&mut cx, "data", data)?;
+    Ok(result)
+}
+
+// logicalToPhysical[i] is the statevector position of logical qubit i;
+// null or undefined restores the identity layout
+fn set_layout(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let layout = match cx.argument_opt(1) {
+        Some(arg) if arg.is_a::<JsArray, _>(&mut cx) => {
+            let layout_js = arg.downcast_or_throw::<JsArray, _>(&mut cx)?;
+            Some(js_array_to_f64s(&mut cx, layout_js)?.into_iter().map(|q| q as usize).collect())
+        },
+        _ => None,
+    };
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match simulator.set_layout(layout) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn get_state_probabilities(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        let probabilities = simulator.get_probabilities();
+        
+        let js_array = cx.empty_array();
+        for (i, prob) in probabilities.iter().enumerate() {
+            let js_value = cx.number(simulator.present(*prob));
+            js_array.set(&mut cx, i as u32, js_value)?;
+        }
+        
+        Ok(js_array)
+    } else {
+        Ok(cx.empty_array())
+    }
+}
+
+fn expectation(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let pauli = cx.argument::<JsString>(1)?.value(&mut cx);
+    let qubit = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        let value = match pauli.to_ascii_uppercase().as_str() {
+            "X" => simulator.expectation_pauli_x(qubit),
+            "Y" => simulator.expectation_pauli_y(qubit),
+            "Z" => simulator.expectation_pauli_z(qubit),
+            other => return cx.throw_error(format!("Unknown Pauli operator {}", other)),
+        };
+        match value {
+            Ok(value) => Ok(cx.number(simulator.present(value))),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+// paulis maps qubit index to letter, e.g. {0: "X", 2: "Z"}
+fn expectation_pauli_string(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let paulis_js = cx.argument::<JsObject>(1)?;
+    
+    let keys = paulis_js.get_own_property_names(&mut cx)?;
+    let mut paulis = Vec::new();
+    for i in 0..keys.len(&mut cx) {
+        let key = keys.get::<JsString, _, _>(&mut cx, i)?.value(&mut cx);
+        let qubit = match key.parse::<usize>() {
+            Ok(qubit) => qubit,
+            Err(_) => return cx.throw_error(format!("Pauli string key {} is not a qubit index", key)),
+        };
+        let letter = paulis_js.get::<JsString, _, _>(&mut cx, key.as_str())?.value(&mut cx);
+        let mut chars = letter.chars();
+        match (chars.next(), chars.next()) {
+            (Some(letter), None) => paulis.push((qubit, letter)),
+            _ => return cx.throw_error(format!("Expected a single Pauli letter for qubit {}", qubit)),
+        }
+    }
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        match simulator.expectation_pauli_string(&paulis) {
+            Ok(value) => Ok(cx.number(simulator.present(value))),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn marginal_distribution(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let qubits_js = cx.argument::<JsArray>(1)?;
+    
+    let qubits: Vec<usize> = js_array_to_f64s(&mut cx, qubits_js)?
+        .into_iter()
+        .map(|q| q as usize)
+        .collect();
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        let marginal = match simulator.marginal_distribution(&qubits) {
+            Ok(marginal) => marginal,
+            Err(e) => return cx.throw_error(e.to_string()),
+        };
+        
+        let js_results = cx.empty_object();
+        for (outcome, prob) in marginal {
+            let js_key = cx.string(outcome);
+            let js_value = cx.number(simulator.present(prob));
+            js_results.set(&mut cx, js_key, js_value)?;
+        }
+        Ok(js_results)
+    } else {
+        Ok(cx.empty_object())
+    }
+}
+
+fn are_entangled(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let qubit_a = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let qubit_b = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    let tol = cx.argument::<JsNumber>(3)?.value(&mut cx);
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        match simulator.are_entangled(qubit_a, qubit_b, tol) {
+            Ok(entangled) => Ok(cx.boolean(entangled)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+// Flat row-major [ρ00, ρ01, ρ10, ρ11] per qubit, each entry as [re, im]
+fn single_qubit_density_matrices(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        let js_array = cx.empty_array();
+        for (qubit, rho) in simulator.all_single_qubit_rdms().iter().enumerate() {
+            let matrix = cx.empty_array();
+            for (k, entry) in rho.iter().enumerate() {
+                let re = cx.number(simulator.present(entry.re));
+                matrix.set(&mut cx, 2 * k as u32, re)?;
+                let im = cx.number(simulator.present(entry.im));
+                matrix.set(&mut cx, 2 * k as u32 + 1, im)?;
+            }
+            js_array.set(&mut cx, qubit as u32, matrix)?;
+        }
+        Ok(js_array)
+    } else {
+        Ok(cx.empty_array())
+    }
+}
+
+fn reduced_density_matrix(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let keep_js = cx.argument::<JsArray>(1)?;
+    
+    let keep: Vec<usize> = js_array_to_f64s(&mut cx, keep_js)?
+        .into_iter()
+        .map(|q| q as usize)
+        .collect();
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        let rho = match simulator.reduced_density_matrix(&keep) {
+            Ok(rho) => rho,
+            Err(e) => return cx.throw_error(e.to_string()),
+        };
+        
+        let js_array = cx.empty_array();
+        for (k, entry) in rho.iter().enumerate() {
+            let re = cx.number(simulator.present(entry.re));
+            js_array.set(&mut cx, 2 * k as u32, re)?;
+            let im = cx.number(simulator.present(entry.im));
+            js_array.set(&mut cx, 2 * k as u32 + 1, im)?;
+        }
+        Ok(js_array)
+    } else {
+        Ok(cx.empty_array())
+    }
+}
+
+fn stabilizer_renyi_entropy(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        match pauli::stabilizer_renyi_entropy(simulator.amplitudes.as_slice(), simulator.num_qubits) {
+            Ok(value) => Ok(cx.number(simulator.present(value))),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn concurrence(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let qubit_a = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let qubit_b = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        match simulator.concurrence(qubit_a, qubit_b) {
+            Ok(value) => Ok(cx.number(value)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn entanglement_entropy(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let partition_js = cx.argument::<JsArray>(1)?;
+    
+    let partition: Vec<usize> = js_array_to_f64s(&mut cx, partition_js)?
+        .into_iter()
+        .map(|q| q as usize)
+        .collect();
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        match simulator.entanglement_entropy(&partition) {
+            Ok(value) => Ok(cx.number(simulator.present(value))),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn active_qubits(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let tol = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        let active = simulator.active_qubits(tol);
+        
+        let js_array = cx.empty_array();
+        for (i, qubit) in active.iter().enumerate() {
+            let js_value = cx.number(*qubit as f64);
+            js_array.set(&mut cx, i as u32, js_value)?;
+        }
+        
+        Ok(js_array)
+    } else {
+        Ok(cx.empty_array())
+    }
+}
+
+fn get_sparse_probabilities(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let tol = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let (indices, probabilities) = match simulator.as_deref() {
+        Some(simulator) => {
+            let (indices, probabilities) = simulator.sparse_probabilities(tol);
+            (indices, probabilities.into_iter().map(|p| simulator.present(p)).collect())
+        },
+        None => (Vec::new(), Vec::new()),
+    };
+    drop(simulator);
+    
+    let js_result = cx.empty_object();
+    let js_indices = JsUint32Array::from_slice(&mut cx, &indices)?;
+    js_result.set(&mut cx, "indices", js_indices)?;
+    let js_probabilities = JsFloat64Array::from_slice(&mut cx, &probabilities)?;
+    js_result.set(&mut cx, "probabilities", js_probabilities)?;
+    
+    Ok(js_result)
+}
+
+fn get_fidelity(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id1 = simulator_arg(&mut cx, 0)?;
+    let sim_id2 = simulator_arg(&mut cx, 1)?;
+    
+    match with_simulator_pair(&sim_id1, &sim_id2, |sim1, sim2| sim1.get_fidelity(sim2)) {
+        Some(Ok(fidelity)) => Ok(cx.number(fidelity)),
+        Some(Err(e)) => cx.throw_error(e.to_string()),
+        None => Ok(cx.number(0.0)),
+    }
+}
+
+fn projector_expectation(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let target_js = cx.argument::<JsArray>(1)?;
+    
+    let flat = js_array_to_f64s(&mut cx, target_js)?;
+    let target = complex_from_flat(&mut cx, &flat)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        match simulator.projector_expectation(&target) {
+            Ok(value) => Ok(cx.number(value)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn fidelity_gradient(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let structure_js = cx.argument::<JsArray>(1)?;
+    let params_js = cx.argument::<JsArray>(2)?;
+    let param_index = cx.argument::<JsNumber>(3)?.value(&mut cx) as usize;
+    let target_js = cx.argument::<JsArray>(4)?;
+    
+    let structure = js_parameterized_gates(&mut cx, structure_js)?;
+    let params = js_array_to_f64s(&mut cx, params_js)?;
+    let flat = js_array_to_f64s(&mut cx, target_js)?;
+    let target = complex_from_flat(&mut cx, &flat)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        match gradient::fidelity_gradient(&simulator, &structure, &params, param_index, &target) {
+            Ok(value) => Ok(cx.number(value)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn overlaps_with_references(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let references_js = cx.argument::<JsArray>(1)?;
+    let count = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    
+    let flat = js_array_to_f64s(&mut cx, references_js)?;
+    let references = complex_from_flat(&mut cx, &flat)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        let overlaps = match simulator.overlaps_with_references(&references, count) {
+            Ok(overlaps) => overlaps,
+            Err(e) => return cx.throw_error(e.to_string()),
+        };
+        
+        let js_array = cx.empty_array();
+        for (i, overlap) in overlaps.iter().enumerate() {
+            let js_value = cx.number(simulator.present(*overlap));
+            js_array.set(&mut cx, i as u32, js_value)?;
+        }
+        
+        Ok(js_array)
+    } else {
+        Ok(cx.empty_array())
+    }
+}
+
+fn sample_haar_overlap(mut cx: FunctionContext) -> JsResult<JsFloat64Array> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let samples = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let seed = cx.argument::<JsNumber>(2)?.value(&mut cx) as u64;
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let overlaps = match simulator.as_deref() {
+        Some(simulator) => simulator.haar_overlaps(samples, seed),
+        None => Vec::new(),
+    };
+    drop(simulator);
+    
+    JsFloat64Array::from_slice(&mut cx, &overlaps)
+}
+
+fn trace_distance(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id1 = simulator_arg(&mut cx, 0)?;
+    let sim_id2 = simulator_arg(&mut cx, 1)?;
+    
+    match with_simulator_pair(&sim_id1, &sim_id2, |sim1, sim2| sim1.trace_distance(sim2)) {
+        Some(Ok(distance)) => Ok(cx.number(distance)),
+        Some(Err(e)) => cx.throw_error(e.to_string()),
+        None => Ok(cx.number(1.0)),
+    }
+}
+
+fn get_num_qubits(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        Ok(cx.number(simulator.num_qubits as f64))
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn get_dimension(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        Ok(cx.number(simulator.dimension() as f64))
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn get_norm(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let simulator = handle.lock().unwrap();
+        Ok(cx.number(simulator.norm()))
+    } else {
+        Ok(cx.number(0.0))
+    }
+}
+
+fn swap_test(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id1 = simulator_arg(&mut cx, 0)?;
+    let sim_id2 = simulator_arg(&mut cx, 1)?;
+    let shots = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    let seed = optional_seed(&mut cx, 3)?.unwrap_or_else(|| {
+        use rand::Rng;
+        rand::thread_rng().gen()
+    });
+    
+    match with_simulator_pair(&sim_id1, &sim_id2, |sim1, sim2| sim1.swap_test(sim2, shots, seed).map(|estimate| sim1.present(estimate))) {
+        Some(Ok(estimate)) => Ok(cx.number(estimate)),
+        Some(Err(e)) => cx.throw_error(e.to_string()),
+        None => Ok(cx.number(0.0)),
+    }
+}
+
+fn fidelity_gradient_all(mut cx: FunctionContext) -> JsResult<JsFloat64Array> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let structure_js = cx.argument::<JsArray>(1)?;
+    let params_js = cx.argument::<JsArray>(2)?;
+    let target_js = cx.argument::<JsArray>(3)?;
+    
+    let structure = js_parameterized_gates(&mut cx, structure_js)?;
+    let params = js_array_to_f64s(&mut cx, params_js)?;
+    let flat = js_array_to_f64s(&mut cx, target_js)?;
+    let target = complex_from_flat(&mut cx, &flat)?;
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let gradient = match simulator.as_deref() {
+        Some(simulator) => match gradient::fidelity_gradient_all(simulator, &structure, &params, &target) {
+            Ok(gradient) => gradient,
+            Err(e) => return cx.throw_error(e.to_string()),
+        },
+        None => Vec::new(),
+    };
+    drop(simulator);
+    
+    JsFloat64Array::from_slice(&mut cx, &gradient)
+}
+
+// The rotation is looked up in the gate history, so recording must be on
+// (`setRecordHistory`) and it must be the last change to the state
+fn analytic_rotation_gradient(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let gate_index = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let observable_js = cx.argument::<JsArray>(2)?;
+    
+    let observable = js_pauli_terms(&mut cx, observable_js)?;
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    match simulator.as_deref().map(|simulator| gradient::history_rotation_gradient(simulator, gate_index, &observable)) {
+        Some(Ok(value)) => Ok(cx.number(value)),
+        Some(Err(e)) => cx.throw_error(e.to_string()),
+        None => cx.throw_error(format!("Unknown simulator id {}", sim_id)),
+    }
+}
+
+fn exact_ground_state(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let terms_js = cx.argument::<JsArray>(0)?;
+    let num_qubits = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    
+    let terms = js_pauli_terms(&mut cx, terms_js)?;
+    let (energy, state) = match pauli::exact_ground_state(&terms, num_qubits) {
+        Ok(result) => result,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    
+    let flat: Vec<f64> = state.iter().flat_map(|amp| [amp.re, amp.im]).collect();
+    
+    let js_result = cx.empty_object();
+    let js_energy = cx.number(energy);
+    js_result.set(&mut cx, "energy", js_energy)?;
+    let js_state = JsFloat64Array::from_slice(&mut cx, &flat)?;
+    js_result.set(&mut cx, "stateFlat", js_state)?;
+    
+    Ok(js_result)
+}
+
+// allocateShots(terms, totalShots, strategy, simId?): with a simulator, a
+// term giving a Pauli string but no variance has it estimated from the state
+fn allocate_shots(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let terms_js = cx.argument::<JsArray>(0)?;
+    let total_shots = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let strategy_name = cx.argument::<JsString>(2)?.value(&mut cx);
+    let sim_id = match cx.argument_opt(3) {
+        Some(arg) if !arg.is_a::<JsUndefined, _>(&mut cx) && !arg.is_a::<JsNull, _>(&mut cx) => Some(simulator_arg(&mut cx, 3)?),
+        _ => None,
+    };
+    
+    let strategy = match strategy_name.parse::<shots::ShotStrategy>() {
+        Ok(strategy) => strategy,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    let handle = match &sim_id {
+        Some(sim_id) => match sim_id.handle() {
+            Some(handle) => Some(handle),
+            None => return cx.throw_error(format!("Unknown simulator id {}", sim_id)),
+        },
+        None => None,
+    };
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    
+    // terms are {coefficient, variance?, paulis?}
+    let mut terms = Vec::new();
+    let length = terms_js.len(&mut cx);
+    for i in 0..length {
+        let term_js: Handle<JsObject> = terms_js.get(&mut cx, i)?;
+        let coefficient = term_js.get::<JsNumber, _, _>(&mut cx, "coefficient")?.value(&mut cx);
+        let mut variance = term_js
+            .get_opt::<JsNumber, _, _>(&mut cx, "variance")?
+            .map(|variance| variance.value(&mut cx));
+        let paulis = term_js
+            .get_opt::<JsString, _, _>(&mut cx, "paulis")?
+            .map(|paulis| paulis.value(&mut cx));
+        if let (None, Some(paulis), Some(simulator)) = (variance, &paulis, simulator.as_deref()) {
+            match shots::pauli_variance(simulator, &pauli::parse_pauli_string(paulis)) {
+                Ok(estimate) => variance = Some(estimate),
+                Err(e) => return cx.throw_error(e.to_string()),
+            }
+        }
+        terms.push(shots::ShotTerm { coefficient, variance });
+    }
+    
+    let allocation = match shots::allocate_shots(&terms, total_shots, strategy) {
+        Ok(allocation) => allocation,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    
+    let js_array = cx.empty_array();
+    for (i, count) in allocation.iter().enumerate() {
+        let js_value = cx.number(*count as f64);
+        js_array.set(&mut cx, i as u32, js_value)?;
+    }
+    
+    Ok(js_array)
+}
+
+fn benchmark(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let num_qubits = cx.argument::<JsNumber>(0)?.value(&mut cx) as usize;
+    let gate_count = cx.argument::<JsNumber>(1)?.value(&mut cx) as usize;
+    let seed = cx.argument::<JsNumber>(2)?.value(&mut cx) as u64;
+    
+    if num_qubits == 0 {
+        return cx.throw_error("Benchmark requires at least one qubit");
+    }
+    
+    let report = match benchmark::run_benchmark(num_qubits, gate_count, seed) {
+        Ok(report) => report,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    
+    let js_report = cx.empty_object();
+    let setup_ms = cx.number(report.setup_ms);
+    js_report.set(&mut cx, "setupMs", setup_ms)?;
+    let circuit_ms = cx.number(report.circuit_ms);
+    js_report.set(&mut cx, "circuitMs", circuit_ms)?;
+    let measure_ms = cx.number(report.measure_ms);
+    js_report.set(&mut cx, "measureMs", measure_ms)?;
+    let gates_per_second = cx.number(report.gates_per_second);
+    js_report.set(&mut cx, "gatesPerSecond", gates_per_second)?;
+    
+    Ok(js_report)
+}
+
+fn snap_to_rational(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    let tol = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        match simulator.snap_to_rational(tol) {
+            Ok(()) => Ok(cx.boolean(true)),
+            Err(e) => cx.throw_error(e.to_string()),
+        }
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn conjugate_state(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        simulator.conjugate();
+        Ok(cx.boolean(true))
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn clone_simulator(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    let handle = sim_id.handle();
+    let simulator = handle.as_ref().map(|handle| handle.lock().unwrap());
+    let copy = match simulator.as_deref() {
+        Some(simulator) => simulator.clone(),
+        None => return cx.throw_error(format!("Unknown simulator id {}", sim_id)),
+    };
+    drop(simulator);
+    let id = insert_simulator(copy);
+    
+    Ok(cx.number(id as f64))
+}
+
+fn save_state(mut cx: FunctionContext) -> JsResult<JsBuffer> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    let bytes = match sim_id.handle() {
+        Some(handle) => handle.lock().unwrap().to_bytes(),
+        None => return cx.throw_error(format!("Unknown simulator id {}", sim_id)),
+    };
+    JsBuffer::from_slice(&mut cx, &bytes)
+}
+
+fn load_state(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let buffer = cx.argument::<JsBuffer>(0)?;
+    
+    let simulator = match QuantumState::from_bytes(buffer.as_slice(&cx)) {
+        Ok(simulator) => simulator,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    let id = insert_simulator(simulator);
+    
+    Ok(cx.number(id as f64))
+}
+
+fn reset_simulator(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    if let Some(handle) = sim_id.handle() {
+        let mut simulator = handle.lock().unwrap();
+        simulator.reset();
+        Ok(cx.boolean(true))
+    } else {
+        Ok(cx.boolean(false))
+    }
+}
+
+fn destroy_simulator(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let sim_id = simulator_arg(&mut cx, 0)?;
+    
+    // Boxed simulators are freed by the garbage collector, not here
+    let removed = match sim_id {
+        SimulatorRef::Id(id) => SIMULATORS.write().unwrap().remove(&id).is_some(),
+        SimulatorRef::Boxed(_) => false,
+    };
+    
+    Ok(cx.boolean(removed))
+}
+
+#[neon::main]
+fn main(mut cx: ModuleContext) -> NeonResult<()> {
+    cx.export_function("createSimulator", create_simulator)?;
+    cx.export_function("createBoxedSimulator", create_boxed_simulator)?;
+    cx.export_function("createSimulatorFromState", create_simulator_from_state)?;
+    cx.export_function("createProductState", create_product_state)?;
+    cx.export_function("createGhz", create_ghz)?;
+    cx.export_function("createW", create_w)?;
+    cx.export_function("cloneSimulator", clone_simulator)?;
+    cx.export_function("saveState", save_state)?;
+    cx.export_function("loadState", load_state)?;
+    cx.export_function("applyGate", apply_gate)?;
+    cx.export_function("applyGates", apply_gates)?;
+    cx.export_function("applyUnitary", apply_unitary)?;
+    cx.export_function("applyCircuit", apply_circuit)?;
+    cx.export_function("runCircuit", run_circuit)?;
+    cx.export_function("runQasm", run_qasm)?;
+    cx.export_function("exportQasm", export_qasm)?;
+    cx.export_function("setRecordHistory", set_record_history)?;
+    cx.export_function("clearHistory", clear_history)?;
+    cx.export_function("applyCircuitAsync", apply_circuit_async)?;
+    cx.export_function("applyCircuitBinary", apply_circuit_binary)?;
+    cx.export_function("encodeCircuit", encode_circuit)?;
+    cx.export_function("registerGate", register_gate)?;
+    cx.export_function("unregisterGate", unregister_gate)?;
+    cx.export_function("listSupportedGates", list_supported_gates)?;
+    cx.export_function("validateCircuit", validate_circuit)?;
+    cx.export_function("lightCone", light_cone)?;
+    cx.export_function("transpileForCoupling", transpile_for_coupling)?;
+    cx.export_function("pauliTwirl", pauli_twirl)?;
+    cx.export_function("applySingleQubitLayer", apply_single_qubit_layer)?;
+    cx.export_function("applyAllToAllLayer", apply_all_to_all_layer)?;
+    cx.export_function("applyGateWhereMarginal", apply_gate_where_marginal)?;
+    cx.export_function("applyControlledSubcircuit", apply_controlled_subcircuit)?;
+    cx.export_function("applyLayerWithIdleNoise", apply_layer_with_idle_noise)?;
+    cx.export_function("applyUniformDepolarizing", apply_uniform_depolarizing)?;
+    cx.export_function("applyNoise", apply_noise)?;
+    cx.export_function("applyTrotterStep", apply_trotter_step)?;
+    cx.export_function("applyGivensRotation", apply_givens_rotation)?;
+    cx.export_function("applyQft", apply_qft)?;
+    cx.export_function("applyInverseQft", apply_inverse_qft)?;
+    cx.export_function("applyGroverDiffusion", apply_grover_diffusion)?;
+    cx.export_function("conjugateState", conjugate_state)?;
+    cx.export_function("measureQubits", measure_qubits)?;
+    cx.export_function("measureQubitsAsync", measure_qubits_async)?;
+    cx.export_function("sampleBitstrings", sample_bitstrings)?;
+    cx.export_function("sampleRaw", sample_raw)?;
+    cx.export_function("createSampler", create_sampler)?;
+    cx.export_function("sampleNext", sample_next)?;
+    cx.export_function("destroySampler", destroy_sampler)?;
+    cx.export_function("measureImportance", measure_importance)?;
+    cx.export_function("costExpectation", cost_expectation)?;
+    cx.export_function("exactCostExpectation", exact_cost_expectation)?;
+    cx.export_function("expectedHammingWeight", expected_hamming_weight)?;
+    cx.export_function("measureWithCorrection", measure_with_correction)?;
+    cx.export_function("measureQubit", measure_qubit)?;
+    cx.export_function("conditionalReset", conditional_reset)?;
+    cx.export_function("measureSubsetBranch", measure_subset_branch)?;
+    cx.export_function("measureMajority", measure_majority)?;
+    cx.export_function("setOutputPrecision", set_output_precision)?;
+    cx.export_function("setLayout", set_layout)?;
+    cx.export_function("snapToRational", snap_to_rational)?;
+    cx.export_function("getStateProbabilities", get_state_probabilities)?;
+    cx.export_function("getMostLikely", get_most_likely)?;
+    cx.export_function("getProbabilityOf", get_probability_of)?;
+    cx.export_function("getStateVector", get_state_vector)?;
+    cx.export_function("getNumQubits", get_num_qubits)?;
+    cx.export_function("getDimension", get_dimension)?;
+    cx.export_function("getNorm", get_norm)?;
+    cx.export_function("exportQutipJson", export_qutip_json)?;
+    cx.export_function("expectation", expectation)?;
+    cx.export_function("expectationPauliString", expectation_pauli_string)?;
+    cx.export_function("marginalDistribution", marginal_distribution)?;
+    cx.export_function("areEntangled", are_entangled)?;
+    cx.export_function("concurrence", concurrence)?;
+    cx.export_function("entanglementEntropy", entanglement_entropy)?;
+    cx.export_function("stabilizerRenyiEntropy", stabilizer_renyi_entropy)?;
+    cx.export_function("singleQubitDensityMatrices", single_qubit_density_matrices)?;
+    cx.export_function("reducedDensityMatrix", reduced_density_matrix)?;
+    cx.export_function("activeQubits", active_qubits)?;
+    cx.export_function("getSparseProbabilities", get_sparse_probabilities)?;
+    cx.export_function("getFidelity", get_fidelity)?;
+    cx.export_function("projectorExpectation", projector_expectation)?;
+    cx.export_function("fidelityGradient", fidelity_gradient)?;
+    cx.export_function("fidelityGradientAll", fidelity_gradient_all)?;
+    cx.export_function("analyticRotationGradient", analytic_rotation_gradient)?;
+    cx.export_function("overlapsWithReferences", overlaps_with_references)?;
+    cx.export_function("sampleHaarOverlap", sample_haar_overlap)?;
+    cx.export_function("traceDistance", trace_distance)?;
+    cx.export_function("swapTest", swap_test)?;
+    cx.export_function("exactGroundState", exact_ground_state)?;
+    cx.export_function("allocateShots", allocate_shots)?;
+    cx.export_function("benchmark", benchmark)?;
+    cx.export_function("resetSimulator", reset_simulator)?;
+    cx.export_function("destroySimulator", destroy_simulator)?;
+    cx.export_function("dmCreate", dm_create)?;
+    cx.export_function("dmApplyGate", dm_apply_gate)?;
+    cx.export_function("dmApplyNoise", dm_apply_noise)?;
+    cx.export_function("dmGetProbabilities", dm_get_probabilities)?;
+    cx.export_function("dmGetPurity", dm_get_purity)?;
+    cx.export_function("dmTraceDistance", dm_trace_distance)?;
+    cx.export_function("dmMeasure", dm_measure)?;
+    cx.export_function("dmDestroy", dm_destroy)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circuit::apply_named_gate;
+    use gates::Gates;
+    use error::QuantumError;
+    
+    #[test]
+    fn test_simulator_creation() {
+        let sim = QuantumState::new(2).unwrap();
+        assert_eq!(sim.num_qubits, 2);
+        assert_eq!(sim.amplitudes.len(), 4);
+    }
+    
+    #[test]
+    fn test_hadamard_gate() {
+        let mut sim = QuantumState::new(1).unwrap();
+        let h_gate = Gates::hadamard();
+        sim.apply_single_qubit_gate(&h_gate, 0).unwrap();
+        
+        let probs = sim.get_probabilities();
+        assert!((probs[0] - 0.5).abs() < 1e-10);
+        assert!((probs[1] - 0.5).abs() < 1e-10);
+    }
+    
+    #[test]
+    fn test_cnot_bell_state() {
+        let mut sim = QuantumState::new(2).unwrap();
+        let h_gate = Gates::hadamard();
+        let cnot_gate = Gates::cnot();
+        
+        sim.apply_single_qubit_gate(&h_gate, 0).unwrap();
+        sim.apply_two_qubit_gate(&cnot_gate, 0, 1).unwrap();
+        
+        let probs = sim.get_probabilities();
+        // Should create bell state: |00⟩ + |11⟩
+        assert!((probs[0] - 0.5).abs() < 1e-10); // |00⟩
+        assert!(probs[1] < 1e-10); // |01⟩ should be ~0
+        assert!(probs[2] < 1e-10); // |10⟩ should be ~0
+        assert!((probs[3] - 0.5).abs() < 1e-10); // |11⟩
+    }
+    
+    #[test]
+    fn test_trace_distance_orthogonal_states() {
+        let zero = QuantumState::new(1).unwrap();
+        let mut one = QuantumState::new(1).unwrap();
+        one.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        
+        let distance = zero.trace_distance(&one).unwrap();
+        assert!((distance - 1.0).abs() < 1e-10);
+        assert!(zero.trace_distance(&zero).unwrap() < 1e-10);
+        
+        let wider = QuantumState::new(2).unwrap();
+        assert!(zero.trace_distance(&wider).is_err());
+    }
+    
+    #[test]
+    fn test_probability_cache_invalidation() {
+        let mut sim = QuantumState::new(1).unwrap();
+        assert!(!sim.has_cached_probabilities());
+        
+        let first = sim.probabilities().as_ptr();
+        assert!(sim.has_cached_probabilities());
+        assert_eq!(first, sim.probabilities().as_ptr());
+        
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        assert!(!sim.has_cached_probabilities());
+        
+        let probs = sim.get_probabilities();
+        assert!(probs[0] < 1e-10);
+        assert!((probs[1] - 1.0).abs() < 1e-10);
+    }
+    
+    #[test]
+    fn test_givens_rotation_mixes_two_basis_states() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_givens_rotation(0, 3, std::f64::consts::FRAC_PI_4, 0.0).unwrap();
+        
+        let probs = sim.get_probabilities();
+        assert!((probs[0] - 0.5).abs() < 1e-10);
+        assert!(probs[1] < 1e-10);
+        assert!(probs[2] < 1e-10);
+        assert!((probs[3] - 0.5).abs() < 1e-10);
+        
+        assert!(sim.apply_givens_rotation(1, 4, 0.1, 0.0).is_err());
+        assert!(sim.apply_givens_rotation(2, 2, 0.1, 0.0).is_err());
+    }
+    
+    #[test]
+    fn test_projector_expectation_of_own_state() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        sim.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        
+        let target: Vec<Complex64> = sim.amplitudes.iter().cloned().collect();
+        assert!((sim.projector_expectation(&target).unwrap() - 1.0).abs() < 1e-10);
+        assert!(sim.projector_expectation(&target[..2]).is_err());
+    }
+    
+    #[test]
+    fn test_random_circuit_is_deterministic() {
+        let a = circuit::random_circuit(4, 50, 7);
+        let b = circuit::random_circuit(4, 50, 7);
+        assert_eq!(a, b);
+        assert_ne!(a, circuit::random_circuit(4, 50, 8));
+        
+        let report = benchmark::run_benchmark(4, 50, 7).unwrap();
+        assert!(report.circuit_ms >= 0.0);
+        assert!(report.gates_per_second >= 0.0);
+    }
+    
+    #[test]
+    fn test_controlled_x_subcircuit_matches_cnot() {
+        let mut expected = QuantumState::new(2).unwrap();
+        expected.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        expected.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        let subcircuit = vec![GateOp::new("X", vec![1], vec![])];
+        circuit::apply_controlled_subcircuit(&mut sim, 0, &subcircuit).unwrap();
+        
+        for (a, b) in sim.amplitudes.iter().zip(expected.amplitudes.iter()) {
+            assert!((a - b).norm() < 1e-10);
+        }
+        
+        let overlapping = vec![GateOp::new("X", vec![0], vec![])];
+        assert!(circuit::apply_controlled_subcircuit(&mut sim, 0, &overlapping).is_err());
+    }
+    
+    #[test]
+    fn test_majority_vote_reads_logical_zero() {
+        // Logical |0⟩ of a 3-qubit repetition code with one flipped bit
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 1).unwrap();
+        
+        let counts = sim.measure_majority(&[0, 1, 2], 200, 11).unwrap();
+        assert_eq!(counts, [200, 0]);
+        
+        assert!(sim.measure_majority(&[0, 1], 10, 11).is_err());
+        assert!(sim.measure_majority(&[0, 1, 3], 10, 11).is_err());
+    }
+    
+    #[test]
+    fn test_registered_sqrt_x_applied_twice_is_x() {
+        let half = |re: f64, im: f64| Complex64::new(re / 2.0, im / 2.0);
+        let sqrt_x = [half(1.0, 1.0), half(1.0, -1.0), half(1.0, -1.0), half(1.0, 1.0)];
+        circuit::register_gate("SX_TEST", &sqrt_x, 1).unwrap();
+        
+        let mut sim = QuantumState::new(1).unwrap();
+        assert!(apply_named_gate(&mut sim, "SX_TEST", &[0], &[]).unwrap());
+        assert!(apply_named_gate(&mut sim, "SX_TEST", &[0], &[]).unwrap());
+        assert!((sim.get_probabilities()[1] - 1.0).abs() < 1e-10);
+        
+        assert!(circuit::register_gate("H", &sqrt_x, 1).is_err());
+        let not_unitary = [Complex64::new(1.0, 0.0); 4];
+        assert!(circuit::register_gate("BAD_TEST", &not_unitary, 1).is_err());
+        // Arity is checked before the matrix size is computed from it
+        for arity in [0, 3, 32, 64, usize::MAX] {
+            assert!(matches!(
+                circuit::register_gate("WIDE_TEST", &sqrt_x, arity),
+                Err(QuantumError::InvalidArgument { .. })
+            ));
+        }
+        
+        assert!(circuit::unregister_gate("SX_TEST"));
+        assert_eq!(
+            apply_named_gate(&mut sim, "SX_TEST", &[0], &[]),
+            Err(QuantumError::UnknownGate { name: "SX_TEST".to_string() })
+        );
+    }
+    
+    #[test]
+    fn test_light_cone_excludes_unrelated_gates() {
+        let gates = vec![
+            GateOp::new("H", vec![0], vec![]),
+            GateOp::new("H", vec![2], vec![]),
+            GateOp::new("CNOT", vec![0, 1], vec![]),
+            GateOp::new("X", vec![2], vec![]),
+            GateOp::new("CNOT", vec![1, 3], vec![]),
+        ];
+        
+        assert_eq!(circuit::light_cone(&gates, 0, 4).unwrap(), vec![0, 2]);
+        assert_eq!(circuit::light_cone(&gates, 2, 4).unwrap(), vec![1, 3]);
+        assert_eq!(circuit::light_cone(&gates, 3, 4).unwrap(), vec![0, 2, 4]);
+        assert!(circuit::light_cone(&gates, 4, 4).is_err());
+    }
+    
+    #[test]
+    fn test_idle_noise_only_hits_untouched_qubits() {
+        let layer = vec![GateOp::new("H", vec![0], vec![])];
+        let mut idle_flipped = false;
+        
+        for seed in 0..20 {
+            // |++⟩: the layer returns qubit 0 to |0⟩, qubit 1 idles under p = 1
+            let mut sim = QuantumState::new(2).unwrap();
+            sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+            sim.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+            
+            let idle = circuit::apply_layer_with_idle_noise(&mut sim, &layer, 1.0, seed).unwrap();
+            assert_eq!(idle, vec![1]);
+            
+            let probs = sim.get_probabilities();
+            assert!((probs[0] + probs[2] - 1.0).abs() < 1e-10);
+            
+            // Y or Z on qubit 1 turns |+⟩ into |−⟩
+            idle_flipped |= (sim.amplitudes[2].re + sim.amplitudes[0].re).abs() < 1e-10;
+        }
+        
+        assert!(idle_flipped);
+        
+        // A bad idle probability is rejected before the layer runs
+        let mut sim = QuantumState::new(2).unwrap();
+        for idle_p in [f64::NAN, 1.5, -0.1] {
+            assert!(circuit::apply_layer_with_idle_noise(&mut sim, &layer, idle_p, 0).is_err());
+        }
+        assert_eq!(sim.amplitudes[0], Complex64::new(1.0, 0.0));
+    }
+    
+    #[test]
+    fn test_fidelity_gradient_matches_finite_difference() {
+        let initial = QuantumState::new(1).unwrap();
+        let structure = vec![ParameterizedGate {
+            op: GateOp::new("RX", vec![0], vec![]),
+            param_index: Some(0),
+        }];
+        let one = [Complex64::new(0.0, 0.0), Complex64::new(1.0, 0.0)];
+        let theta = 0.7;
+        
+        let analytic = gradient::fidelity_gradient(&initial, &structure, &[theta], 0, &one).unwrap();
+        
+        let fidelity = |angle: f64| {
+            let mut state = initial.clone();
+            state.apply_single_qubit_gate(&Gates::rotation_x(angle), 0).unwrap();
+            state.projector_expectation(&one).unwrap()
+        };
+        let h = 1e-6;
+        let numeric = (fidelity(theta + h) - fidelity(theta - h)) / (2.0 * h);
+        
+        assert!((analytic - numeric).abs() < 1e-6);
+        assert!((analytic - theta.sin() / 2.0).abs() < 1e-10);
+    }
+    
+    #[test]
+    fn test_sparse_probabilities_of_bell_state() {
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        sim.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        
+        let (indices, probabilities) = sim.sparse_probabilities(1e-12);
+        assert_eq!(indices, vec![0, 3]);
+        assert_eq!(probabilities.len(), 2);
+        assert!((probabilities[0] - 0.5).abs() < 1e-10);
+        assert!((probabilities[1] - 0.5).abs() < 1e-10);
+    }
+    
+    #[test]
+    fn test_single_qubit_layer_per_qubit_gates() {
+        let specs = vec![
+            Some(("H".to_string(), vec![])),
+            Some(("X".to_string(), vec![])),
+            None,
+        ];
+        let layer = circuit::single_qubit_layer(&specs);
+        assert_eq!(layer.len(), 2);
+        
+        let mut sim = QuantumState::new(3).unwrap();
+        circuit::apply_gate_ops(&mut sim, &layer).unwrap();
+        
+        // Qubit 0 in |+⟩, qubit 1 in |1⟩, qubit 2 untouched
+        let probs = sim.get_probabilities();
+        assert!((probs[0b010] - 0.5).abs() < 1e-10);
+        assert!((probs[0b011] - 0.5).abs() < 1e-10);
+        
+        let two_qubit = circuit::single_qubit_layer(&[Some(("CNOT".to_string(), vec![]))]);
+        assert!(circuit::apply_gate_ops(&mut sim, &two_qubit).is_err());
+    }
+    
+    #[test]
+    fn test_seeded_samples_preserve_shot_order() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        sim.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        
+        let samples = sim.sample_with_seed(500, 3);
+        assert_eq!(samples.len(), 500);
+        assert_eq!(samples, sim.sample_with_seed(500, 3));
+        assert!(samples.iter().all(|&s| s == 0 || s == 3));
+        assert!(samples.contains(&0) && samples.contains(&3));
+    }
+    
+    #[test]
+    fn test_active_qubits_skips_classical_wires() {
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 2).unwrap();
+        
+        assert_eq!(sim.active_qubits(1e-9), vec![0]);
+    }
+    
+    #[test]
+    fn test_shot_allocations_sum_to_budget() {
+        use shots::{allocate_shots, ShotStrategy, ShotTerm};
+        
+        let terms = vec![
+            ShotTerm { coefficient: 0.5, variance: Some(1.0) },
+            ShotTerm { coefficient: -0.25, variance: Some(0.36) },
+            ShotTerm { coefficient: 0.1, variance: None },
+        ];
+        
+        for strategy in [ShotStrategy::Uniform, ShotStrategy::Weighted] {
+            let allocation = allocate_shots(&terms, 1001, strategy).unwrap();
+            assert_eq!(allocation.iter().sum::<usize>(), 1001);
+        }
+        
+        assert_eq!(allocate_shots(&terms, 10, ShotStrategy::Uniform).unwrap(), vec![4, 3, 3]);
+        let weighted = allocate_shots(&terms, 850, ShotStrategy::Weighted).unwrap();
+        assert_eq!(weighted, vec![500, 250, 100]);
+        assert!("bogus".parse::<ShotStrategy>().is_err());
+        
+        // The optimal strategy never guesses a missing variance
+        assert!(allocate_shots(&terms, 1001, ShotStrategy::Optimal).is_err());
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        let z = shots::pauli_variance(&sim, &[(1, 'Z')]).unwrap();
+        let x = shots::pauli_variance(&sim, &[(0, 'X')]).unwrap();
+        let zx = shots::pauli_variance(&sim, &[(0, 'Z')]).unwrap();
+        assert!(z.abs() < 1e-12 && x.abs() < 1e-12 && (zx - 1.0).abs() < 1e-12);
+        
+        let estimated = vec![
+            ShotTerm { coefficient: 0.5, variance: Some(1.0) },
+            ShotTerm { coefficient: -0.25, variance: Some(0.36) },
+            ShotTerm { coefficient: 0.1, variance: Some(z) },
+        ];
+        let optimal = allocate_shots(&estimated, 650, ShotStrategy::Optimal).unwrap();
+        assert_eq!(optimal, vec![500, 150, 0]);
+    }
+    
+    #[test]
+    fn test_validate_circuit_reports_every_problem() {
+        let gates = vec![
+            GateOp::new("H", vec![0], vec![]),
+            GateOp::new("HADAMARD", vec![0], vec![]),
+            GateOp::new("RX", vec![5], vec![]),
+            GateOp::new("CNOT", vec![1, 1], vec![]),
+            GateOp::new("CZ", vec![0], vec![]),
+        ];
+        
+        let problems = circuit::validate_circuit(3, &gates);
+        let indices: Vec<usize> = problems.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indices, vec![1, 2, 2, 3, 4]);
+        assert_eq!(problems[0].1, QuantumError::UnknownGate { name: "HADAMARD".to_string() });
+        assert_eq!(problems[2].1, QuantumError::QubitOutOfRange { qubit: 5, num_qubits: 3 });
+        assert_eq!(problems[3].1, QuantumError::IdenticalQubits { qubit: 1 });
+    }
+    
+    #[test]
+    fn test_gate_metadata_matches_dispatch() {
+        for info in circuit::BUILTIN_GATES {
+            let params = vec![0.3; info.num_params];
+            let matrix = circuit::gate_matrix(info.name, &params).unwrap();
+            assert_eq!(matrix.arity(), info.num_qubits, "{}", info.name);
+            
+            let entries: Vec<Complex64> = match matrix {
+                circuit::GateMatrix::Single(gate) => gate.to_vec(),
+                circuit::GateMatrix::Two(gate) => gate.to_vec(),
+                circuit::GateMatrix::Three(gate) => gate.to_vec(),
+            };
+            let dim = 1 << info.num_qubits;
+            let diagonal = (0..dim * dim)
+                .filter(|k| k / dim != k % dim)
+                .all(|k| entries[k].norm() < 1e-12);
+            assert_eq!(diagonal, info.is_diagonal, "{}", info.name);
+        }
+    }
+    
+    #[test]
+    fn test_trotterization_converges_to_exact_evolution() {
+        use pauli::PauliMasks;
+        
+        // Transverse-field Ising pair: H = Z₀Z₁ + 0.5·X₀ + 0.5·X₁
+        let terms = vec![
+            PauliTerm { coefficient: 1.0, paulis: pauli::parse_pauli_string("ZZ") },
+            PauliTerm { coefficient: 0.5, paulis: pauli::parse_pauli_string("XI") },
+            PauliTerm { coefficient: 0.5, paulis: pauli::parse_pauli_string("IX") },
+        ];
+        let time = 0.8;
+        
+        let mut initial = QuantumState::new(2).unwrap();
+        initial.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        
+        // Exact exp(−iHt)|ψ⟩ by Taylor series
+        let apply_h = |v: &[Complex64]| {
+            let mut out = vec![Complex64::new(0.0, 0.0); v.len()];
+            for term in &terms {
+                let masks = PauliMasks::from_paulis(&term.paulis, 2).unwrap();
+                for (o, p) in out.iter_mut().zip(masks.apply_to(v)) {
+                    *o += p * term.coefficient;
+                }
+            }
+            out
+        };
+        let mut exact: Vec<Complex64> = initial.amplitudes.iter().cloned().collect();
+        let mut power = exact.clone();
+        for k in 1..40 {
+            power = apply_h(&power)
+                .into_iter()
+                .map(|x| x * Complex64::new(0.0, -time) / k as f64)
+                .collect();
+            for (e, p) in exact.iter_mut().zip(&power) {
+                *e += p;
+            }
+        }
+        
+        let steps = 200;
+        for order in [1, 2] {
+            let mut sim = initial.clone();
+            for _ in 0..steps {
+                sim.apply_trotter_step(&terms, time / steps as f64, order).unwrap();
+            }
+            let fidelity = sim.projector_expectation(&exact).unwrap();
+            assert!((fidelity - 1.0).abs() < 1e-4, "order {} fidelity {}", order, fidelity);
+        }
+        
+        assert!(initial.clone().apply_trotter_step(&terms, 0.1, 3).is_err());
+    }
+    
+    #[test]
+    fn test_entanglement_between_qubit_pairs() {
+        let mut bell = QuantumState::new(2).unwrap();
+        bell.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        bell.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        assert!(bell.are_entangled(0, 1, 1e-9).unwrap());
+        
+        let mut plus_plus = QuantumState::new(2).unwrap();
+        plus_plus.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        plus_plus.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+        assert!(!plus_plus.are_entangled(0, 1, 1e-9).unwrap());
+        
+        assert!(bell.are_entangled(0, 0, 1e-9).is_err());
+    }
+    
+    #[test]
+    fn test_output_precision_leaves_state_untouched() {
+        let mut sim = QuantumState::new(1).unwrap();
+        sim.apply_single_qubit_gate(&Gates::rotation_y(1.0), 0).unwrap();
+        let before: Vec<Complex64> = sim.amplitudes.iter().cloned().collect();
+        let p0 = sim.get_probabilities()[0];
+        
+        sim.set_output_precision(Some(3)).unwrap();
+        assert_eq!(sim.present(p0), (p0 * 1000.0).round() / 1000.0);
+        assert_eq!(sim.present(1.2e-17), 0.0);
+        
+        let after: Vec<Complex64> = sim.amplitudes.iter().cloned().collect();
+        assert_eq!(before, after);
+        assert_eq!(sim.get_probabilities()[0], p0);
+        
+        sim.set_output_precision(None).unwrap();
+        assert_eq!(sim.present(p0), p0);
+        
+        // Precisions past what an f64 holds would overflow the scale
+        assert!(sim.set_output_precision(Some(400)).is_err());
+        assert!(sim.set_output_precision(Some(u32::MAX)).is_err());
+        assert_eq!(sim.present(p0), p0);
+        sim.set_output_precision(Some(quantum_state::MAX_OUTPUT_DECIMALS)).unwrap();
+        assert!((sim.present(p0) - p0).abs() < 1e-15);
+    }
+    
+    #[test]
+    fn test_measurement_correction_resets_bell_partner() {
+        // Measuring qubit 0 of a Bell pair and flipping both on outcome 1
+        // always leaves |00⟩
+        let corrections = [
+            Vec::new(),
+            vec![GateOp::new("X", vec![0], vec![]), GateOp::new("X", vec![1], vec![])],
+        ];
+        let mut outcomes = [0; 2];
+        
+        for seed in 0..20 {
+            let mut sim = QuantumState::new(2).unwrap();
+            sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+            sim.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+            
+            let outcome = circuit::measure_with_correction(&mut sim, 0, &corrections, seed).unwrap();
+            outcomes[usize::from(outcome)] += 1;
+            assert!((sim.get_probabilities()[0] - 1.0).abs() < 1e-10);
+        }
+        
+        assert!(outcomes[0] > 0 && outcomes[1] > 0);
+    }
+    
+    #[test]
+    fn test_overlaps_with_computational_basis_are_probabilities() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::rotation_y(0.9), 0).unwrap();
+        sim.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+        
+        let mut basis = vec![Complex64::new(0.0, 0.0); 16];
+        for i in 0..4 {
+            basis[i * 4 + i] = Complex64::new(1.0, 0.0);
+        }
+        
+        let overlaps = sim.overlaps_with_references(&basis, 4).unwrap();
+        for (overlap, prob) in overlaps.iter().zip(sim.get_probabilities()) {
+            assert!((overlap - prob).abs() < 1e-12);
+        }
+        assert!(sim.overlaps_with_references(&basis, 3).is_err());
+    }
+    
+    #[test]
+    fn test_subset_branch_leaves_original_intact() {
+        let mut original = QuantumState::new(3).unwrap();
+        original.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        original.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        original.apply_single_qubit_gate(&Gates::hadamard(), 2).unwrap();
+        let before = original.get_probabilities();
+        
+        let mut branch = original.clone();
+        let outcomes = branch.measure_subset_collapse(&[1, 2], 5).unwrap();
+        
+        assert_eq!(original.get_probabilities(), before);
+        
+        // The collapsed branch has qubit 0 locked to qubit 1's outcome
+        let expected = usize::from(outcomes[0]) * 0b011 + usize::from(outcomes[1]) * 0b100;
+        assert!((branch.get_probabilities()[expected] - 1.0).abs() < 1e-10);
+    }
+    
+    #[test]
+    fn test_exact_ground_state_of_ising_pair() {
+        // H = −Z₀Z₁ − g(X₀ + X₁) has ground energy −√(1 + 4g²)
+        let g = 0.5;
+        let terms = vec![
+            PauliTerm { coefficient: -1.0, paulis: pauli::parse_pauli_string("ZZ") },
+            PauliTerm { coefficient: -g, paulis: pauli::parse_pauli_string("XI") },
+            PauliTerm { coefficient: -g, paulis: pauli::parse_pauli_string("IX") },
+        ];
+        
+        let (energy, state) = pauli::exact_ground_state(&terms, 2).unwrap();
+        assert!((energy + (1.0 + 4.0 * g * g).sqrt()).abs() < 1e-10);
+        
+        let h = pauli::hamiltonian_matrix(&terms, 2).unwrap();
+        let v = nalgebra::DVector::from_vec(state);
+        assert!((&h * &v - &v * Complex64::new(energy, 0.0)).norm() < 1e-10);
+        
+        assert!(pauli::exact_ground_state(&terms, pauli::MAX_EXACT_QUBITS + 1).is_err());
+    }
+    
+    #[test]
+    fn test_conditional_reset_always_leaves_zero() {
+        let preparations = [Gates::identity(), Gates::pauli_x(), Gates::hadamard()];
+        
+        for gate in &preparations {
+            for seed in 0..10 {
+                let mut sim = QuantumState::new(2).unwrap();
+                sim.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+                sim.apply_single_qubit_gate(gate, 1).unwrap();
+                
+                sim.conditional_reset(1, seed).unwrap();
+                // Qubit 1 is |0⟩ and the spectator qubit 0 keeps its |1⟩
+                assert!((sim.get_probabilities()[0b01] - 1.0).abs() < 1e-10);
+            }
+        }
+    }
+    
+    #[test]
+    fn test_transpile_routes_distant_cnot_on_a_line() {
+        let line = [(0, 1), (1, 2)];
+        let gates = vec![
+            GateOp::new("H", vec![0], vec![]),
+            GateOp::new("CNOT", vec![0, 2], vec![]),
+        ];
+        
+        let (routed, layout) = circuit::transpile_for_coupling(&gates, 3, &line).unwrap();
+        assert_eq!(routed, vec![
+            GateOp::new("H", vec![0], vec![]),
+            GateOp::new("SWAP", vec![0, 1], vec![]),
+            GateOp::new("CNOT", vec![1, 2], vec![]),
+        ]);
+        assert_eq!(layout, vec![1, 0, 2]);
+        
+        // The logical Bell pair lands on physical qubits 1 and 2
+        let mut sim = QuantumState::new(3).unwrap();
+        circuit::apply_gate_ops(&mut sim, &routed).unwrap();
+        let probs = sim.get_probabilities();
+        assert!((probs[0b000] - 0.5).abs() < 1e-10);
+        assert!((probs[0b110] - 0.5).abs() < 1e-10);
+        
+        assert!(circuit::transpile_for_coupling(&gates, 3, &[(0, 1)]).is_err());
+    }
+    
+    #[test]
+    fn test_concurrence_extremes() {
+        let mut bell = QuantumState::new(3).unwrap();
+        bell.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        bell.apply_two_qubit_gate(&Gates::cnot(), 0, 2).unwrap();
+        bell.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+        
+        assert!((bell.concurrence(0, 2).unwrap() - 1.0).abs() < 1e-8);
+        assert!(bell.concurrence(0, 1).unwrap().abs() < 1e-8);
+        
+        let mut product = QuantumState::new(2).unwrap();
+        product.apply_single_qubit_gate(&Gates::rotation_y(0.7), 0).unwrap();
+        product.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+        assert!(product.concurrence(0, 1).unwrap().abs() < 1e-8);
+        
+        assert!(product.concurrence(0, 0).is_err());
+    }
+    
+    #[test]
+    fn test_binary_circuit_round_trip_matches_gate_list() {
+        let gates = circuit::random_circuit(4, 40, 11);
+        let bytes = circuit::encode_circuit(&gates).unwrap();
+        let decoded = circuit::decode_circuit(&bytes).unwrap();
+        assert_eq!(decoded, gates);
+        
+        let mut from_list = QuantumState::new(4).unwrap();
+        let mut from_binary = QuantumState::new(4).unwrap();
+        circuit::apply_gate_ops(&mut from_list, &gates).unwrap();
+        circuit::apply_gate_ops(&mut from_binary, &decoded).unwrap();
+        assert_eq!(from_list.amplitudes, from_binary.amplitudes);
+        
+        assert!(circuit::decode_circuit(&bytes[..bytes.len() - 1]).is_err());
+        assert!(circuit::encode_circuit(&[GateOp::new("NOPE", vec![0], vec![])]).is_err());
+    }
+    
+    #[test]
+    fn test_qutip_ordering_puts_qubit_zero_first() {
+        // |q2 q1 q0⟩ = |001⟩ is QuTiP's basis(2,1) ⊗ basis(2,0) ⊗ basis(2,0) = index 4
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        
+        let data = sim.qutip_ket_data();
+        assert_eq!(data[4], Complex64::new(1.0, 0.0));
+        assert!(data.iter().enumerate().all(|(i, a)| i == 4 || a.norm() == 0.0));
+    }
+    
+    #[test]
+    fn test_uniform_depolarizing_with_zero_probability_is_identity() {
+        let mut sim = QuantumState::new(3).unwrap();
+        circuit::apply_gate_ops(&mut sim, &circuit::random_circuit(3, 20, 5)).unwrap();
+        let before = sim.amplitudes.clone();
+        
+        sim.apply_uniform_depolarizing(0.0, 42).unwrap();
+        assert_eq!(sim.amplitudes, before);
+        
+        assert!(sim.apply_uniform_depolarizing(1.5, 42).is_err());
+    }
+    
+    #[test]
+    fn test_analytic_rotation_gradient_matches_parameter_shift() {
+        let observable = vec![
+            PauliTerm { coefficient: 1.0, paulis: vec![(0, 'Z')] },
+            PauliTerm { coefficient: 0.5, paulis: vec![(0, 'Y'), (1, 'Z')] },
+        ];
+        let prepared = |theta: f64| {
+            let mut sim = QuantumState::new(2).unwrap();
+            sim.apply_single_qubit_gate(&Gates::rotation_y(0.4), 0).unwrap();
+            sim.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+            sim.apply_single_qubit_gate(&Gates::rotation_x(theta), 0).unwrap();
+            sim
+        };
+        let energy = |sim: &QuantumState| {
+            let o_psi = pauli::apply_terms(&observable, sim.amplitudes.as_slice(), 2).unwrap();
+            sim.amplitudes.iter().zip(&o_psi).map(|(a, b)| a.conj() * b).sum::<Complex64>().re
+        };
+        
+        let theta = 0.9;
+        let shift = std::f64::consts::FRAC_PI_2;
+        let expected = (energy(&prepared(theta + shift)) - energy(&prepared(theta - shift))) / 2.0;
+        let analytic = gradient::rotation_gradient(&prepared(theta), &[(0, 'X')], &observable).unwrap();
+        
+        assert!((analytic - expected).abs() < 1e-10);
+        
+        // The same rotation resolved from the recorded history
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.set_record_history(true);
+        assert!(apply_named_gate(&mut sim, "RY", &[0], &[0.4]).unwrap());
+        assert!(apply_named_gate(&mut sim, "H", &[1], &[]).unwrap());
+        assert!(apply_named_gate(&mut sim, "RX", &[0], &[theta]).unwrap());
+        let from_history = gradient::history_rotation_gradient(&sim, 2, &observable).unwrap();
+        assert!((from_history - expected).abs() < 1e-10);
+        
+        // Only a trailing rotation can be differentiated
+        assert!(gradient::history_rotation_gradient(&sim, 0, &observable).is_err());
+        assert!(gradient::history_rotation_gradient(&sim, 3, &observable).is_err());
+        assert!(apply_named_gate(&mut sim, "H", &[0], &[]).unwrap());
+        assert!(gradient::history_rotation_gradient(&sim, 3, &observable).is_err());
+        
+        // An unrecorded change after the rotation is caught too
+        assert!(apply_named_gate(&mut sim, "RX", &[0], &[theta]).unwrap());
+        assert!(gradient::history_rotation_gradient(&sim, 4, &observable).is_ok());
+        sim.apply_unitary(&Gates::hadamard(), 1).unwrap();
+        assert!(gradient::history_rotation_gradient(&sim, 4, &observable).is_err());
+    }
+    
+    #[test]
+    fn test_batched_single_qubit_rdms_match_partial_trace() {
+        let mut sim = QuantumState::new(4).unwrap();
+        circuit::apply_gate_ops(&mut sim, &circuit::random_circuit(4, 30, 3)).unwrap();
+        
+        let batched = sim.all_single_qubit_rdms();
+        for (qubit, rho) in batched.iter().enumerate() {
+            let traced = sim.reduced_density_matrix(&[qubit]).unwrap();
+            for k in 0..4 {
+                assert!((rho[k] - traced[k]).norm() < 1e-12);
+            }
+        }
+        
+        // Served from the cache until the state changes
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 2).unwrap();
+        assert!((sim.all_single_qubit_rdms()[2][0] - batched[2][3]).norm() < 1e-12);
+    }
+    
+    #[test]
+    fn test_double_conjugation_is_identity() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        sim.apply_single_qubit_gate(&Gates::s_gate(), 0).unwrap();
+        sim.apply_single_qubit_gate(&Gates::rotation_x(0.3), 1).unwrap();
+        let original = sim.amplitudes.clone();
+        
+        sim.conjugate();
+        assert!((sim.amplitudes[1] - original[1].conj()).norm() < 1e-12);
+        assert_ne!(sim.amplitudes, original);
+        
+        sim.conjugate();
+        assert_eq!(sim.amplitudes, original);
+    }
+    
+    #[test]
+    fn test_importance_sampling_reduces_tail_variance() {
+        // P(1) = sin²(θ/2) = 1e-3
+        let tail: f64 = 1e-3;
+        let mut sim = QuantumState::new(1).unwrap();
+        sim.apply_single_qubit_gate(&Gates::rotation_y(2.0 * tail.sqrt().asin()), 0).unwrap();
+        
+        let shots = 1000;
+        let mut plain = Vec::new();
+        let mut weighted = Vec::new();
+        for seed in 0..30 {
+            let hits = sim.sample_with_seed(shots, seed).iter().filter(|&&s| s == 1).count();
+            plain.push(hits as f64 / shots as f64);
+            let counts = sim.measure_importance(&[0.5, 0.5], shots, seed).unwrap();
+            weighted.push(counts.get("1").copied().unwrap_or(0.0) / shots as f64);
+        }
+        
+        let mean = |xs: &[f64]| xs.iter().sum::<f64>() / xs.len() as f64;
+        let variance = |xs: &[f64]| {
+            let m = mean(xs);
+            xs.iter().map(|x| (x - m).powi(2)).sum::<f64>() / xs.len() as f64
+        };
+        
+        assert!((mean(&weighted) - tail).abs() < 1e-4);
+        assert!(variance(&weighted) < variance(&plain) / 10.0);
+        
+        assert!(sim.measure_importance(&[1.0, 0.0], shots, 0).is_err());
+        assert!(sim.measure_importance(&[1.0], shots, 0).is_err());
+    }
+    
+    #[test]
+    fn test_gate_where_marginal_only_hits_qubits_in_band() {
+        // Marginals: q0 = 1, q1 = 0.5, q2 = 0
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+        
+        let targets = circuit::apply_gate_where_marginal(&mut sim, "H", 0.25, 0.75, &[]).unwrap();
+        assert_eq!(targets, vec![1]);
+        assert!((sim.get_probabilities()[0b001] - 1.0).abs() < 1e-10);
+        
+        let targets = circuit::apply_gate_where_marginal(&mut sim, "X", 0.9, 1.0, &[]).unwrap();
+        assert_eq!(targets, vec![0]);
+        assert!((sim.get_probabilities()[0] - 1.0).abs() < 1e-10);
+        
+        assert!(circuit::apply_gate_where_marginal(&mut sim, "RX", 0.0, 1.0, &[]).is_err());
+        assert!(circuit::apply_gate_where_marginal(&mut sim, "CNOT", 0.0, 1.0, &[]).is_err());
+        
+        let mut empty = QuantumState::new(0).unwrap();
+        assert_eq!(circuit::apply_gate_where_marginal(&mut empty, "H", 0.0, 1.0, &[]).unwrap(), Vec::<usize>::new());
+        assert!(circuit::apply_gate_where_marginal(&mut empty, "CNOT", 0.0, 1.0, &[]).is_err());
+    }
+    
+    #[test]
+    fn test_full_fidelity_gradient_matches_finite_differences() {
+        let initial = QuantumState::new(2).unwrap();
+        let bound = |name: &str, qubits: Vec<usize>, index: Option<usize>| ParameterizedGate {
+            op: GateOp::new(name, qubits, vec![]),
+            param_index: index,
+        };
+        // Parameter 0 appears twice to exercise accumulation
+        let structure = vec![
+            bound("RY", vec![0], Some(0)),
+            bound("RX", vec![1], Some(1)),
+            bound("CNOT", vec![0, 1], None),
+            bound("RZ", vec![1], Some(2)),
+            bound("RY", vec![1], Some(0)),
+        ];
+        let params = [0.4, 1.1, -0.6];
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let target = [
+            Complex64::new(s, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, s),
+        ];
+        
+        let analytic = gradient::fidelity_gradient_all(&initial, &structure, &params, &target).unwrap();
+        
+        let fidelity = |p: &[f64]| {
+            let mut state = initial.clone();
+            circuit::apply_gate_ops(&mut state, &circuit::bind_parameters(&structure, p).unwrap()).unwrap();
+            state.projector_expectation(&target).unwrap()
+        };
+        let h = 1e-6;
+        for k in 0..params.len() {
+            let (mut plus, mut minus) = (params, params);
+            plus[k] += h;
+            minus[k] -= h;
+            let numeric = (fidelity(&plus) - fidelity(&minus)) / (2.0 * h);
+            assert!((analytic[k] - numeric).abs() < 1e-6);
+        }
+    }
+    
+    #[test]
+    fn test_marginal_distribution_of_ghz_and_ordering() {
+        let mut ghz = QuantumState::new(3).unwrap();
+        ghz.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        ghz.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        ghz.apply_two_qubit_gate(&Gates::cnot(), 1, 2).unwrap();
+        
+        let single = ghz.marginal_distribution(&[1]).unwrap();
+        assert_eq!(single.len(), 2);
+        assert!((single["0"] - 0.5).abs() < 1e-10);
+        assert!((single["1"] - 0.5).abs() < 1e-10);
+        
+        // |q2 q1 q0⟩ = |001⟩: listing [0, 2] reads q0 first
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        let pair = sim.marginal_distribution(&[0, 2]).unwrap();
+        assert_eq!(pair.len(), 1);
+        assert!((pair["10"] - 1.0).abs() < 1e-10);
+        
+        assert!(sim.marginal_distribution(&[0, 0]).is_err());
+        assert!(sim.marginal_distribution(&[3]).is_err());
+    }
+    
+    #[test]
+    fn test_all_to_all_cz_layer_builds_triangle_graph_state() {
+        let mut sim = QuantumState::new(3).unwrap();
+        for q in 0..3 {
+            sim.apply_single_qubit_gate(&Gates::hadamard(), q).unwrap();
+        }
+        
+        assert_eq!(circuit::apply_all_to_all_layer(&mut sim, "CZ", &[]).unwrap(), 3);
+        
+        // Amplitude sign is (−1)^(x0·x1 + x0·x2 + x1·x2): one CZ per edge
+        let scale = 1.0 / 8f64.sqrt();
+        for x in 0..8usize {
+            let bits: Vec<usize> = (0..3).map(|q| (x >> q) & 1).collect();
+            let edges = bits[0] * bits[1] + bits[0] * bits[2] + bits[1] * bits[2];
+            let sign = if edges.is_multiple_of(2) { 1.0 } else { -1.0 };
+            assert!((sim.amplitudes[x] - Complex64::new(sign * scale, 0.0)).norm() < 1e-10);
+        }
+        for (a, b) in [(0, 1), (0, 2), (1, 2)] {
+            assert!(sim.are_entangled(a, b, 1e-9).unwrap());
+        }
+        
+        assert!(circuit::apply_all_to_all_layer(&mut sim, "H", &[]).is_err());
+    }
+    
+    #[test]
+    fn test_layout_remaps_logical_qubits() {
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.set_layout(Some(vec![2, 0, 1])).unwrap();
+        
+        let physical = sim.physical_qubits(&[0]);
+        assert_eq!(physical, vec![2]);
+        assert!(apply_named_gate(&mut sim, "X", &physical, &[]).unwrap());
+        assert!((sim.get_probabilities()[0b100] - 1.0).abs() < 1e-10);
+        
+        assert!(sim.set_layout(Some(vec![0, 0, 1])).is_err());
+        assert!(sim.set_layout(Some(vec![0, 1])).is_err());
+        
+        sim.set_layout(None).unwrap();
+        assert_eq!(sim.physical_qubits(&[0, 1]), vec![0, 1]);
+    }
+    
+    #[test]
+    fn test_stabilizer_renyi_entropy_separates_clifford_from_t() {
+        let mut bell = QuantumState::new(2).unwrap();
+        bell.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        bell.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        bell.apply_single_qubit_gate(&Gates::s_gate(), 1).unwrap();
+        assert!(pauli::stabilizer_renyi_entropy(bell.amplitudes.as_slice(), 2).unwrap().abs() < 1e-10);
+        
+        // T|+⟩: ⟨X⟩ = ⟨Y⟩ = 1/√2 gives Σ⟨P⟩⁴ = 3/2 and M₂ = log₂(4/3)
+        let mut t_state = QuantumState::new(1).unwrap();
+        t_state.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        t_state.apply_single_qubit_gate(&Gates::t_gate(), 0).unwrap();
+        let magic = pauli::stabilizer_renyi_entropy(t_state.amplitudes.as_slice(), 1).unwrap();
+        assert!((magic - (4.0f64 / 3.0).log2()).abs() < 1e-10);
+        
+        let large = QuantumState::new(pauli::MAX_MAGIC_QUBITS + 1).unwrap();
+        assert!(pauli::stabilizer_renyi_entropy(large.amplitudes.as_slice(), large.num_qubits).is_err());
+    }
+    
+    #[test]
+    fn test_sampler_batches_continue_one_stream() {
+        let mut sim = QuantumState::new(3).unwrap();
+        circuit::apply_gate_ops(&mut sim, &circuit::random_circuit(3, 20, 9)).unwrap();
+        
+        let mut sampler = sim.sampler(17);
+        let mut batched = sampler.sample(100);
+        batched.extend(sampler.sample(100));
+        
+        assert_eq!(batched, sim.sampler(17).sample(200));
+        assert_eq!(batched, sim.sample_with_seed(200, 17));
+    }
+    
+    #[test]
+    fn test_snap_to_rational_equalises_uniform_superposition() {
+        let mut sim = QuantumState::new(3).unwrap();
+        for q in 0..3 {
+            sim.apply_single_qubit_gate(&Gates::rotation_y(std::f64::consts::FRAC_PI_2), q).unwrap();
+        }
+        
+        sim.snap_to_rational(1e-9).unwrap();
+        let expected = Complex64::new(1.0 / 8f64.sqrt(), 0.0);
+        assert!(sim.amplitudes.iter().all(|&amp| amp == expected));
+        
+        assert!(sim.snap_to_rational(-1.0).is_err());
+        
+        // A run of values each within tol of the next is not one cluster
+        let last = (1.0 - 0.30f64.powi(2) - 0.308f64.powi(2) - 0.316f64.powi(2)).sqrt();
+        let real = |values: [f64; 4]| values.map(|value| Complex64::new(value, 0.0)).to_vec();
+        let mut sim = QuantumState::from_amplitudes(real([0.30, 0.308, 0.316, last])).unwrap();
+        sim.snap_to_rational(0.01).unwrap();
+        assert_eq!(sim.amplitudes[0], sim.amplitudes[1]);
+        assert!((sim.amplitudes[2].re - sim.amplitudes[1].re).abs() > 0.01);
+        
+        // Real and imaginary parts are never pooled
+        let last = (1.0 - 0.36 - 0.605f64.powi(2)).sqrt();
+        let amplitudes = vec![Complex64::new(0.6, 0.0), Complex64::new(0.0, 0.605), Complex64::new(last, 0.0), Complex64::new(0.0, 0.0)];
+        let mut sim = QuantumState::from_amplitudes(amplitudes).unwrap();
+        sim.snap_to_rational(0.01).unwrap();
+        assert_eq!(sim.amplitudes[0].re, 0.6);
+        assert_eq!(sim.amplitudes[1].im, 0.605);
+    }
+    
+    #[test]
+    fn test_hamming_weight_cost_matches_expected_weight() {
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::rotation_y(0.8), 0).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+        sim.apply_two_qubit_gate(&Gates::cnot(), 1, 2).unwrap();
+        
+        let hamming: Vec<f64> = (0..8u32).map(|x| x.count_ones() as f64).collect();
+        let exact = sim.exact_cost_expectation(&hamming).unwrap();
+        
+        assert!((exact - sim.expected_hamming_weight()).abs() < 1e-12);
+        assert!((exact - ((0.4f64).sin().powi(2) + 1.0)).abs() < 1e-12);
+        assert!(sim.exact_cost_expectation(&hamming[..4]).is_err());
+    }
+    
+    #[test]
+    fn test_pauli_twirl_preserves_ideal_circuit() {
+        let gates = circuit::random_circuit(4, 40, 21);
+        let mut original = QuantumState::new(4).unwrap();
+        circuit::apply_gate_ops(&mut original, &gates).unwrap();
+        
+        for seed in 0..5 {
+            let twirled = circuit::pauli_twirl(&gates, seed);
+            assert!(twirled.len() > gates.len());
+            
+            let mut sim = QuantumState::new(4).unwrap();
+            circuit::apply_gate_ops(&mut sim, &twirled).unwrap();
+            // Conjugation signs only contribute a global phase
+            assert!((sim.projector_expectation(original.amplitudes.as_slice()).unwrap() - 1.0).abs() < 1e-10);
+        }
+    }
+    
+    #[test]
+    fn test_haar_overlaps_follow_porter_thomas() {
+        let sim = QuantumState::new(3).unwrap();
+        let overlaps = sim.haar_overlaps(4000, 13);
+        assert_eq!(overlaps.len(), 4000);
+        assert!(overlaps.iter().all(|&x| (0.0..=1.0).contains(&x)));
+        
+        // Beta(1, d − 1): mean 1/d and P(x > 2/d) = (1 − 2/d)^(d − 1)
+        let d = 8.0;
+        let mean = overlaps.iter().sum::<f64>() / overlaps.len() as f64;
+        assert!((mean - 1.0 / d).abs() < 0.01);
+        let tail = overlaps.iter().filter(|&&x| x > 2.0 / d).count() as f64 / overlaps.len() as f64;
+        assert!((tail - (1.0 - 2.0 / d).powf(d - 1.0)).abs() < 0.02);
+        
+        assert_eq!(overlaps, sim.haar_overlaps(4000, 13));
+    }
+    
+    #[test]
+    fn test_parallel_single_qubit_gate_matches_serial_at_22_qubits() {
+        use rand::{Rng, SeedableRng};
+        
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        let mut sim = QuantumState::new(22).unwrap();
+        for amp in sim.amplitudes.iter_mut() {
+            *amp = Complex64::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+        }
+        let gate = Gates::u3(0.3, 1.1, -0.4);
+        
+        for qubit in [0, 11, 21] {
+            let old = sim.amplitudes.clone();
+            let mut serial = old.clone();
+            let bit = 1 << qubit;
+            for i0 in (0..old.len()).filter(|i| i & bit == 0) {
+                let i1 = i0 | bit;
+                serial[i0] = gate[0] * old[i0] + gate[1] * old[i1];
+                serial[i1] = gate[2] * old[i0] + gate[3] * old[i1];
+            }
+            
+            sim.apply_single_qubit_gate(&gate, qubit).unwrap();
+            assert_eq!(sim.amplitudes, serial);
+        }
+    }
+    
+    #[test]
+    fn test_ten_thousand_in_place_hadamards() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::rotation_y(0.6), 0).unwrap();
+        sim.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        let initial = sim.amplitudes.clone();
+        
+        let start = std::time::Instant::now();
+        for i in 0..10_000 {
+            sim.apply_single_qubit_gate(&Gates::hadamard(), i % 2).unwrap();
+        }
+        // Generous bound: only catches a pathological slowdown
+        assert!(start.elapsed().as_secs() < 5);
+        
+        // An even number of H on each qubit is the identity
+        for (amp, expected) in sim.amplitudes.iter().zip(initial.iter()) {
+            assert!((amp - expected).norm() < 1e-9);
+        }
+    }
+    
+    #[test]
+    fn test_measure_with_seed_is_reproducible() {
+        let mut sim = QuantumState::new(3).unwrap();
+        circuit::apply_gate_ops(&mut sim, &circuit::random_circuit(3, 25, 4)).unwrap();
+        
+        let first = sim.measure_with_seed(500, 99);
+        assert_eq!(first, sim.measure_with_seed(500, 99));
+        assert_eq!(first.values().sum::<usize>(), 500);
+        assert_ne!(first, sim.measure_with_seed(500, 100));
+    }
+    
+    #[test]
+    fn test_measuring_bell_qubit_forces_partner() {
+        for _ in 0..20 {
+            let mut sim = QuantumState::new(2).unwrap();
+            sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+            sim.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+            
+            let first = sim.measure_qubit(0).unwrap();
+            assert!((sim.amplitudes.norm() - 1.0).abs() < 1e-12);
+            assert_eq!(sim.measure_qubit(1).unwrap(), first);
+        }
+        
+        assert!(QuantumState::new(2).unwrap().measure_qubit(2).is_err());
+    }
+    
+    #[test]
+    fn test_ccx_flips_target_when_both_controls_set() {
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 1).unwrap();
+        
+        assert!(apply_named_gate(&mut sim, "CCX", &[0, 1, 2], &[]).unwrap());
+        assert!((sim.get_probabilities()[0b111] - 1.0).abs() < 1e-12);
+        
+        // One control clear: the target is left alone
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 1).unwrap();
+        assert!(apply_named_gate(&mut sim, "TOFFOLI", &[0, 1, 2], &[]).unwrap());
+        assert!((sim.get_probabilities()[0b010] - 1.0).abs() < 1e-12);
+    }
+    
+    #[test]
+    fn test_controlled_phase_and_u3_dispatch() {
+        // CP(π) on |11⟩ after H⊗H flips the sign of that component only
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+        assert!(apply_named_gate(&mut sim, "CP", &[0, 1], &[std::f64::consts::PI]).unwrap());
+        assert!((sim.amplitudes[3] - Complex64::new(-0.5, 0.0)).norm() < 1e-12);
+        assert!((sim.amplitudes[0] - Complex64::new(0.5, 0.0)).norm() < 1e-12);
+        assert!(apply_named_gate(&mut sim, "CPHASE", &[1, 0], &[std::f64::consts::PI]).unwrap());
+        assert!((sim.amplitudes[3] - Complex64::new(0.5, 0.0)).norm() < 1e-12);
+        
+        // U3(π, 0, π) = X
+        let mut sim = QuantumState::new(1).unwrap();
+        assert!(apply_named_gate(&mut sim, "U3", &[0], &[std::f64::consts::PI, 0.0, std::f64::consts::PI]).unwrap());
+        assert!((sim.get_probabilities()[1] - 1.0).abs() < 1e-12);
+        assert!(apply_named_gate(&mut sim, "U", &[0], &[std::f64::consts::PI, 0.0, std::f64::consts::PI]).unwrap());
+        assert!((sim.get_probabilities()[0] - 1.0).abs() < 1e-12);
+        
+        let before = sim.amplitudes.clone();
+        assert!(!apply_named_gate(&mut sim, "U3", &[0], &[0.1]).unwrap());
+        assert!(!apply_named_gate(&mut sim, "CP", &[0, 1], &[]).unwrap());
+        assert_eq!(sim.amplitudes, before);
+    }
+    
+    #[test]
+    fn test_out_of_range_qubit_is_an_error() {
+        let mut sim = QuantumState::new(3).unwrap();
+        let expected = QuantumError::QubitOutOfRange { qubit: 5, num_qubits: 3 };
+        
+        assert_eq!(sim.apply_single_qubit_gate(&Gates::hadamard(), 5), Err(expected.clone()));
+        assert_eq!(sim.apply_two_qubit_gate(&Gates::cnot(), 0, 5), Err(expected.clone()));
+        assert_eq!(apply_named_gate(&mut sim, "H", &[5], &[]), Err(expected));
+        assert!((sim.get_probabilities()[0] - 1.0).abs() < 1e-12);
+    }
+    
+    #[test]
+    fn test_cnot_with_control_equal_to_target_is_rejected() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        let before = sim.amplitudes.clone();
+        
+        let expected = QuantumError::IdenticalQubits { qubit: 1 };
+        assert_eq!(sim.apply_two_qubit_gate(&Gates::cnot(), 1, 1), Err(expected.clone()));
+        assert_eq!(apply_named_gate(&mut sim, "CNOT", &[1, 1], &[]), Err(expected));
+        assert_eq!(sim.amplitudes, before);
+    }
+    
+    #[test]
+    fn test_single_pauli_expectations_after_hadamard() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        
+        assert!(sim.expectation_pauli_z(0).unwrap().abs() < 1e-12);
+        assert!((sim.expectation_pauli_x(0).unwrap() - 1.0).abs() < 1e-12);
+        assert!(sim.expectation_pauli_y(0).unwrap().abs() < 1e-12);
+        assert!((sim.expectation_pauli_z(1).unwrap() - 1.0).abs() < 1e-12);
+        
+        // S maps |+⟩ to |+i⟩
+        sim.apply_single_qubit_gate(&Gates::s_gate(), 0).unwrap();
+        assert!((sim.expectation_pauli_y(0).unwrap() - 1.0).abs() < 1e-12);
+        
+        assert!(sim.expectation_pauli_x(2).is_err());
+    }
+    
+    #[test]
+    fn test_pauli_string_expectations_on_bell_state() {
+        let mut bell = QuantumState::new(2).unwrap();
+        bell.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        bell.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        
+        assert!((bell.expectation_pauli_string(&[(0, 'Z'), (1, 'Z')]).unwrap() - 1.0).abs() < 1e-12);
+        assert!((bell.expectation_pauli_string(&[(0, 'X'), (1, 'X')]).unwrap() - 1.0).abs() < 1e-12);
+        assert!((bell.expectation_pauli_string(&[(0, 'Y'), (1, 'Y')]).unwrap() + 1.0).abs() < 1e-12);
+        assert!(bell.expectation_pauli_string(&[(0, 'Z')]).unwrap().abs() < 1e-12);
+        assert!(bell.expectation_pauli_string(&[(0, 'Q')]).is_err());
+    }
+    
+    #[test]
+    fn test_reset_returns_to_ground_state() {
+        let mut sim = QuantumState::new(3).unwrap();
+        circuit::apply_gate_ops(&mut sim, &circuit::random_circuit(3, 30, 6)).unwrap();
+        let _ = sim.probabilities();
+        
+        sim.reset();
+        assert!(!sim.has_cached_probabilities());
+        assert_eq!(sim.get_probabilities()[0], 1.0);
+        assert_eq!(sim.amplitudes.len(), 8);
+    }
+    
+    #[test]
+    fn test_reset_qubit_recycles_ancilla() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 1).unwrap();
+        
+        sim.reset_qubit(0).unwrap();
+        assert_eq!(sim.measure_with_seed(10, 0).get("10"), Some(&10));
+        
+        // Through the applyGate dispatch, from a superposition
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        assert!(apply_named_gate(&mut sim, "RESET", &[0], &[]).unwrap());
+        assert!((sim.get_probabilities()[0b10] - 1.0).abs() < 1e-12);
+        assert!(apply_named_gate(&mut sim, "RESET", &[0, 1], &[]).is_err());
+    }
+    
+    #[test]
+    fn test_from_amplitudes_loads_ghz_and_rejects_bad_lengths() {
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let mut ghz = vec![Complex64::new(0.0, 0.0); 8];
+        ghz[0] = Complex64::new(s, 0.0);
+        ghz[7] = Complex64::new(s, 0.0);
+        
+        let sim = QuantumState::from_amplitudes(ghz).unwrap();
+        assert_eq!(sim.num_qubits, 3);
+        assert!((sim.expectation_pauli_string(&[(0, 'X'), (1, 'X'), (2, 'X')]).unwrap() - 1.0).abs() < 1e-12);
+        
+        let three = vec![Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0), Complex64::new(0.0, 0.0)];
+        assert!(QuantumState::from_amplitudes(three).is_err());
+        assert!(QuantumState::from_amplitudes(vec![Complex64::new(0.5, 0.0); 2]).is_err());
+    }
+    
+    #[test]
+    fn test_state_vector_after_hadamard() {
+        let mut sim = QuantumState::new(1).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        
+        let (re, im) = sim.state_vector_parts();
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        assert_eq!(re.len(), 2);
+        assert!(re.iter().all(|&x| (x - s).abs() < 1e-12));
+        assert!(im.iter().all(|&x| x == 0.0));
+        
+        sim.set_output_precision(Some(3)).unwrap();
+        assert_eq!(sim.state_vector_parts().0, vec![0.707, 0.707]);
+    }
+    
+    #[test]
+    fn test_fused_single_qubit_runs_match_sequential() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(265);
+        
+        for _ in 0..10 {
+            let run: Vec<[Complex64; 4]> = (0..rng.gen_range(1..8))
+                .map(|_| Gates::u3(rng.gen_range(0.0..6.3), rng.gen_range(0.0..6.3), rng.gen_range(0.0..6.3)))
+                .collect();
+            
+            let mut sequential = QuantumState::new(2).unwrap();
+            sequential.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+            let mut fused = sequential.clone();
+            for gate in &run {
+                sequential.apply_single_qubit_gate(gate, 1).unwrap();
+            }
+            fused.apply_fused_single_qubit(&run, 1).unwrap();
+            assert!((sequential.get_fidelity(&fused).unwrap() - 1.0).abs() < 1e-10);
+        }
+        
+        // Whole-circuit fusion must respect multi-qubit gates between runs
+        let gates = circuit::random_circuit(4, 60, 7);
+        let mut sequential = QuantumState::new(4).unwrap();
+        for op in &gates {
+            assert!(circuit::apply_gate_op(&mut sequential, op).unwrap());
+        }
+        let mut fused = QuantumState::new(4).unwrap();
+        circuit::apply_gate_ops(&mut fused, &gates).unwrap();
+        assert!((sequential.get_fidelity(&fused).unwrap() - 1.0).abs() < 1e-10);
+    }
+    
+    #[test]
+    fn test_two_qubit_gates_in_every_wire_order() {
+        // CNOT from each ordered pair on 3 qubits, starting with only the control set
+        for control in 0..3 {
+            for target in (0..3).filter(|&t| t != control) {
+                let mut sim = QuantumState::new(3).unwrap();
+                sim.apply_single_qubit_gate(&Gates::pauli_x(), control).unwrap();
+                sim.apply_two_qubit_gate(&Gates::cnot(), control, target).unwrap();
+                let expected = (1 << control) | (1 << target);
+                assert!((sim.get_probabilities()[expected] - 1.0).abs() < 1e-12);
+                
+                // With the control clear the target must stay put
+                let mut idle = QuantumState::new(3).unwrap();
+                idle.apply_two_qubit_gate(&Gates::cnot(), control, target).unwrap();
+                assert!((idle.get_probabilities()[0] - 1.0).abs() < 1e-12);
+            }
+        }
+        
+        // SWAP across non-adjacent wires given high-to-low
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        sim.apply_two_qubit_gate(&Gates::swap(), 2, 0).unwrap();
+        assert!((sim.get_probabilities()[0b100] - 1.0).abs() < 1e-12);
+        
+        // CP is symmetric, so swapping its wires must not change the result
+        let mut forward = QuantumState::new(3).unwrap();
+        let mut reverse = QuantumState::new(3).unwrap();
+        for sim in [&mut forward, &mut reverse] {
+            sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+            sim.apply_single_qubit_gate(&Gates::hadamard(), 2).unwrap();
+        }
+        forward.apply_two_qubit_gate(&Gates::controlled_phase(0.7), 0, 2).unwrap();
+        reverse.apply_two_qubit_gate(&Gates::controlled_phase(0.7), 2, 0).unwrap();
+        assert!((forward.get_fidelity(&reverse).unwrap() - 1.0).abs() < 1e-12);
+    }
+    
+    #[test]
+    fn test_n_qubit_gate_reproduces_cnot_and_toffoli() {
+        let prepare = |sim: &mut QuantumState| {
+            sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+            sim.apply_single_qubit_gate(&Gates::rotation_y(0.9), 1).unwrap();
+            sim.apply_single_qubit_gate(&Gates::hadamard(), 2).unwrap();
+        };
+        
+        let mut expected = QuantumState::new(3).unwrap();
+        let mut generic = QuantumState::new(3).unwrap();
+        prepare(&mut expected);
+        prepare(&mut generic);
+        expected.apply_two_qubit_gate(&Gates::cnot(), 2, 0).unwrap();
+        generic.apply_n_qubit_gate(&Gates::cnot(), &[2, 0]).unwrap();
+        assert!((expected.get_fidelity(&generic).unwrap() - 1.0).abs() < 1e-12);
+        
+        expected.apply_three_qubit_gate(&Gates::toffoli(), 1, 2, 0).unwrap();
+        generic.apply_n_qubit_gate(&Gates::toffoli(), &[1, 2, 0]).unwrap();
+        assert!((expected.get_fidelity(&generic).unwrap() - 1.0).abs() < 1e-12);
+        
+        assert!(matches!(
+            generic.apply_n_qubit_gate(&Gates::cnot(), &[0, 1, 2]),
+            Err(QuantumError::LengthMismatch { expected: 64, found: 16 })
+        ));
+        assert!(matches!(
+            generic.apply_n_qubit_gate(&Gates::cnot(), &[1, 1]),
+            Err(QuantumError::IdenticalQubits { qubit: 1 })
+        ));
+    }
+    
+    #[test]
+    fn test_depolarizing_noise_error_rate_matches_p() {
+        // X and Y flip |0⟩, Z does not, so the observed flip rate is 2p/3
+        let p = 0.3;
+        let trials = 20_000;
+        let flips = (0..trials as u64)
+            .filter(|&seed| {
+                let mut sim = QuantumState::new(1).unwrap();
+                sim.apply_noise("depolarizing", 0, p, seed).unwrap();
+                sim.get_probabilities()[1] > 0.5
+            })
+            .count();
+        let rate = flips as f64 / trials as f64;
+        assert!((rate - 2.0 * p / 3.0).abs() < 0.015, "flip rate {}", rate);
+        
+        let mut sim = QuantumState::new(1).unwrap();
+        assert!(sim.apply_noise("depolarizing", 0, 1.2, 0).is_err());
+        assert!(sim.apply_noise("dephasing", 0, 0.1, 0).is_err());
+    }
+    
+    #[test]
+    fn test_full_amplitude_damping_relaxes_to_ground() {
+        for seed in 0..20 {
+            let mut sim = QuantumState::new(2).unwrap();
+            sim.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+            sim.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+            sim.apply_noise("amplitude_damping", 0, 1.0, seed).unwrap();
+            
+            let probs = sim.get_probabilities();
+            assert!((probs[0b00] - 0.5).abs() < 1e-12);
+            assert!((probs[0b10] - 0.5).abs() < 1e-12);
+        }
+        
+        // Zero damping leaves the state alone
+        let mut sim = QuantumState::new(1).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        let before = sim.clone();
+        sim.apply_noise("amplitude_damping", 0, 0.0, 1).unwrap();
+        assert!((sim.get_fidelity(&before).unwrap() - 1.0).abs() < 1e-12);
+    }
+    
+    #[test]
+    fn test_bell_state_reduced_density_matrix_is_maximally_mixed() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        sim.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        
+        for qubit in 0..2 {
+            let rho = sim.reduced_density_matrix(&[qubit]).unwrap();
+            let expected = [0.5, 0.0, 0.0, 0.5];
+            for (entry, want) in rho.iter().zip(expected) {
+                assert!((entry - Complex64::new(want, 0.0)).norm() < 1e-12);
+            }
+        }
+        
+        // Keeping both qubits gives back the pure projector
+        let rho = sim.reduced_density_matrix(&[0, 1]).unwrap();
+        assert!((rho[0] - Complex64::new(0.5, 0.0)).norm() < 1e-12);
+        assert!((rho[3] - Complex64::new(0.5, 0.0)).norm() < 1e-12);
+        assert!((rho[15] - Complex64::new(0.5, 0.0)).norm() < 1e-12);
+    }
+    
+    #[test]
+    fn test_entanglement_entropy_of_product_and_bell_states() {
+        let mut product = QuantumState::new(2).unwrap();
+        product.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        product.apply_single_qubit_gate(&Gates::rotation_y(0.4), 1).unwrap();
+        assert!(product.entanglement_entropy(&[0]).unwrap().abs() < 1e-10);
+        
+        let mut bell = QuantumState::new(2).unwrap();
+        bell.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        bell.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        assert!((bell.entanglement_entropy(&[0]).unwrap() - 1.0).abs() < 1e-10);
+        assert!((bell.entanglement_entropy(&[1]).unwrap() - 1.0).abs() < 1e-10);
+        assert!(bell.entanglement_entropy(&[0, 1]).unwrap().abs() < 1e-10);
+    }
+    
+    #[test]
+    fn test_fidelity_identical_orthogonal_and_mismatched() {
+        let mut plus = QuantumState::new(2).unwrap();
+        plus.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        assert!((plus.get_fidelity(&plus.clone()).unwrap() - 1.0).abs() < 1e-12);
+        
+        let zero = QuantumState::new(1).unwrap();
+        let mut one = QuantumState::new(1).unwrap();
+        one.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        assert!(zero.get_fidelity(&one).unwrap().abs() < 1e-12);
+        
+        assert_eq!(
+            zero.get_fidelity(&plus),
+            Err(QuantumError::DimensionMismatch { expected: 1, found: 2 })
+        );
+    }
+    
+    #[test]
+    fn test_batched_gates_match_sequential_and_report_bad_index() {
+        let gates = circuit::random_circuit(5, 50, 273);
+        
+        let mut sequential = QuantumState::new(5).unwrap();
+        for op in &gates {
+            assert!(apply_named_gate(&mut sequential, &op.name, &op.qubits, &op.params).unwrap());
+        }
+        let mut batched = QuantumState::new(5).unwrap();
+        let resolved = circuit::resolve_gates(&batched, &gates).unwrap();
+        circuit::apply_resolved(&mut batched, resolved).unwrap();
+        assert!((sequential.get_fidelity(&batched).unwrap() - 1.0).abs() < 1e-10);
+        
+        let mut broken = gates.clone();
+        broken[17] = GateOp::new("CNOT", vec![0, 9], vec![]);
+        assert!(matches!(
+            circuit::resolve_gates(&batched, &broken),
+            Err((17, QuantumError::QubitOutOfRange { qubit: 9, .. }))
+        ));
+    }
+    
+    #[test]
+    fn test_run_circuit_bell_histogram() {
+        let bell = vec![
+            GateOp::new("H", vec![0], vec![]),
+            GateOp::new("CNOT", vec![0, 1], vec![]),
+        ];
+        let counts = circuit::run_circuit(2, &bell, 2000, 274).unwrap();
+        
+        let zeros = counts.get("00").copied().unwrap_or(0);
+        let ones = counts.get("11").copied().unwrap_or(0);
+        assert_eq!(zeros + ones, 2000);
+        assert!((zeros as f64 / 2000.0 - 0.5).abs() < 0.05);
+        
+        assert!(circuit::run_circuit(2, &[GateOp::new("H", vec![3], vec![])], 10, 0).is_err());
+    }
+    
+    #[test]
+    fn test_cloned_simulator_is_independent() {
+        let mut original = QuantumState::new(2).unwrap();
+        original.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        let before = original.get_probabilities();
+        
+        let original_id = insert_simulator(original);
+        let copy = simulator_handle(original_id).unwrap().lock().unwrap().clone();
+        let clone_id = insert_simulator(copy);
+        assert_ne!(original_id, clone_id);
+        
+        let clone = simulator_handle(clone_id).unwrap();
+        clone.lock().unwrap().apply_single_qubit_gate(&Gates::pauli_x(), 1).unwrap();
+        assert_eq!(simulator_handle(original_id).unwrap().lock().unwrap().get_probabilities(), before);
+        assert!((clone.lock().unwrap().get_probabilities()[0b10] - 0.5).abs() < 1e-12);
+        
+        let mut simulators = SIMULATORS.write().unwrap();
+        simulators.remove(&original_id);
+        simulators.remove(&clone_id);
+    }
+    
+    #[test]
+    fn test_gate_then_dagger_restores_state() {
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        sim.apply_single_qubit_gate(&Gates::rotation_y(0.3), 2).unwrap();
+        let start = sim.clone();
+        
+        let u3 = Gates::u3(0.7, 1.9, -0.4);
+        sim.apply_single_qubit_gate(&u3, 1).unwrap();
+        sim.apply_single_qubit_gate(&gates::dagger(&u3), 1).unwrap();
+        assert!((sim.get_fidelity(&start).unwrap() - 1.0).abs() < 1e-12);
+        
+        let cp = Gates::controlled_phase(0.8);
+        sim.apply_two_qubit_gate(&cp, 0, 2).unwrap();
+        sim.apply_two_qubit_gate(&gates::dagger(&cp), 0, 2).unwrap();
+        assert!((sim.get_fidelity(&start).unwrap() - 1.0).abs() < 1e-12);
+        
+        // Through the dispatch flag, including a three-qubit gate
+        for (name, qubits, params) in [("U3", vec![1], vec![0.7, 1.9, -0.4]), ("CCX", vec![0, 2, 1], vec![]), ("S", vec![0], vec![])] {
+            assert!(apply_named_gate(&mut sim, name, &qubits, &params).unwrap());
+            assert!(apply_named_gate_with_inverse(&mut sim, name, &qubits, &params, true).unwrap());
+            assert!((sim.get_fidelity(&start).unwrap() - 1.0).abs() < 1e-12);
+        }
+        assert!(apply_named_gate_with_inverse(&mut sim, "RESET", &[0], &[], true).is_err());
+    }
+    
+    #[test]
+    fn test_iswap_and_sqrt_swap() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        assert!(apply_named_gate(&mut sim, "ISWAP", &[0, 1], &[]).unwrap());
+        assert!((sim.amplitudes[0b10] - Complex64::new(0.0, 1.0)).norm() < 1e-12);
+        
+        // iSWAP² = Z⊗Z: the swapped-twice states only pick up a sign
+        let mut twice = QuantumState::new(2).unwrap();
+        twice.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        twice.apply_single_qubit_gate(&Gates::rotation_y(0.6), 1).unwrap();
+        let mut expected = twice.clone();
+        for _ in 0..2 {
+            twice.apply_two_qubit_gate(&Gates::iswap(), 0, 1).unwrap();
+        }
+        expected.apply_single_qubit_gate(&Gates::pauli_z(), 0).unwrap();
+        expected.apply_single_qubit_gate(&Gates::pauli_z(), 1).unwrap();
+        assert!((twice.get_fidelity(&expected).unwrap() - 1.0).abs() < 1e-12);
+        
+        let mut root = QuantumState::new(3).unwrap();
+        root.apply_single_qubit_gate(&Gates::pauli_x(), 2).unwrap();
+        root.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        let mut swapped = root.clone();
+        for _ in 0..2 {
+            assert!(apply_named_gate(&mut root, "SQRTSWAP", &[2, 0], &[]).unwrap());
+        }
+        swapped.apply_two_qubit_gate(&Gates::swap(), 2, 0).unwrap();
+        for (a, b) in root.amplitudes.iter().zip(swapped.amplitudes.iter()) {
+            assert!((a - b).norm() < 1e-12);
+        }
+    }
+    
+    #[test]
+    fn test_crz_acts_only_when_control_is_set() {
+        let theta = 0.9;
+        
+        // Control clear: the target superposition is untouched
+        let mut idle = QuantumState::new(2).unwrap();
+        idle.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+        let before = idle.clone();
+        assert!(apply_named_gate(&mut idle, "CRZ", &[0, 1], &[theta]).unwrap());
+        for (a, b) in idle.amplitudes.iter().zip(before.amplitudes.iter()) {
+            assert!((a - b).norm() < 1e-12);
+        }
+        
+        // Control set: the target picks up RZ's relative phase e^(iθ)
+        let mut active = QuantumState::new(2).unwrap();
+        active.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        active.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+        assert!(apply_named_gate(&mut active, "CRZ", &[0, 1], &[theta]).unwrap());
+        let relative = active.amplitudes[0b11] / active.amplitudes[0b01];
+        assert!((relative - Complex64::new(theta.cos(), theta.sin())).norm() < 1e-12);
+        
+        // CRX(π) with the control set flips the target up to a phase
+        let mut flip = QuantumState::new(2).unwrap();
+        flip.apply_single_qubit_gate(&Gates::pauli_x(), 1).unwrap();
+        assert!(apply_named_gate(&mut flip, "CRX", &[1, 0], &[std::f64::consts::PI]).unwrap());
+        assert!((flip.get_probabilities()[0b11] - 1.0).abs() < 1e-12);
+        assert!(!apply_named_gate(&mut flip, "CRY", &[1, 0], &[]).unwrap());
+    }
+    
+    #[test]
+    fn test_fredkin_swaps_targets_only_under_control() {
+        // Control on qubit 2, targets 0 and 1, starting from |target 0 = 1⟩
+        let mut idle = QuantumState::new(3).unwrap();
+        idle.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        assert!(apply_named_gate(&mut idle, "CSWAP", &[2, 0, 1], &[]).unwrap());
+        assert!((idle.get_probabilities()[0b001] - 1.0).abs() < 1e-12);
+        
+        let mut active = QuantumState::new(3).unwrap();
+        active.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        active.apply_single_qubit_gate(&Gates::pauli_x(), 2).unwrap();
+        assert!(apply_named_gate(&mut active, "FREDKIN", &[2, 0, 1], &[]).unwrap());
+        assert!((active.get_probabilities()[0b110] - 1.0).abs() < 1e-12);
+    }
+    
+    #[test]
+    fn test_swap_test_estimates_fidelity() {
+        let shots = 20_000;
+        for seed in 0..4 {
+            let mut psi = QuantumState::new(2).unwrap();
+            let mut phi = QuantumState::new(2).unwrap();
+            circuit::apply_gate_ops(&mut psi, &circuit::random_circuit(2, 12, seed)).unwrap();
+            circuit::apply_gate_ops(&mut phi, &circuit::random_circuit(2, 12, seed + 100)).unwrap();
+            
+            let exact = psi.get_fidelity(&phi).unwrap();
+            let estimate = psi.swap_test(&phi, shots, seed).unwrap();
+            // Standard error of 2·P̂(0) − 1 is at most 1/√shots
+            assert!((estimate - exact).abs() < 4.0 / (shots as f64).sqrt(), "{} vs {}", estimate, exact);
+        }
+        
+        let same = QuantumState::new(1).unwrap();
+        assert!((same.swap_test(&same, 100, 0).unwrap() - 1.0).abs() < 1e-12);
+        assert!(same.swap_test(&QuantumState::new(2).unwrap(), 100, 0).is_err());
+    }
+    
+    #[test]
+    fn test_auto_normalize_keeps_norm_through_many_rotations() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(281);
+        
+        let mut sim = QuantumState::new(3).unwrap().with_auto_normalize(true);
+        for _ in 0..10_000 {
+            let qubit = rng.gen_range(0..3);
+            let gate = match rng.gen_range(0..3) {
+                0 => Gates::rotation_x(rng.gen_range(-3.2..3.2)),
+                1 => Gates::rotation_y(rng.gen_range(-3.2..3.2)),
+                _ => Gates::rotation_z(rng.gen_range(-3.2..3.2)),
+            };
+            sim.apply_single_qubit_gate(&gate, qubit).unwrap();
+        }
+        assert!((sim.norm() - 1.0).abs() < 1e-9);
+        assert!(sim.is_normalized(1e-9));
+        
+        sim.amplitudes *= Complex64::new(2.0, 0.0);
+        assert!(!sim.is_normalized(1e-6));
+    }
+    
+    #[test]
+    fn test_qasm_bell_and_ghz_programs() {
+        let bell = "OPENQASM 2.0;
+include \"qelib1.inc\";
+qreg q[2];
+creg c[2];
+h q[0];
+cx q[0], q[1];
+measure q -> c;
+";
+        let program = qasm::parse_qasm2(bell).unwrap();
+        assert_eq!(program.num_qubits, 2);
+        assert_eq!(program.gates, vec![
+            GateOp::new("H", vec![0], vec![]),
+            GateOp::new("CX", vec![0, 1], vec![]),
+        ]);
+        let counts = program.run(2000, 282).unwrap();
+        assert_eq!(counts.len(), 2);
+        assert!((counts["00"] as f64 / 2000.0 - 0.5).abs() < 0.05);
+        
+        // GHZ with a parameterised no-op, comments and a second register
+        let ghz = "OPENQASM 2.0;
+qreg a[1]; qreg b[2];
+creg c[3];
+h a[0]; // superpose
+rz(-pi/2 + 2*(pi/4)) a[0];
+cx a[0], b;
+measure a[0] -> c[0];
+measure b[0] -> c[1];
+measure b[1] -> c[2];
+";
+        let program = qasm::parse_qasm2(ghz).unwrap();
+        assert_eq!(program.gates.len(), 4);
+        assert!(program.gates[1].params[0].abs() < 1e-12);
+        let counts = program.run(2000, 7).unwrap();
+        assert_eq!(counts.len(), 2);
+        assert!((counts["111"] as f64 / 2000.0 - 0.5).abs() < 0.05);
+        
+        assert!(matches!(
+            qasm::parse_qasm2("qreg q[1];\nfoo q[0];"),
+            Err(QuantumError::Qasm { line: 2, .. })
+        ));
+        assert!(qasm::parse_qasm2("qreg q[1]; creg c[1]; measure q -> c; h q[0];").is_err());
+        
+        // Register sizes are bounded before anything is allocated
+        assert!(matches!(
+            qasm::parse_qasm2("OPENQASM 2.0;\nqreg q[4000000000]; h q;"),
+            Err(QuantumError::Qasm { line: 2, .. })
+        ));
+        assert!(qasm::parse_qasm2("qreg a[20];\nqreg b[20];").is_err());
+        assert!(qasm::parse_qasm2("qreg a[1];\nqreg b[18446744073709551615];").is_err());
+        assert!(qasm::parse_qasm2("creg c[4000000000];").is_err());
+        
+        // Gate arity and parameter counts are checked at parse time
+        for bad in ["qreg q[2];\ncx q[0];", "qreg q[2];\nrx q[0];", "qreg q[2];\nh q[0], q[1];"] {
+            assert!(matches!(qasm::parse_qasm2(bad), Err(QuantumError::Qasm { line: 2, .. })));
+        }
+        
+        let reset = qasm::parse_qasm2("qreg q[2]; x q; reset q[1];").unwrap();
+        assert_eq!(reset.run(50, 0).unwrap().get("01"), Some(&50));
+    }
+    
+    #[test]
+    fn test_qasm_history_round_trip() {
+        let source = "OPENQASM 2.0;
+qreg q[3];
+h q[0];
+cx q[0], q[2];
+u3(0.3, -1.2, pi/7) q[1];
+crz(2.5) q[2], q[1];
+ccx q[1], q[2], q[0];
+reset q[1];
+";
+        let imported = qasm::parse_qasm2(source).unwrap();
+        
+        let mut sim = QuantumState::new(3).unwrap();
+        assert!(sim.to_qasm2().is_err());
+        sim.set_record_history(true);
+        for op in &imported.gates {
+            assert!(apply_named_gate(&mut sim, &op.name, &op.qubits, &op.params).unwrap());
+        }
+        assert_eq!(sim.history(), imported.gates.as_slice());
+        
+        let reimported = qasm::parse_qasm2(&sim.to_qasm2().unwrap()).unwrap();
+        assert_eq!(reimported.gates, imported.gates);
+        
+        // Inverses are recorded as the gate that undoes them
+        sim.reset();
+        assert!(apply_named_gate_with_inverse(&mut sim, "S", &[0], &[], true).unwrap());
+        assert!(apply_named_gate_with_inverse(&mut sim, "ISWAP", &[0, 1], &[], true).unwrap());
+        assert_eq!(sim.history()[0], GateOp::new("U3", vec![0], vec![0.0, 0.0, -std::f64::consts::FRAC_PI_2]));
+        assert!(sim.to_qasm2().is_err());
+        
+        // Recording is opt-in and can be dropped without touching the state
+        sim.clear_history();
+        assert!(sim.history().is_empty());
+        sim.set_record_history(false);
+        assert!(apply_named_gate(&mut sim, "H", &[0], &[]).unwrap());
+        assert!(sim.history().is_empty());
+        assert!(QuantumState::new(1).unwrap().history().is_empty());
+        
+        // Changes the history cannot show make it incomplete until `reset`
+        sim.reset();
+        sim.set_record_history(true);
+        assert!(apply_named_gate(&mut sim, "RX", &[0], &[0.3]).unwrap());
+        assert!(sim.to_qasm2().is_ok());
+        sim.apply_unitary(&Gates::hadamard(), 1).unwrap();
+        assert!(sim.to_qasm2().is_err());
+        assert!(apply_named_gate(&mut sim, "H", &[1], &[]).unwrap());
+        assert!(sim.to_qasm2().is_err());
+        sim.reset();
+        assert_eq!(circuit::apply_all_to_all_layer(&mut sim, "CZ", &[]).unwrap(), 3);
+        assert_eq!(sim.history().len(), 3);
+        assert!(sim.to_qasm2().is_ok());
+        assert!(!QuantumState::ghz(2).unwrap().history_is_complete());
+    }
+    
+    #[test]
+    fn test_state_bytes_round_trip_exactly() {
+        let mut sim = QuantumState::new(4).unwrap();
+        circuit::apply_gate_ops(&mut sim, &circuit::random_circuit(4, 40, 284)).unwrap();
+        
+        let bytes = sim.to_bytes();
+        assert_eq!(bytes.len(), 4 + 16 * 16);
+        let restored = QuantumState::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.num_qubits, 4);
+        for (a, b) in restored.amplitudes.iter().zip(sim.amplitudes.iter()) {
+            assert_eq!(a.re.to_bits(), b.re.to_bits());
+            assert_eq!(a.im.to_bits(), b.im.to_bits());
+        }
+        assert_eq!(restored.to_bytes(), bytes);
+        
+        assert!(QuantumState::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(QuantumState::from_bytes(&[1, 0]).is_err());
+        assert!(QuantumState::from_bytes(&[200, 0, 0, 0]).is_err());
+    }
+    
+    #[test]
+    fn test_ghz_and_w_state_constructors() {
+        let ghz = QuantumState::ghz(4).unwrap();
+        let probs = ghz.get_probabilities();
+        assert!((probs[0] - 0.5).abs() < 1e-12);
+        assert!((probs[15] - 0.5).abs() < 1e-12);
+        assert!((probs.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        
+        let w = QuantumState::w_state(5).unwrap();
+        let probs = w.get_probabilities();
+        for (index, p) in probs.iter().enumerate() {
+            let expected = if index.count_ones() == 1 { 0.2 } else { 0.0 };
+            assert!((p - expected).abs() < 1e-12);
+        }
+        assert!(QuantumState::w_state(0).is_err());
+    }
+    
+    #[test]
+    fn test_measure_subset_of_bell_state() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        sim.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        
+        let counts = sim.measure_subset_with_seed(&[0], 4000, 286).unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["0"] + counts["1"], 4000);
+        assert!((counts["0"] as f64 / 4000.0 - 0.5).abs() < 0.05);
+        
+        // Listed order sets the character order
+        let mut flipped = QuantumState::new(3).unwrap();
+        flipped.apply_single_qubit_gate(&Gates::pauli_x(), 2).unwrap();
+        assert_eq!(flipped.measure_subset(&[2, 0], 10).unwrap().get("10"), Some(&10));
+        assert!(flipped.measure_subset(&[0, 0], 10).is_err());
+    }
+    
+    #[test]
+    fn test_sampling_matches_skewed_distribution_and_never_defaults_to_zero() {
+        // P(1) = sin²(0.1) ≈ 0.00997 on each of 3 qubits
+        let mut sim = QuantumState::new(3).unwrap();
+        for qubit in 0..3 {
+            sim.apply_single_qubit_gate(&Gates::rotation_y(0.2), qubit).unwrap();
+        }
+        let shots = 200_000;
+        let samples = sim.sample_with_seed(shots, 287);
+        let probs = sim.get_probabilities();
+        for (state, p) in probs.iter().enumerate() {
+            let observed = samples.iter().filter(|&&s| s == state).count() as f64 / shots as f64;
+            assert!((observed - p).abs() < 5.0 * (p / shots as f64).sqrt() + 1e-4, "state {}: {} vs {}", state, observed, p);
+        }
+        
+        // A sub-normalised state must not leak the missing mass into |00⟩
+        let mut leaky = QuantumState::new(2).unwrap();
+        leaky.amplitudes[0] = Complex64::new(0.0, 0.0);
+        leaky.amplitudes[3] = Complex64::new(0.9, 0.0);
+        leaky.invalidate_cache();
+        assert!(leaky.sample_with_seed(10_000, 1).iter().all(|&s| s == 3));
+    }
+    
+    #[test]
+    fn test_global_phase_changes_amplitudes_not_probabilities() {
+        let mut sim = QuantumState::new(2).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        let probs = sim.get_probabilities();
+        let (re_before, _) = sim.state_vector_parts();
+        
+        assert!(apply_named_gate(&mut sim, "GPHASE", &[], &[std::f64::consts::FRAC_PI_2]).unwrap());
+        assert_eq!(sim.get_probabilities(), probs);
+        
+        // e^(iπ/2) turns the real amplitudes imaginary
+        let (re, im) = sim.state_vector_parts();
+        for (k, &before) in re_before.iter().enumerate() {
+            assert!(re[k].abs() < 1e-12);
+            assert!((im[k] - before).abs() < 1e-12);
+        }
+        
+        assert!(apply_named_gate_with_inverse(&mut sim, "GPHASE", &[], &[std::f64::consts::FRAC_PI_2], true).unwrap());
+        assert!((sim.state_vector_parts().0[0] - re_before[0]).abs() < 1e-12);
+        assert!(apply_named_gate(&mut sim, "GPHASE", &[0], &[1.0]).is_err());
+    }
+    
+    #[test]
+    fn test_qft_matches_dft_and_inverts() {
+        let uniform = {
+            let mut sim = QuantumState::new(3).unwrap();
+            sim.apply_qft(&[0, 1, 2]).unwrap();
+            sim
+        };
+        for amp in uniform.amplitudes.iter() {
+            assert!((amp - Complex64::new(1.0 / 8f64.sqrt(), 0.0)).norm() < 1e-12);
+        }
+        
+        // Against the DFT of a random state, over a non-contiguous register
+        let mut sim = QuantumState::new(4).unwrap();
+        circuit::apply_gate_ops(&mut sim, &circuit::random_circuit(4, 30, 289)).unwrap();
+        let start = sim.clone();
+        let qubits = [3, 0, 2, 1];
+        sim.apply_qft(&qubits).unwrap();
+        
+        let local = |index: usize| -> usize {
+            qubits.iter().enumerate().map(|(j, &q)| ((index >> q) & 1) << j).sum()
+        };
+        for y in 0..16 {
+            let expected: Complex64 = (0..16)
+                .map(|x| {
+                    let angle = 2.0 * std::f64::consts::PI * (local(x) * local(y)) as f64 / 16.0;
+                    start.amplitudes[x] * Complex64::new(angle.cos(), angle.sin()) / 4.0
+                })
+                .sum();
+            assert!((sim.amplitudes[y] - expected).norm() < 1e-10);
+        }
+        
+        sim.apply_inverse_qft(&qubits).unwrap();
+        assert!((sim.get_fidelity(&start).unwrap() - 1.0).abs() < 1e-10);
+    }
+    
+    #[test]
+    fn test_grover_iteration_amplifies_marked_state() {
+        let marked = 0b101;
+        let mut oracle = vec![Complex64::new(0.0, 0.0); 64];
+        for k in 0..8 {
+            oracle[k * 8 + k] = Complex64::new(if k == marked { -1.0 } else { 1.0 }, 0.0);
+        }
+        
+        let mut sim = QuantumState::new(3).unwrap();
+        for qubit in 0..3 {
+            sim.apply_single_qubit_gate(&Gates::hadamard(), qubit).unwrap();
+        }
+        let before = sim.get_probabilities()[marked];
+        
+        // The oracle's local index puts its first qubit most significant
+        sim.apply_n_qubit_gate(&oracle, &[2, 1, 0]).unwrap();
+        sim.apply_grover_diffusion(&[0, 1, 2]).unwrap();
+        
+        // One iteration on N = 8 lifts P(marked) from 1/8 to 25/32
+        let after = sim.get_probabilities()[marked];
+        assert!(after > before);
+        assert!((after - 25.0 / 32.0).abs() < 1e-12);
+        assert!((sim.norm() - 1.0).abs() < 1e-12);
+    }
+    
+    #[test]
+    fn test_three_control_mcx_truth_table() {
+        // Controls 0, 2, 3 and target 1 over every basis input
+        for input in 0..16usize {
+            let mut sim = QuantumState::new(4).unwrap();
+            for qubit in (0..4).filter(|q| input & (1 << q) != 0) {
+                sim.apply_single_qubit_gate(&Gates::pauli_x(), qubit).unwrap();
+            }
+            assert!(apply_named_gate(&mut sim, "MCX", &[0, 2, 3, 1], &[]).unwrap());
+            
+            let all_controls = input & 0b1101 == 0b1101;
+            let expected = if all_controls { input ^ 0b0010 } else { input };
+            assert!((sim.get_probabilities()[expected] - 1.0).abs() < 1e-12, "input {:04b}", input);
+        }
+        
+        // MCZ flips the sign of |1111⟩ only
+        let mut sim = QuantumState::new(4).unwrap();
+        for qubit in 0..4 {
+            sim.apply_single_qubit_gate(&Gates::hadamard(), qubit).unwrap();
+        }
+        sim.apply_mcz(&[0, 1, 2], 3).unwrap();
+        assert!(sim.amplitudes[15].re < 0.0);
+        assert!(sim.amplitudes.iter().take(15).all(|amp| amp.re > 0.0));
+        
+        assert!(sim.apply_mcx(&[1, 1], 0).is_err());
+        assert!(apply_named_gate(&mut sim, "MCZ", &[], &[]).is_err());
+    }
+    
+    #[test]
+    fn test_most_likely_and_probability_lookup_on_ghz() {
+        let ghz = QuantumState::ghz(3).unwrap();
+        let (bitstring, probability) = ghz.most_likely();
+        // 000 and 111 tie; the lower index wins
+        assert_eq!(bitstring, "000");
+        assert!((probability - 0.5).abs() < 1e-12);
+        
+        assert!((ghz.probability_of("111").unwrap() - 0.5).abs() < 1e-12);
+        assert!(ghz.probability_of("010").unwrap().abs() < 1e-12);
+        assert!(ghz.probability_of("11").is_err());
+        assert!(ghz.probability_of("1x1").is_err());
+        
+        let mut flipped = QuantumState::new(2).unwrap();
+        flipped.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        assert_eq!(flipped.most_likely().0, "01");
+    }
+    
+    #[test]
+    fn test_density_matrix_depolarizing_diagonal() {
+        let p = 0.3;
+        let mut rho = DensityMatrix::new(1).unwrap();
+        rho.apply_noise("depolarizing", 0, p).unwrap();
+        
+        let probabilities = rho.probabilities();
+        assert!((probabilities[0] - (1.0 - 2.0 * p / 3.0)).abs() < 1e-12);
+        assert!((probabilities[1] - 2.0 * p / 3.0).abs() < 1e-12);
+        assert!(rho.rho[(0, 1)].norm() < 1e-12);
+        
+        // Named gates build the same Bell state as the statevector path
+        let mut rho_h = DensityMatrix::new(2).unwrap();
+        rho_h.apply_gate(&GateOp { name: "H".to_string(), qubits: vec![0], params: vec![] }).unwrap();
+        rho_h.apply_gate(&GateOp { name: "CNOT".to_string(), qubits: vec![0, 1], params: vec![] }).unwrap();
+        let bell = rho_h.probabilities();
+        assert!((bell[0] - 0.5).abs() < 1e-12 && (bell[3] - 0.5).abs() < 1e-12);
+        assert!((rho_h.rho[(0, 3)].re - 0.5).abs() < 1e-12);
+    }
+    
+    #[test]
+    fn test_density_matrix_purity() {
+        let mut state = QuantumState::new(2).unwrap();
+        state.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        state.apply_two_qubit_gate(&Gates::cnot(), 0, 1).unwrap();
+        let pure = DensityMatrix::from_state(&state).unwrap();
+        assert!((pure.purity() - 1.0).abs() < 1e-12);
+        assert!(pure.is_pure(1e-9));
+        
+        // Full depolarization (p = 3/4) on both qubits leaves I/4
+        let mut mixed = DensityMatrix::new(2).unwrap();
+        mixed.apply_noise("depolarizing", 0, 0.75).unwrap();
+        mixed.apply_noise("depolarizing", 1, 0.75).unwrap();
+        assert!((mixed.purity() - 0.25).abs() < 1e-12);
+        assert!(!mixed.is_pure(1e-9));
+    }
+    
+    #[test]
+    fn test_register_size_is_capped() {
+        assert_eq!(
+            QuantumState::new(40).err(),
+            Some(QuantumError::TooManyQubits { requested: 40, max: quantum_state::MAX_QUBITS })
+        );
+        assert!(QuantumState::ghz(64).is_err());
+        assert!(QuantumState::from_bytes(&40u32.to_le_bytes()).is_err());
+    }
+    
+    #[test]
+    fn test_distinct_simulators_run_concurrently() {
+        let ids: Vec<u32> = (0..4).map(|_| insert_simulator(QuantumState::new(3).unwrap())).collect();
+        
+        // Each worker holds only its own simulator's lock while applying gates
+        let workers: Vec<_> = ids
+            .iter()
+            .map(|&id| {
+                std::thread::spawn(move || {
+                    let handle = simulator_handle(id).unwrap();
+                    for _ in 0..200 {
+                        let mut simulator = handle.lock().unwrap();
+                        apply_named_gate(&mut simulator, "H", &[0], &[]).unwrap();
+                        apply_named_gate(&mut simulator, "CNOT", &[0, 1], &[]).unwrap();
+                        apply_named_gate(&mut simulator, "CNOT", &[0, 1], &[]).unwrap();
+                        apply_named_gate(&mut simulator, "H", &[0], &[]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        
+        for &id in &ids {
+            let probabilities = simulator_handle(id).unwrap().lock().unwrap().get_probabilities();
+            assert!((probabilities[0] - 1.0).abs() < 1e-9);
+        }
+        
+        // Comparing a simulator with itself locks it only once
+        assert_eq!(with_simulator_pair(&SimulatorRef::Id(ids[0]), &SimulatorRef::Id(ids[0]), |a, b| a.num_qubits + b.num_qubits), Some(6));
+        assert!(with_simulator_pair(&SimulatorRef::Id(ids[1]), &SimulatorRef::Id(ids[0]), |a, b| a.get_fidelity(b)).unwrap().is_ok());
+        
+        let mut simulators = SIMULATORS.write().unwrap();
+        for id in ids {
+            simulators.remove(&id);
+        }
+    }
+    
+    #[test]
+    fn test_boxed_simulator_frees_state_on_drop() {
+        let boxed = BoxedSimulator(Arc::new(Mutex::new(QuantumState::new(2).unwrap())));
+        let sim_ref = SimulatorRef::Boxed(Arc::clone(&boxed.0));
+        let state = Arc::downgrade(&boxed.0);
+        
+        let handle = sim_ref.handle().unwrap();
+        apply_named_gate(&mut handle.lock().unwrap(), "X", &[1], &[]).unwrap();
+        assert_eq!(boxed.0.lock().unwrap().get_probabilities()[0b10], 1.0);
+        
+        // Once the JS handle and any in-flight call release it, the state goes
+        drop(handle);
+        drop(sim_ref);
+        drop(boxed);
+        assert!(state.upgrade().is_none());
+    }
+    
+    #[test]
+    fn test_raw_samples_match_measure_histogram() {
+        let mut sim = QuantumState::new(3).unwrap();
+        sim.apply_single_qubit_gate(&Gates::hadamard(), 0).unwrap();
+        sim.apply_single_qubit_gate(&Gates::rotation_y(1.1), 2).unwrap();
+        
+        let raw = sim.sample_raw_with_seed(500, 298);
+        assert_eq!(raw.len(), 500);
+        
+        let mut counts = HashMap::new();
+        for state in raw {
+            *counts.entry(format!("{:03b}", state)).or_insert(0) += 1;
+        }
+        assert_eq!(counts, sim.measure_with_seed(500, 298));
+        assert_eq!(sim.sample_raw(40).len(), 40);
+    }
+    
+    #[test]
+    fn test_apply_unitary_validates_matrix() {
+        let mut custom = QuantumState::new(2).unwrap();
+        custom.apply_unitary(&Gates::hadamard(), 1).unwrap();
+        let mut builtin = QuantumState::new(2).unwrap();
+        builtin.apply_single_qubit_gate(&Gates::hadamard(), 1).unwrap();
+        assert_eq!(custom.get_probabilities(), builtin.get_probabilities());
+        
+        let zero = Complex64::new(0.0, 0.0);
+        let not_unitary = [Complex64::new(1.0, 0.0), Complex64::new(1.0, 0.0), zero, Complex64::new(1.0, 0.0)];
+        let before = custom.get_probabilities();
+        assert!(matches!(custom.apply_unitary(&not_unitary, 0), Err(QuantumError::InvalidArgument { .. })));
+        assert_eq!(custom.get_probabilities(), before);
+    }
+    
+    #[test]
+    fn test_product_state_builder() {
+        let h = std::f64::consts::FRAC_1_SQRT_2;
+        let zero = [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)];
+        let plus = [Complex64::new(h, 0.0), Complex64::new(h, 0.0)];
+        
+        // |+0⟩: qubit 0 in |0⟩, qubit 1 in |+⟩
+        let sim = QuantumState::from_product(&[zero, plus]).unwrap();
+        assert_eq!(sim.num_qubits, 2);
+        let expected = [h, 0.0, h, 0.0];
+        for (amp, want) in sim.amplitudes.iter().zip(expected) {
+            assert!((amp - Complex64::new(want, 0.0)).norm() < 1e-12);
+        }
+        
+        assert!(QuantumState::from_product(&[[Complex64::new(1.0, 0.0); 2]]).is_err());
+        assert_eq!(QuantumState::from_product(&[]).unwrap().amplitudes.len(), 1);
+    }
+    
+    #[test]
+    fn test_unknown_gate_name_is_an_error() {
+        let mut sim = QuantumState::new(1).unwrap();
+        assert_eq!(
+            apply_named_gate(&mut sim, "HADAMARD", &[0], &[]),
+            Err(QuantumError::UnknownGate { name: "HADAMARD".to_string() })
+        );
+        assert_eq!(sim.get_probabilities()[0], 1.0);
+        
+        let supported = circuit::supported_gate_names();
+        assert!(supported.iter().any(|name| name == "H"));
+        assert!(supported.iter().any(|name| name == "MCX"));
+        assert!(!supported.iter().any(|name| name == "HADAMARD"));
+    }
+    
+    #[test]
+    fn test_register_size_round_trips_through_store() {
+        let id = insert_simulator(QuantumState::new(5).unwrap());
+        let handle = simulator_handle(id).unwrap();
+        let simulator = handle.lock().unwrap();
+        assert_eq!(simulator.num_qubits, 5);
+        assert_eq!(simulator.dimension(), 32);
+        drop(simulator);
+        
+        SIMULATORS.write().unwrap().remove(&id);
+    }
+    
+    #[test]
+    fn test_swap_test_rejects_oversized_combined_register() {
+        let psi = QuantumState::new(15).unwrap();
+        let phi = QuantumState::new(15).unwrap();
+        assert_eq!(
+            psi.swap_test(&phi, 10, 0),
+            Err(QuantumError::TooManyQubits { requested: 31, max: quantum_state::MAX_QUBITS })
+        );
+    }
+    
+    #[test]
+    fn test_density_matrix_size_is_capped() {
+        let cap = density_matrix::MAX_DENSITY_QUBITS;
+        for requested in [16, 64] {
+            assert_eq!(
+                DensityMatrix::new(requested).err(),
+                Some(QuantumError::TooManyQubits { requested, max: cap })
+            );
+        }
+        assert!(DensityMatrix::from_state(&QuantumState::new(cap + 1).unwrap()).is_err());
+        assert_eq!(DensityMatrix::new(3).unwrap().rho.nrows(), 8);
+    }
+    
+    #[test]
+    fn test_pseudo_gates_run_through_gate_lists() {
+        let gates = vec![
+            GateOp::new("X", vec![0], vec![]),
+            GateOp::new("X", vec![1], vec![]),
+            GateOp::new("MCX", vec![0, 1, 2], vec![]),
+            GateOp::new("GPHASE", vec![], vec![std::f64::consts::PI]),
+            GateOp::new("MCZ", vec![0, 1, 2], vec![]),
+            GateOp::new("H", vec![3], vec![]),
+            GateOp::new("RESET", vec![3], vec![]),
+        ];
+        assert!(circuit::validate_circuit(4, &gates).is_empty());
+        
+        let mut sim = QuantumState::new(4).unwrap();
+        sim.set_record_history(true);
+        circuit::apply_gate_ops(&mut sim, &gates).unwrap();
+        // GPHASE(π) and the MCZ phase cancel on |0111⟩
+        assert!((sim.amplitudes[0b0111] - Complex64::new(1.0, 0.0)).norm() < 1e-12);
+        let names: Vec<&str> = sim.history().iter().map(|op| op.name.as_str()).collect();
+        assert_eq!(names, ["X", "X", "MCX", "GPHASE", "MCZ", "H", "RESET"]);
+        
+        let broken = vec![GateOp::new("H", vec![0], vec![]), GateOp::new("MCX", vec![], vec![])];
+        assert!(matches!(circuit::apply_gate_ops_indexed(&mut sim, &broken), Err((1, QuantumError::InvalidArgument { .. }))));
+        assert!(matches!(
+            circuit::resolve_gates(&sim, &gates[2..3]),
+            Err((0, QuantumError::InvalidArgument { .. }))
+        ));
+    }
+    
+    #[test]
+    fn test_density_matrix_trace_distance() {
+        let zero = DensityMatrix::new(1).unwrap();
+        let mut one = DensityMatrix::new(1).unwrap();
+        one.apply_single_qubit_gate(&Gates::pauli_x(), 0).unwrap();
+        assert!((zero.trace_distance(&one).unwrap() - 1.0).abs() < 1e-12);
+        assert!(zero.trace_distance(&zero).unwrap().abs() < 1e-12);
+        
+        // |0⟩ against I/2 is ½(|½| + |−½|)
+        let mut mixed = DensityMatrix::new(1).unwrap();
+        mixed.apply_noise("depolarizing", 0, 0.75).unwrap();
+        assert!((zero.trace_distance(&mixed).unwrap() - 0.5).abs() < 1e-12);
+        
+        assert!(zero.trace_distance(&DensityMatrix::new(2).unwrap()).is_err());
+    }
+}
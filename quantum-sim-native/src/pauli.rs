@@ -0,0 +1,169 @@
+use nalgebra::DMatrix;
+use num_complex::Complex64;
+
+use crate::error::QuantumError;
+
+/// A Pauli string P = i^(#Y) · X^x_mask · Z^z_mask, so that
+/// P|y⟩ = i^(#Y) · (−1)^popcount(y & z_mask) · |y ⊕ x_mask⟩.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PauliMasks {
+    pub x_mask: usize,
+    pub z_mask: usize,
+    pub y_count: usize,
+}
+
+impl PauliMasks {
+    pub fn from_paulis(paulis: &[(usize, char)], num_qubits: usize) -> Result<Self, QuantumError> {
+        let mut masks = PauliMasks { x_mask: 0, z_mask: 0, y_count: 0 };
+        let mut seen = 0usize;
+        
+        for &(qubit, letter) in paulis {
+            if qubit >= num_qubits {
+                return Err(QuantumError::QubitOutOfRange { qubit, num_qubits });
+            }
+            let bit = 1 << qubit;
+            if seen & bit != 0 {
+                return Err(QuantumError::IdenticalQubits { qubit });
+            }
+            seen |= bit;
+            
+            match letter.to_ascii_uppercase() {
+                'I' => {},
+                'X' => masks.x_mask |= bit,
+                'Z' => masks.z_mask |= bit,
+                'Y' => {
+                    masks.x_mask |= bit;
+                    masks.z_mask |= bit;
+                    masks.y_count += 1;
+                },
+                other => {
+                    return Err(QuantumError::InvalidArgument {
+                        reason: format!("unknown Pauli operator {}", other),
+                    })
+                },
+            }
+        }
+        
+        Ok(masks)
+    }
+    
+    /// Coefficient c in P|y⟩ = c|y ⊕ x_mask⟩.
+    pub fn phase(&self, y: usize) -> Complex64 {
+        let i_power = match self.y_count % 4 {
+            0 => Complex64::new(1.0, 0.0),
+            1 => Complex64::new(0.0, 1.0),
+            2 => Complex64::new(-1.0, 0.0),
+            _ => Complex64::new(0.0, -1.0),
+        };
+        if (y & self.z_mask).count_ones() % 2 == 1 {
+            -i_power
+        } else {
+            i_power
+        }
+    }
+    
+    /// ⟨ψ|P|ψ⟩, which is real for a Hermitian Pauli string.
+    pub fn expectation(&self, amplitudes: &[Complex64]) -> f64 {
+        amplitudes
+            .iter()
+            .enumerate()
+            .map(|(y, amp)| (amplitudes[y ^ self.x_mask].conj() * self.phase(y) * amp).re)
+            .sum()
+    }
+    
+    /// P applied to a full amplitude vector.
+    pub fn apply_to(&self, amplitudes: &[Complex64]) -> Vec<Complex64> {
+        let mut result = vec![Complex64::new(0.0, 0.0); amplitudes.len()];
+        for (y, amp) in amplitudes.iter().enumerate() {
+            result[y ^ self.x_mask] = self.phase(y) * amp;
+        }
+        result
+    }
+}
+
+/// Parse a Pauli string where character k acts on qubit k ("XZ" = X₀Z₁).
+pub fn parse_pauli_string(paulis: &str) -> Vec<(usize, char)> {
+    paulis.chars().enumerate().collect()
+}
+
+/// A weighted Pauli string, one term of a Hamiltonian H = Σ cₖ Pₖ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PauliTerm {
+    pub coefficient: f64,
+    pub paulis: Vec<(usize, char)>,
+}
+
+/// H|ψ⟩ = Σ cₖ Pₖ|ψ⟩ without materializing H.
+pub fn apply_terms(terms: &[PauliTerm], amplitudes: &[Complex64], num_qubits: usize) -> Result<Vec<Complex64>, QuantumError> {
+    let mut result = vec![Complex64::new(0.0, 0.0); amplitudes.len()];
+    for term in terms {
+        let masks = PauliMasks::from_paulis(&term.paulis, num_qubits)?;
+        for (y, amp) in amplitudes.iter().enumerate() {
+            result[y ^ masks.x_mask] += masks.phase(y) * amp * term.coefficient;
+        }
+    }
+    Ok(result)
+}
+
+/// Largest register `exact_ground_state` will materialize as a dense matrix.
+pub const MAX_EXACT_QUBITS: usize = 10;
+
+/// Largest register `stabilizer_renyi_entropy` will enumerate 4ⁿ strings for.
+pub const MAX_MAGIC_QUBITS: usize = 8;
+
+/// Stabilizer Rényi-2 entropy M₂ = −log₂(Σ_P ⟨P⟩⁴ / 2ⁿ) over all 4ⁿ Pauli
+/// strings: zero for stabilizer states, positive for magic states.
+pub fn stabilizer_renyi_entropy(amplitudes: &[Complex64], num_qubits: usize) -> Result<f64, QuantumError> {
+    if num_qubits > MAX_MAGIC_QUBITS {
+        return Err(QuantumError::InvalidArgument {
+            reason: format!("stabilizer Rényi entropy is limited to {} qubits, got {}", MAX_MAGIC_QUBITS, num_qubits),
+        });
+    }
+    
+    let dim = 1usize << num_qubits;
+    let mut sum = 0.0;
+    for x_mask in 0..dim {
+        for z_mask in 0..dim {
+            let y_count = (x_mask & z_mask).count_ones() as usize;
+            let masks = PauliMasks { x_mask, z_mask, y_count };
+            sum += masks.expectation(amplitudes).powi(4);
+        }
+    }
+    
+    Ok(-(sum / dim as f64).log2())
+}
+
+/// Dense 2ⁿ × 2ⁿ matrix of H = Σ cₖ Pₖ.
+pub fn hamiltonian_matrix(terms: &[PauliTerm], num_qubits: usize) -> Result<DMatrix<Complex64>, QuantumError> {
+    let dim = 1 << num_qubits;
+    let mut matrix = DMatrix::zeros(dim, dim);
+    
+    for term in terms {
+        let masks = PauliMasks::from_paulis(&term.paulis, num_qubits)?;
+        for column in 0..dim {
+            matrix[(column ^ masks.x_mask, column)] += masks.phase(column) * term.coefficient;
+        }
+    }
+    
+    Ok(matrix)
+}
+
+/// Lowest eigenvalue and eigenvector of H by dense Hermitian diagonalization.
+pub fn exact_ground_state(terms: &[PauliTerm], num_qubits: usize) -> Result<(f64, Vec<Complex64>), QuantumError> {
+    if num_qubits > MAX_EXACT_QUBITS {
+        return Err(QuantumError::InvalidArgument {
+            reason: format!("exact diagonalization is limited to {} qubits, got {}", MAX_EXACT_QUBITS, num_qubits),
+        });
+    }
+    
+    let eigen = hamiltonian_matrix(terms, num_qubits)?.symmetric_eigen();
+    let (ground, energy) = eigen
+        .eigenvalues
+        .iter()
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(i, &e)| (i, e))
+        .unwrap();
+    
+    Ok((energy, eigen.eigenvectors.column(ground).iter().cloned().collect()))
+}
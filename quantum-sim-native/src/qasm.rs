@@ -0,0 +1,381 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use crate::circuit::{apply_gate_ops, gate_problems, GateOp};
+use crate::error::QuantumError;
+use crate::quantum_state::{QuantumState, MAX_QUBITS};
+
+/// A parsed OpenQASM 2.0 program. Registers are laid out back to back in
+/// declaration order, so `qreg a[2]; qreg b[1];` puts b[0] on qubit 2.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Circuit {
+    pub num_qubits: usize,
+    pub num_clbits: usize,
+    pub gates: Vec<GateOp>,
+    // (qubit, clbit) pairs; measurements are terminal
+    pub measurements: Vec<(usize, usize)>,
+}
+
+impl Circuit {
+    /// Apply the gates to a fresh register and sample `shots` outcomes. With
+    /// measurements the histogram is over the classical bits (clbit 0
+    /// rightmost, unwritten bits 0); without, over the whole register.
+    /// Resets draw their collapse from the same seed as the shots.
+    pub fn run(&self, shots: usize, seed: u64) -> Result<HashMap<String, usize>, QuantumError> {
+        let mut simulator = QuantumState::new(self.num_qubits)?;
+        let mut rng = StdRng::seed_from_u64(seed);
+        for segment in self.gates.split_inclusive(|op| op.name == "RESET") {
+            match segment.split_last() {
+                Some((last, unitary)) if last.name == "RESET" => {
+                    apply_gate_ops(&mut simulator, unitary)?;
+                    simulator.reset_qubit_with_rng(last.qubits[0], &mut rng)?;
+                }
+                _ => apply_gate_ops(&mut simulator, segment)?,
+            }
+        }
+        if self.measurements.is_empty() {
+            return Ok(simulator.measure_with_seed(shots, seed));
+        }
+        
+        let mut counts = HashMap::new();
+        for state in simulator.sample_with_seed(shots, seed) {
+            let mut clbits = vec!['0'; self.num_clbits];
+            for &(qubit, clbit) in &self.measurements {
+                if state & (1 << qubit) != 0 {
+                    clbits[self.num_clbits - 1 - clbit] = '1';
+                }
+            }
+            *counts.entry(clbits.into_iter().collect()).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+}
+
+/// Serialize gates on a single register `q` as OpenQASM 2.0 over qelib1.
+/// Parameters are printed with full round-trip precision.
+pub fn to_qasm2(num_qubits: usize, gates: &[GateOp]) -> Result<String, QuantumError> {
+    let mut program = format!("OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[{}];\n", num_qubits);
+    for op in gates {
+        let name = qasm_name(&op.name).ok_or_else(|| QuantumError::InvalidArgument {
+            reason: format!("gate {} has no OpenQASM 2.0 equivalent", op.name),
+        })?;
+        program.push_str(name);
+        if !op.params.is_empty() {
+            let params: Vec<String> = op.params.iter().map(|p| p.to_string()).collect();
+            program.push_str(&format!("({})", params.join(",")));
+        }
+        let qubits: Vec<String> = op.qubits.iter().map(|q| format!("q[{}]", q)).collect();
+        program.push_str(&format!(" {};\n", qubits.join(",")));
+    }
+    Ok(program)
+}
+
+fn qasm_name(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "H" => "h",
+        "X" => "x",
+        "Y" => "y",
+        "Z" => "z",
+        "S" => "s",
+        "T" => "t",
+        "RX" => "rx",
+        "RY" => "ry",
+        "RZ" => "rz",
+        "U3" | "U" => "u3",
+        "CNOT" | "CX" => "cx",
+        "CZ" => "cz",
+        "SWAP" => "swap",
+        "CP" | "CPHASE" => "cu1",
+        "CRX" => "crx",
+        "CRY" => "cry",
+        "CRZ" => "crz",
+        "CCX" | "TOFFOLI" => "ccx",
+        "CSWAP" | "FREDKIN" => "cswap",
+        "RESET" => "reset",
+        _ => return None,
+    })
+}
+
+// Register name → (offset, size)
+type Registers = HashMap<String, (usize, usize)>;
+
+/// Parse an OpenQASM 2.0 program over the qelib1 gates this crate supports
+/// (h, x, y, z, s, t, rx, ry, rz, u3/u, cx, cz, swap, cp/cu1, crx, cry, crz,
+/// ccx, cswap), plus `measure`, `reset` and `barrier`. Gate definitions and
+/// classically controlled operations are rejected. A gate or reset on an
+/// already-measured qubit is an error because measurements are terminal.
+pub fn parse_qasm2(src: &str) -> Result<Circuit, QuantumError> {
+    let mut qregs = Registers::new();
+    let mut cregs = Registers::new();
+    let mut circuit = Circuit { num_qubits: 0, num_clbits: 0, gates: Vec::new(), measurements: Vec::new() };
+    
+    let stripped: String = src
+        .lines()
+        .map(|line| line.split("//").next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n");
+    
+    let mut line = 1;
+    for raw in stripped.split(';') {
+        let leading = &raw[..raw.len() - raw.trim_start().len()];
+        let statement_line = line + leading.matches('\n').count();
+        line += raw.matches('\n').count();
+        
+        let statement = raw.split_whitespace().collect::<Vec<_>>().join(" ");
+        if statement.is_empty() {
+            continue;
+        }
+        parse_statement(&statement, &mut qregs, &mut cregs, &mut circuit)
+            .map_err(|reason| QuantumError::Qasm { line: statement_line, reason })?;
+    }
+    
+    Ok(circuit)
+}
+
+fn parse_statement(statement: &str, qregs: &mut Registers, cregs: &mut Registers, circuit: &mut Circuit) -> Result<(), String> {
+    let (keyword, rest) = match statement.find([' ', '(']) {
+        Some(split) => (&statement[..split], statement[split..].trim()),
+        None => (statement, ""),
+    };
+    
+    match keyword {
+        "OPENQASM" => {
+            if rest != "2.0" {
+                return Err(format!("unsupported OpenQASM version {}", rest));
+            }
+        }
+        "include" => {}
+        "qreg" => circuit.num_qubits = declare_register(rest, circuit.num_qubits, qregs)?,
+        "creg" => circuit.num_clbits = declare_register(rest, circuit.num_clbits, cregs)?,
+        "barrier" => {}
+        "measure" => {
+            let (source, target) = rest
+                .split_once("->")
+                .ok_or_else(|| "measure needs the form q -> c".to_string())?;
+            let qubits = resolve_argument(source.trim(), qregs)?;
+            let clbits = resolve_argument(target.trim(), cregs)?;
+            if qubits.len() != clbits.len() {
+                return Err(format!("cannot measure {} qubits into {} bits", qubits.len(), clbits.len()));
+            }
+            circuit.measurements.extend(qubits.into_iter().zip(clbits));
+        }
+        "gate" | "opaque" | "if" => return Err(format!("'{}' is not supported", keyword)),
+        _ => {
+            let (params, args) = if let Some(inner) = rest.strip_prefix('(') {
+                let close = matching_paren(inner).ok_or_else(|| "unclosed parameter list".to_string())?;
+                let params = inner[..close]
+                    .split(',')
+                    .map(|expr| evaluate(expr.trim()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                (params, inner[close + 1..].trim())
+            } else {
+                (Vec::new(), rest)
+            };
+            
+            let name = gate_name(keyword).ok_or_else(|| format!("unknown gate '{}'", keyword))?;
+            let operands = args
+                .split(',')
+                .map(|arg| resolve_argument(arg.trim(), qregs))
+                .collect::<Result<Vec<_>, _>>()?;
+            
+            // Whole-register operands broadcast against single qubits
+            let width = operands.iter().map(Vec::len).max().unwrap_or(0);
+            if operands.iter().any(|qubits| qubits.len() != 1 && qubits.len() != width) {
+                return Err("register operands have different sizes".to_string());
+            }
+            for k in 0..width {
+                let qubits: Vec<usize> = operands
+                    .iter()
+                    .map(|qubits| if qubits.len() == 1 { qubits[0] } else { qubits[k] })
+                    .collect();
+                if let Some(&(qubit, _)) = circuit.measurements.iter().find(|(q, _)| qubits.contains(q)) {
+                    return Err(format!("qubit {} is used after being measured", qubit));
+                }
+                // Catch arity and parameter mistakes here, where the line is known
+                let op = GateOp::new(name, qubits, params.clone());
+                if let Some(problem) = gate_problems(&op, circuit.num_qubits).into_iter().next() {
+                    return Err(problem.to_string());
+                }
+                circuit.gates.push(op);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Index of the ')' closing a list whose '(' has already been consumed
+fn matching_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(i),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn gate_name(qasm_name: &str) -> Option<&'static str> {
+    Some(match qasm_name {
+        "h" => "H",
+        "x" => "X",
+        "y" => "Y",
+        "z" => "Z",
+        "s" => "S",
+        "t" => "T",
+        "rx" => "RX",
+        "ry" => "RY",
+        "rz" => "RZ",
+        "u3" | "u" | "U" => "U3",
+        "cx" | "CX" => "CX",
+        "cz" => "CZ",
+        "swap" => "SWAP",
+        "cp" | "cu1" => "CP",
+        "crx" => "CRX",
+        "cry" => "CRY",
+        "crz" => "CRZ",
+        "ccx" => "CCX",
+        "cswap" => "CSWAP",
+        "reset" => "RESET",
+        _ => return None,
+    })
+}
+
+// `name[size]`, allocated after the registers declared so far. Returns the
+// new total, which may not exceed `MAX_QUBITS` for either kind of register
+fn declare_register(declaration: &str, offset: usize, registers: &mut Registers) -> Result<usize, String> {
+    let (name, size) = split_indexed(declaration)?;
+    let size = size.ok_or_else(|| format!("register {} needs a size", name))?;
+    let total = offset
+        .checked_add(size)
+        .filter(|&total| total <= MAX_QUBITS)
+        .ok_or_else(|| format!("register {} takes the total past {} bits", name, MAX_QUBITS))?;
+    if registers.insert(name.to_string(), (offset, size)).is_some() {
+        return Err(format!("register {} declared twice", name));
+    }
+    Ok(total)
+}
+
+// `name[i]` to one index, or a bare `name` to the whole register
+fn resolve_argument(argument: &str, registers: &Registers) -> Result<Vec<usize>, String> {
+    let (name, index) = split_indexed(argument)?;
+    let &(offset, size) = registers
+        .get(name)
+        .ok_or_else(|| format!("unknown register {}", name))?;
+    match index {
+        Some(index) if index >= size => Err(format!("index {} out of range for {}[{}]", index, name, size)),
+        Some(index) => Ok(vec![offset + index]),
+        None => Ok((offset..offset + size).collect()),
+    }
+}
+
+fn split_indexed(text: &str) -> Result<(&str, Option<usize>), String> {
+    match text.split_once('[') {
+        Some((name, index)) => {
+            let index = index
+                .strip_suffix(']')
+                .and_then(|index| index.trim().parse().ok())
+                .ok_or_else(|| format!("bad index in '{}'", text))?;
+            Ok((name.trim(), Some(index)))
+        }
+        None => Ok((text, None)),
+    }
+}
+
+/// Evaluate a parameter expression: numbers, `pi`, + - * / and parentheses.
+fn evaluate(expr: &str) -> Result<f64, String> {
+    let tokens = tokenize(expr)?;
+    let mut pos = 0;
+    let value = parse_sum(&tokens, &mut pos)?;
+    if pos != tokens.len() {
+        return Err(format!("unexpected trailing input in '{}'", expr));
+    }
+    Ok(value)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Number(f64),
+    Op(char),
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = expr.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if "+-*/()".contains(c) {
+            tokens.push(Token::Op(c));
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.' || chars[i] == 'e' || chars[i] == 'E'
+                || ((chars[i] == '-' || chars[i] == '+') && matches!(chars[i - 1], 'e' | 'E')))
+            {
+                i += 1;
+            }
+            let literal: String = chars[start..i].iter().collect();
+            tokens.push(Token::Number(literal.parse().map_err(|_| format!("bad number '{}'", literal))?));
+        } else if chars[i..].starts_with(&['p', 'i']) {
+            tokens.push(Token::Number(PI));
+            i += 2;
+        } else {
+            return Err(format!("unexpected '{}' in '{}'", c, expr));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_sum(tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
+    let mut value = parse_product(tokens, pos)?;
+    while let Some(&Token::Op(op @ ('+' | '-'))) = tokens.get(*pos) {
+        *pos += 1;
+        let rhs = parse_product(tokens, pos)?;
+        value = if op == '+' { value + rhs } else { value - rhs };
+    }
+    Ok(value)
+}
+
+fn parse_product(tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
+    let mut value = parse_unary(tokens, pos)?;
+    while let Some(&Token::Op(op @ ('*' | '/'))) = tokens.get(*pos) {
+        *pos += 1;
+        let rhs = parse_unary(tokens, pos)?;
+        value = if op == '*' { value * rhs } else { value / rhs };
+    }
+    Ok(value)
+}
+
+fn parse_unary(tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
+    match tokens.get(*pos) {
+        Some(Token::Op('-')) => {
+            *pos += 1;
+            Ok(-parse_unary(tokens, pos)?)
+        }
+        Some(Token::Op('+')) => {
+            *pos += 1;
+            parse_unary(tokens, pos)
+        }
+        Some(Token::Op('(')) => {
+            *pos += 1;
+            let value = parse_sum(tokens, pos)?;
+            if tokens.get(*pos) != Some(&Token::Op(')')) {
+                return Err("unbalanced parentheses".to_string());
+            }
+            *pos += 1;
+            Ok(value)
+        }
+        Some(&Token::Number(value)) => {
+            *pos += 1;
+            Ok(value)
+        }
+        _ => Err("expected a number".to_string()),
+    }
+}
@@ -1,119 +1,1302 @@
-use nalgebra::DVector;
+use nalgebra::{DMatrix, DVector};
 use num_complex::Complex64;
-use std::collections::HashMap;
 use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::circuit::{self, GateOp};
+use crate::error::QuantumError;
+use crate::gates::{fuse_single_qubit, is_unitary, Gates};
+use crate::pauli::{PauliMasks, PauliTerm};
+use crate::qasm;
+
+// Amplitude pairs per rayon task; below this a gate stays on one thread
+const PARALLEL_MIN_PAIRS: usize = 1 << 12;
+
+// Allowed deviation of |ψ| from 1 for externally supplied statevectors
+pub const NORMALIZATION_TOLERANCE: f64 = 1e-6;
+
+// Largest register allocated; 2^30 amplitudes already take 16 GiB
+pub const MAX_QUBITS: usize = 30;
+
+// Finest output rounding; an f64 carries about 17 significant digits
+pub const MAX_OUTPUT_DECIMALS: u32 = 17;
 
+#[derive(Clone)]
 pub struct QuantumState {
     pub amplitudes: DVector<Complex64>,
     pub num_qubits: usize,
+    // Lazily computed |amp|² vector; cleared by every mutating method.
+    // Code writing `amplitudes` directly must call `invalidate_cache`.
+    probabilities_cache: OnceLock<Vec<f64>>,
+    // Lazily computed single-qubit reduced density matrices, same lifetime
+    single_qubit_rdm_cache: OnceLock<Vec<[Complex64; 4]>>,
+    // Logical → physical qubit map used by `applyGate`; None = identity
+    layout: Option<Vec<usize>>,
+    // Decimal places applied to values handed to JS; None = full precision
+    output_precision: Option<u32>,
+    // Renormalize after every gate to stop rounding error accumulating
+    auto_normalize: bool,
+    // Named gates and resets applied through the circuit dispatch, in order.
+    // Only appended to while `record_history` is on, since it grows with
+    // every gate and is never trimmed
+    history: Vec<GateOp>,
+    record_history: bool,
+    // Bumped by every write to `amplitudes`, through `invalidate_cache` or
+    // `apply_global_phase`
+    revision: u64,
+    // `revision` as of the last `record_gates`
+    recorded_revision: u64,
+    // Set once the amplitudes were changed by something the history does not
+    // show; only `reset` clears it
+    history_incomplete: bool,
 }
 
 impl QuantumState {
-    pub fn new(num_qubits: usize) -> Self {
+    /// |0…0⟩ on `num_qubits`, refusing registers beyond `MAX_QUBITS`.
+    pub fn new(num_qubits: usize) -> Result<Self, QuantumError> {
+        check_num_qubits(num_qubits)?;
         let size = 1 << num_qubits;
         let mut amplitudes = DVector::zeros(size);
         amplitudes[0] = Complex64::new(1.0, 0.0); // |00...0⟩ state
-        
+        let mut state = Self::with_amplitudes(amplitudes, num_qubits);
+        state.history_incomplete = false;
+        Ok(state)
+    }
+    
+    fn with_amplitudes(amplitudes: DVector<Complex64>, num_qubits: usize) -> Self {
         Self {
             amplitudes,
             num_qubits,
+            probabilities_cache: OnceLock::new(),
+            single_qubit_rdm_cache: OnceLock::new(),
+            layout: None,
+            output_precision: None,
+            auto_normalize: false,
+            history: Vec::new(),
+            record_history: false,
+            revision: 0,
+            recorded_revision: 0,
+            // A loaded statevector is not the result of any recorded gates
+            history_incomplete: true,
+        }
+    }
+    
+    /// Builder-style switch for renormalizing after every gate.
+    pub fn with_auto_normalize(mut self, enabled: bool) -> Self {
+        self.auto_normalize = enabled;
+        self
+    }
+    
+    /// (|0…0⟩ + |1…1⟩)/√2. With zero qubits this is just the empty register.
+    pub fn ghz(num_qubits: usize) -> Result<Self, QuantumError> {
+        let mut state = Self::new(num_qubits)?;
+        if num_qubits > 0 {
+            let amp = Complex64::new(std::f64::consts::FRAC_1_SQRT_2, 0.0);
+            state.amplitudes[0] = amp;
+            state.amplitudes[(1 << num_qubits) - 1] = amp;
+            state.invalidate_cache();
+        }
+        Ok(state)
+    }
+    
+    /// Equal superposition of the `num_qubits` single-excitation states.
+    pub fn w_state(num_qubits: usize) -> Result<Self, QuantumError> {
+        if num_qubits == 0 {
+            return Err(QuantumError::InvalidArgument { reason: "a W state needs at least one qubit".to_string() });
+        }
+        
+        let mut state = Self::new(num_qubits)?;
+        let amp = Complex64::new(1.0 / (num_qubits as f64).sqrt(), 0.0);
+        state.amplitudes[0] = Complex64::new(0.0, 0.0);
+        for qubit in 0..num_qubits {
+            state.amplitudes[1 << qubit] = amp;
+        }
+        state.invalidate_cache();
+        Ok(state)
+    }
+    
+    /// Load a precomputed statevector. The length must be a power of two and
+    /// the norm within `NORMALIZATION_TOLERANCE` of 1.
+    pub fn from_amplitudes(amplitudes: Vec<Complex64>) -> Result<Self, QuantumError> {
+        if !amplitudes.len().is_power_of_two() {
+            return Err(QuantumError::InvalidArgument {
+                reason: format!("statevector length {} is not a power of two", amplitudes.len()),
+            });
         }
+        let num_qubits = amplitudes.len().trailing_zeros() as usize;
+        check_num_qubits(num_qubits)?;
+        let norm: f64 = amplitudes.iter().map(|a| a.norm_sqr()).sum::<f64>().sqrt();
+        if (norm - 1.0).abs() > NORMALIZATION_TOLERANCE {
+            return Err(QuantumError::InvalidArgument {
+                reason: format!("statevector norm {} is not 1", norm),
+            });
+        }
+        
+        Ok(Self::with_amplitudes(DVector::from_vec(amplitudes), num_qubits))
     }
     
-    pub fn apply_single_qubit_gate(&mut self, gate: &[Complex64; 4], qubit: usize) {
+    /// Tensor product of per-qubit states, `single_qubit_states[k]` on qubit
+    /// k. Each 2-vector must be normalized within `NORMALIZATION_TOLERANCE`.
+    pub fn from_product(single_qubit_states: &[[Complex64; 2]]) -> Result<Self, QuantumError> {
+        check_num_qubits(single_qubit_states.len())?;
+        for (qubit, state) in single_qubit_states.iter().enumerate() {
+            let norm = (state[0].norm_sqr() + state[1].norm_sqr()).sqrt();
+            if (norm - 1.0).abs() > NORMALIZATION_TOLERANCE {
+                return Err(QuantumError::InvalidArgument {
+                    reason: format!("state for qubit {} has norm {}, not 1", qubit, norm),
+                });
+            }
+        }
+        
+        // Kronecker product, each new qubit becoming the most significant bit
+        let mut amplitudes = vec![Complex64::new(1.0, 0.0)];
+        for state in single_qubit_states {
+            amplitudes = state
+                .iter()
+                .flat_map(|&factor| amplitudes.iter().map(move |&amp| factor * amp))
+                .collect();
+        }
+        Ok(Self::with_amplitudes(DVector::from_vec(amplitudes), single_qubit_states.len()))
+    }
+    
+    /// Snapshot as bytes: `num_qubits` as a little-endian u32, then each
+    /// amplitude as little-endian (re, im) f64 pairs in basis-index order.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + 16 * self.amplitudes.len());
+        bytes.extend_from_slice(&(self.num_qubits as u32).to_le_bytes());
+        for amp in self.amplitudes.iter() {
+            bytes.extend_from_slice(&amp.re.to_le_bytes());
+            bytes.extend_from_slice(&amp.im.to_le_bytes());
+        }
+        bytes
+    }
+    
+    /// Inverse of `to_bytes`. The amplitudes are restored bit for bit and
+    /// checked for normalization like `from_amplitudes`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, QuantumError> {
+        let (header, body) = data.split_first_chunk::<4>().ok_or(QuantumError::LengthMismatch {
+            expected: 4,
+            found: data.len(),
+        })?;
+        let num_qubits = u32::from_le_bytes(*header) as usize;
+        check_num_qubits(num_qubits)?;
+        let expected = 16usize << num_qubits;
+        if body.len() != expected {
+            return Err(QuantumError::LengthMismatch { expected: 4 + expected, found: data.len() });
+        }
+        
+        let amplitudes = body
+            .chunks_exact(16)
+            .map(|pair| {
+                let (re, im) = pair.split_at(8);
+                Complex64::new(
+                    f64::from_le_bytes(re.try_into().unwrap()),
+                    f64::from_le_bytes(im.try_into().unwrap()),
+                )
+            })
+            .collect();
+        Self::from_amplitudes(amplitudes)
+    }
+    
+    /// Return to |0…0⟩ in place, keeping the allocation and settings.
+    pub fn reset(&mut self) {
+        self.amplitudes.fill(Complex64::new(0.0, 0.0));
+        self.amplitudes[0] = Complex64::new(1.0, 0.0);
+        self.history.clear();
+        self.invalidate_cache();
+        self.recorded_revision = self.revision;
+        self.history_incomplete = false;
+    }
+    
+    /// Gates applied by name while recording was on, since construction or
+    /// the last `reset`/`clear_history`. Raw matrices, noise and measurements
+    /// are not recorded, and neither is the starting state of `from_amplitudes`;
+    /// `history_is_complete` tells whether anything like that happened.
+    pub fn history(&self) -> &[GateOp] {
+        &self.history
+    }
+    
+    /// Turn gate recording on or off. Off by default: the history keeps one
+    /// `GateOp` per gate for the lifetime of the simulator, which long or
+    /// repeated circuits should not pay for unless they export it. Switching
+    /// off keeps what was already recorded.
+    pub fn set_record_history(&mut self, enabled: bool) {
+        self.record_history = enabled;
+    }
+    
+    /// Drop the recorded gates without touching the state. Unless nothing
+    /// was recorded, the history no longer replays to the state until `reset`.
+    pub fn clear_history(&mut self) {
+        if !self.history.is_empty() {
+            self.history_incomplete = true;
+        }
+        self.history = Vec::new();
+    }
+    
+    /// Counter bumped by every write to the amplitudes. Take it before
+    /// applying gates and hand it to `record_gates` afterwards.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+    
+    /// Record `gates` as the cause of every amplitude change since `since`.
+    /// Unrecorded changes before that, or gates applied while recording is
+    /// off, mark the history incomplete.
+    pub fn record_gates(&mut self, since: u64, gates: &[GateOp]) {
+        if since != self.recorded_revision || (!self.record_history && self.revision != since) {
+            self.history_incomplete = true;
+        }
+        if self.record_history {
+            self.history.extend_from_slice(gates);
+        }
+        self.recorded_revision = self.revision;
+    }
+    
+    /// Whether the amplitudes are unchanged since the last recorded gate.
+    pub fn history_is_current(&self) -> bool {
+        self.revision == self.recorded_revision
+    }
+    
+    /// Whether replaying the history from |0…0⟩ reproduces the state: every
+    /// change since construction or `reset` went through `record_gates`.
+    pub fn history_is_complete(&self) -> bool {
+        !self.history_incomplete && self.history_is_current()
+    }
+    
+    /// The recorded history as an OpenQASM 2.0 program on one register `q`.
+    /// Fails while recording is off or the history is incomplete, as the
+    /// program would not reproduce the state.
+    pub fn to_qasm2(&self) -> Result<String, QuantumError> {
+        if !self.record_history {
+            return Err(QuantumError::InvalidArgument {
+                reason: "gate history is not being recorded".to_string(),
+            });
+        }
+        if !self.history_is_complete() {
+            return Err(QuantumError::InvalidArgument {
+                reason: "the state was changed by operations the gate history does not record".to_string(),
+            });
+        }
+        qasm::to_qasm2(self.num_qubits, &self.history)
+    }
+    
+    /// Round presented values to `decimals` places, at most
+    /// `MAX_OUTPUT_DECIMALS`, or restore full precision with None.
+    pub fn set_output_precision(&mut self, decimals: Option<u32>) -> Result<(), QuantumError> {
+        if let Some(decimals) = decimals.filter(|&decimals| decimals > MAX_OUTPUT_DECIMALS) {
+            return Err(QuantumError::InvalidArgument {
+                reason: format!("output precision {} exceeds {} decimal places", decimals, MAX_OUTPUT_DECIMALS),
+            });
+        }
+        self.output_precision = decimals;
+        Ok(())
+    }
+    
+    /// Install a logical → physical permutation, or clear it with None.
+    pub fn set_layout(&mut self, layout: Option<Vec<usize>>) -> Result<(), QuantumError> {
+        if let Some(map) = &layout {
+            if map.len() != self.num_qubits {
+                return Err(QuantumError::LengthMismatch { expected: self.num_qubits, found: map.len() });
+            }
+            self.check_qubits(map)?;
+        }
+        self.layout = layout;
+        Ok(())
+    }
+    
+    /// Physical positions of logical qubits. Indices outside the layout pass
+    /// through unchanged so range checks downstream still report them.
+    pub fn physical_qubits(&self, logical: &[usize]) -> Vec<usize> {
+        match &self.layout {
+            Some(map) => logical.iter().map(|&q| map.get(q).copied().unwrap_or(q)).collect(),
+            None => logical.to_vec(),
+        }
+    }
+    
+    /// Round a value for display according to the output precision. The
+    /// stored amplitudes are never rounded.
+    pub fn present(&self, value: f64) -> f64 {
+        match self.output_precision {
+            Some(decimals) => {
+                let scale = 10f64.powi(decimals as i32);
+                (value * scale).round() / scale
+            },
+            None => value,
+        }
+    }
+    
+    pub fn invalidate_cache(&mut self) {
+        self.revision += 1;
+        self.probabilities_cache = OnceLock::new();
+        self.single_qubit_rdm_cache = OnceLock::new();
+    }
+    
+    // Shared tail of every unitary update
+    fn gate_applied(&mut self) {
+        if self.auto_normalize {
+            self.normalize();
+        }
+        self.invalidate_cache();
+    }
+    
+    pub fn has_cached_probabilities(&self) -> bool {
+        self.probabilities_cache.get().is_some()
+    }
+    
+    /// Apply a user-supplied 2×2 matrix (row-major) after checking it is
+    /// unitary to within `circuit::UNITARY_TOLERANCE`.
+    pub fn apply_unitary(&mut self, matrix: &[Complex64; 4], qubit: usize) -> Result<(), QuantumError> {
+        if !is_unitary(matrix, 2, circuit::UNITARY_TOLERANCE) {
+            return Err(QuantumError::InvalidArgument { reason: "matrix is not unitary".to_string() });
+        }
+        self.apply_single_qubit_gate(matrix, qubit)
+    }
+    
+    pub fn apply_single_qubit_gate(&mut self, gate: &[Complex64; 4], qubit: usize) -> Result<(), QuantumError> {
+        self.check_qubit(qubit)?;
+        let target_bit = 1 << qubit;
+        let block = 2 * target_bit;
+        
+        // Each block of 2·target_bit amplitudes holds the i0 indices in its
+        // lower half and their i1 partners in the upper half, so blocks and
+        // the pairs within them are disjoint and can be updated in parallel
+        self.amplitudes
+            .as_mut_slice()
+            .par_chunks_mut(block)
+            .with_min_len((PARALLEL_MIN_PAIRS / target_bit).max(1))
+            .for_each(|chunk| {
+                let (half0, half1) = chunk.split_at_mut(target_bit);
+                
+                half0.par_iter_mut()
+                    .zip(half1.par_iter_mut())
+                    .with_min_len(PARALLEL_MIN_PAIRS)
+                    .for_each(|(slot0, slot1)| {
+                        let (amp0, amp1) = (*slot0, *slot1);
+                        *slot0 = gate[0] * amp0 + gate[1] * amp1;
+                        *slot1 = gate[2] * amp0 + gate[3] * amp1;
+                    });
+            });
+        
+        self.gate_applied();
+        Ok(())
+    }
+    
+    /// Multiply every amplitude by e^(iφ). Probabilities and reduced density
+    /// matrices are unchanged, so the caches stay valid.
+    pub fn apply_global_phase(&mut self, phi: f64) {
+        let phase = Complex64::new(phi.cos(), phi.sin());
+        self.amplitudes.iter_mut().for_each(|amp| *amp *= phase);
+        self.revision += 1;
+    }
+    
+    /// Apply a run of single-qubit gates on one wire in a single pass.
+    pub fn apply_fused_single_qubit(&mut self, gates: &[[Complex64; 4]], qubit: usize) -> Result<(), QuantumError> {
+        self.apply_single_qubit_gate(&fuse_single_qubit(gates), qubit)
+    }
+    
+    /// Row/column k of the 4×4 matrix is the local basis state
+    /// (control bit) << 1 | (target bit), whichever wire is physically lower,
+    /// so `control > target` and non-adjacent wires need no special casing.
+    pub fn apply_two_qubit_gate(&mut self, gate: &[Complex64; 16], control: usize, target: usize) -> Result<(), QuantumError> {
+        self.check_qubits(&[control, target])?;
         let n = self.num_qubits;
         let size = 1 << n;
-        let target_bit = 1 << qubit;
+        let control_bit = 1 << control;
+        let target_bit = 1 << target;
+        
+        for i in 0..size {
+            if (i & control_bit == 0) && (i & target_bit == 0) {
+                let i00 = i;
+                let i01 = i ^ target_bit;
+                let i10 = i ^ control_bit;
+                let i11 = i ^ control_bit ^ target_bit;
+                
+                let amp00 = self.amplitudes[i00];
+                let amp01 = self.amplitudes[i01];
+                let amp10 = self.amplitudes[i10];
+                let amp11 = self.amplitudes[i11];
+                
+                self.amplitudes[i00] = gate[0] * amp00 + gate[1] * amp01 + gate[2] * amp10 + gate[3] * amp11;
+                self.amplitudes[i01] = gate[4] * amp00 + gate[5] * amp01 + gate[6] * amp10 + gate[7] * amp11;
+                self.amplitudes[i10] = gate[8] * amp00 + gate[9] * amp01 + gate[10] * amp10 + gate[11] * amp11;
+                self.amplitudes[i11] = gate[12] * amp00 + gate[13] * amp01 + gate[14] * amp10 + gate[15] * amp11;
+            }
+        }
+        
+        self.gate_applied();
+        Ok(())
+    }
+    
+    /// Three-qubit gate on 8-element groups. Row/column k of the matrix is
+    /// the local basis state with `q0` as its most significant bit and `q2`
+    /// as its least, matching the (control, target) order of two-qubit gates.
+    pub fn apply_three_qubit_gate(&mut self, gate: &[Complex64; 64], q0: usize, q1: usize, q2: usize) -> Result<(), QuantumError> {
+        self.check_qubits(&[q0, q1, q2])?;
+        let size = 1 << self.num_qubits;
+        let bits = [1 << q0, 1 << q1, 1 << q2];
+        let mask = bits[0] | bits[1] | bits[2];
+        let offsets: [usize; 8] = std::array::from_fn(|k| {
+            (0..3).filter(|j| k & (4 >> j) != 0).map(|j| bits[j]).sum()
+        });
+        
+        for base in (0..size).filter(|i| i & mask == 0) {
+            let group: [Complex64; 8] = std::array::from_fn(|k| self.amplitudes[base + offsets[k]]);
+            for (row, &offset) in offsets.iter().enumerate() {
+                self.amplitudes[base + offset] = (0..8).map(|col| gate[row * 8 + col] * group[col]).sum();
+            }
+        }
+        
+        self.gate_applied();
+        Ok(())
+    }
+    
+    /// Dense k-qubit gate given as a row-major 2ᵏ×2ᵏ matrix, with `qubits[0]`
+    /// as the most significant local bit like the fixed-arity methods.
+    pub fn apply_n_qubit_gate(&mut self, gate: &[Complex64], qubits: &[usize]) -> Result<(), QuantumError> {
+        self.check_qubits(qubits)?;
+        let k = qubits.len();
+        let dim = 1 << k;
+        if gate.len() != dim * dim {
+            return Err(QuantumError::LengthMismatch { expected: dim * dim, found: gate.len() });
+        }
+        
+        let size = 1 << self.num_qubits;
+        let mask: usize = qubits.iter().map(|&q| 1 << q).sum();
+        let offsets: Vec<usize> = (0..dim)
+            .map(|local| {
+                (0..k)
+                    .filter(|j| local & (1 << (k - 1 - j)) != 0)
+                    .map(|j| 1 << qubits[j])
+                    .sum()
+            })
+            .collect();
+        let mut group = vec![Complex64::new(0.0, 0.0); dim];
+        
+        for base in (0..size).filter(|i| i & mask == 0) {
+            for (slot, &offset) in group.iter_mut().zip(&offsets) {
+                *slot = self.amplitudes[base + offset];
+            }
+            for (row, &offset) in offsets.iter().enumerate() {
+                self.amplitudes[base + offset] = gate[row * dim..(row + 1) * dim]
+                    .iter()
+                    .zip(&group)
+                    .map(|(g, a)| g * a)
+                    .sum();
+            }
+        }
+        
+        self.gate_applied();
+        Ok(())
+    }
+    
+    /// Two-level (Givens) rotation mixing only the amplitudes at basis
+    /// indices `index1` and `index2`:
+    /// [[cos θ, -e^(-iφ) sin θ], [e^(iφ) sin θ, cos θ]].
+    pub fn apply_givens_rotation(&mut self, index1: usize, index2: usize, theta: f64, phi: f64) -> Result<(), QuantumError> {
+        let dimension = self.amplitudes.len();
+        for &index in &[index1, index2] {
+            if index >= dimension {
+                return Err(QuantumError::BasisIndexOutOfRange { index, dimension });
+            }
+        }
+        if index1 == index2 {
+            return Err(QuantumError::IdenticalIndices { index: index1 });
+        }
+        
+        let c = theta.cos();
+        let s = theta.sin();
+        let phase = Complex64::new(phi.cos(), phi.sin());
         
-        let mut new_amplitudes = self.amplitudes.clone();
+        let amp1 = self.amplitudes[index1];
+        let amp2 = self.amplitudes[index2];
+        self.amplitudes[index1] = amp1 * c - phase.conj() * s * amp2;
+        self.amplitudes[index2] = phase * s * amp1 + amp2 * c;
+        self.gate_applied();
+        
+        Ok(())
+    }
+    
+    /// Single-qubit gate acting only on basis states where `control` is set.
+    pub fn apply_controlled_single_qubit_gate(&mut self, gate: &[Complex64; 4], control: usize, target: usize) -> Result<(), QuantumError> {
+        self.check_qubits(&[control, target])?;
+        let size = 1 << self.num_qubits;
+        let control_bit = 1 << control;
+        let target_bit = 1 << target;
         
         for i in 0..size {
-            if i & target_bit == 0 {
+            if (i & control_bit != 0) && (i & target_bit == 0) {
                 let i0 = i;
                 let i1 = i | target_bit;
                 
                 let amp0 = self.amplitudes[i0];
                 let amp1 = self.amplitudes[i1];
                 
-                new_amplitudes[i0] = gate[0] * amp0 + gate[1] * amp1;
-                new_amplitudes[i1] = gate[2] * amp0 + gate[3] * amp1;
+                self.amplitudes[i0] = gate[0] * amp0 + gate[1] * amp1;
+                self.amplitudes[i1] = gate[2] * amp0 + gate[3] * amp1;
             }
         }
         
-        self.amplitudes = new_amplitudes;
+        self.gate_applied();
+        Ok(())
     }
     
-    pub fn apply_two_qubit_gate(&mut self, gate: &[Complex64; 16], control: usize, target: usize) {
-        let n = self.num_qubits;
-        let size = 1 << n;
+    /// Two-qubit gate acting only on basis states where `control` is set.
+    pub fn apply_controlled_two_qubit_gate(&mut self, gate: &[Complex64; 16], control: usize, qubit0: usize, qubit1: usize) -> Result<(), QuantumError> {
+        self.check_qubits(&[control, qubit0, qubit1])?;
+        let size = 1 << self.num_qubits;
         let control_bit = 1 << control;
-        let target_bit = 1 << target;
-        
-        let mut new_amplitudes = self.amplitudes.clone();
+        let bit0 = 1 << qubit0;
+        let bit1 = 1 << qubit1;
         
         for i in 0..size {
-            if (i & control_bit == 0) && (i & target_bit == 0) {
+            if (i & control_bit != 0) && (i & bit0 == 0) && (i & bit1 == 0) {
                 let i00 = i;
-                let i01 = i ^ target_bit;
-                let i10 = i ^ control_bit;
-                let i11 = i ^ control_bit ^ target_bit;
+                let i01 = i ^ bit1;
+                let i10 = i ^ bit0;
+                let i11 = i ^ bit0 ^ bit1;
                 
                 let amp00 = self.amplitudes[i00];
                 let amp01 = self.amplitudes[i01];
                 let amp10 = self.amplitudes[i10];
                 let amp11 = self.amplitudes[i11];
                 
-                new_amplitudes[i00] = gate[0] * amp00 + gate[1] * amp01 + gate[2] * amp10 + gate[3] * amp11;
-                new_amplitudes[i01] = gate[4] * amp00 + gate[5] * amp01 + gate[6] * amp10 + gate[7] * amp11;
-                new_amplitudes[i10] = gate[8] * amp00 + gate[9] * amp01 + gate[10] * amp10 + gate[11] * amp11;
-                new_amplitudes[i11] = gate[12] * amp00 + gate[13] * amp01 + gate[14] * amp10 + gate[15] * amp11;
+                self.amplitudes[i00] = gate[0] * amp00 + gate[1] * amp01 + gate[2] * amp10 + gate[3] * amp11;
+                self.amplitudes[i01] = gate[4] * amp00 + gate[5] * amp01 + gate[6] * amp10 + gate[7] * amp11;
+                self.amplitudes[i10] = gate[8] * amp00 + gate[9] * amp01 + gate[10] * amp10 + gate[11] * amp11;
+                self.amplitudes[i11] = gate[12] * amp00 + gate[13] * amp01 + gate[14] * amp10 + gate[15] * amp11;
             }
         }
         
-        self.amplitudes = new_amplitudes;
+        self.gate_applied();
+        Ok(())
+    }
+    
+    /// One trajectory step of the depolarizing channel: with probability p/3
+    /// each, apply X, Y or Z to `qubit`; otherwise leave it alone.
+    pub fn apply_depolarizing<R: rand::Rng>(&mut self, qubit: usize, p: f64, rng: &mut R) -> Result<(), QuantumError> {
+        let random: f64 = rng.gen();
+        if random >= p {
+            return self.check_qubit(qubit);
+        }
+        
+        let gate = match (3.0 * random / p) as usize {
+            0 => Gates::pauli_x(),
+            1 => Gates::pauli_y(),
+            _ => Gates::pauli_z(),
+        };
+        self.apply_single_qubit_gate(&gate, qubit)
+    }
+    
+    /// Independent depolarizing steps on every qubit, drawn from one seeded
+    /// stream in qubit order.
+    pub fn apply_uniform_depolarizing(&mut self, p: f64, seed: u64) -> Result<(), QuantumError> {
+        use rand::SeedableRng;
+        
+        check_probability(p)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        for qubit in 0..self.num_qubits {
+            self.apply_depolarizing(qubit, p, &mut rng)?;
+        }
+        Ok(())
+    }
+    
+    /// Seeded single-qubit depolarizing step, rejecting p outside [0, 1].
+    pub fn apply_depolarizing_noise(&mut self, qubit: usize, p: f64, seed: u64) -> Result<(), QuantumError> {
+        use rand::SeedableRng;
+        
+        check_probability(p)?;
+        self.apply_depolarizing(qubit, p, &mut rand::rngs::StdRng::seed_from_u64(seed))
+    }
+    
+    /// One trajectory step of amplitude damping with Kraus operators
+    /// K0 = [[1, 0], [0, √(1−γ)]] and K1 = [[0, √γ], [0, 0]]. The jump K1
+    /// fires with probability γ·P(qubit = 1); either branch is renormalized.
+    pub fn apply_amplitude_damping<R: rand::Rng>(&mut self, qubit: usize, gamma: f64, rng: &mut R) -> Result<(), QuantumError> {
+        self.check_qubit(qubit)?;
+        check_probability(gamma)?;
+        let bit = 1 << qubit;
+        
+        let p_one: f64 = self.probabilities()
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i & bit != 0)
+            .map(|(_, p)| p)
+            .sum();
+        let p_jump = gamma * p_one;
+        
+        if rng.gen::<f64>() < p_jump {
+            let scale = 1.0 / p_one.sqrt();
+            for i in (0..self.amplitudes.len()).filter(|i| i & bit == 0) {
+                self.amplitudes[i] = self.amplitudes[i | bit] * scale;
+                self.amplitudes[i | bit] = Complex64::new(0.0, 0.0);
+            }
+        } else {
+            let scale = 1.0 / (1.0 - p_jump).sqrt();
+            let decay = (1.0 - gamma).sqrt();
+            for (i, amp) in self.amplitudes.iter_mut().enumerate() {
+                *amp *= if i & bit != 0 { decay * scale } else { scale };
+            }
+        }
+        
+        self.invalidate_cache();
+        Ok(())
+    }
+    
+    /// Dispatch a named noise channel on one qubit, as used by `applyNoise`.
+    pub fn apply_noise(&mut self, channel: &str, qubit: usize, p: f64, seed: u64) -> Result<(), QuantumError> {
+        match channel {
+            "depolarizing" => self.apply_depolarizing_noise(qubit, p, seed),
+            "amplitude_damping" => {
+                use rand::SeedableRng;
+                self.apply_amplitude_damping(qubit, p, &mut rand::rngs::StdRng::seed_from_u64(seed))
+            }
+            _ => Err(QuantumError::InvalidArgument { reason: format!("unknown noise channel '{}'", channel) }),
+        }
+    }
+    
+    /// Quantum Fourier transform over `qubits` (first listed = least
+    /// significant), applied and recorded as named gates.
+    pub fn apply_qft(&mut self, qubits: &[usize]) -> Result<(), QuantumError> {
+        self.check_qubits(qubits)?;
+        circuit::apply_gate_ops(self, &circuit::qft(qubits))
+    }
+    
+    pub fn apply_inverse_qft(&mut self, qubits: &[usize]) -> Result<(), QuantumError> {
+        self.check_qubits(qubits)?;
+        circuit::apply_gate_ops(self, &circuit::inverse_qft(qubits))
+    }
+    
+    /// X on `target` wherever every control is |1⟩, by swapping amplitude
+    /// pairs in place rather than building a 2ⁿ matrix.
+    pub fn apply_mcx(&mut self, controls: &[usize], target: usize) -> Result<(), QuantumError> {
+        let (control_mask, target_bit) = self.control_masks(controls, target)?;
+        for i in 0..self.amplitudes.len() {
+            if i & control_mask == control_mask && i & target_bit == 0 {
+                self.amplitudes.swap_rows(i, i | target_bit);
+            }
+        }
+        self.gate_applied();
+        Ok(())
+    }
+    
+    /// Phase flip of the states with every control and the target |1⟩.
+    pub fn apply_mcz(&mut self, controls: &[usize], target: usize) -> Result<(), QuantumError> {
+        let (control_mask, target_bit) = self.control_masks(controls, target)?;
+        let mask = control_mask | target_bit;
+        for (i, amp) in self.amplitudes.iter_mut().enumerate() {
+            if i & mask == mask {
+                *amp = -*amp;
+            }
+        }
+        self.gate_applied();
+        Ok(())
+    }
+    
+    fn control_masks(&self, controls: &[usize], target: usize) -> Result<(usize, usize), QuantumError> {
+        let mut qubits = controls.to_vec();
+        qubits.push(target);
+        self.check_qubits(&qubits)?;
+        Ok((controls.iter().map(|&q| 1 << q).sum(), 1 << target))
+    }
+    
+    /// Inversion about the mean, 2|s⟩⟨s| − I, on the subspace of `qubits`
+    /// (|s⟩ their uniform superposition). Each block of amplitudes sharing the
+    /// other qubits' values is reflected about its own mean, which is the
+    /// H–X–MCZ–X–H sequence up to a global phase of −1.
+    pub fn apply_grover_diffusion(&mut self, qubits: &[usize]) -> Result<(), QuantumError> {
+        self.check_qubits(qubits)?;
+        let mask: usize = qubits.iter().map(|&q| 1 << q).sum();
+        let offsets: Vec<usize> = (0..1usize << qubits.len())
+            .map(|local| {
+                qubits.iter()
+                    .enumerate()
+                    .filter(|(j, _)| local & (1 << j) != 0)
+                    .map(|(_, &q)| 1 << q)
+                    .sum()
+            })
+            .collect();
+        let scale = Complex64::new(2.0 / offsets.len() as f64, 0.0);
+        
+        for base in (0..self.amplitudes.len()).filter(|i| i & mask == 0) {
+            let sum: Complex64 = offsets.iter().map(|&offset| self.amplitudes[base + offset]).sum();
+            for &offset in &offsets {
+                let amp = &mut self.amplitudes[base + offset];
+                *amp = scale * sum - *amp;
+            }
+        }
+        
+        self.gate_applied();
+        Ok(())
+    }
+    
+    /// exp(−iθP) = cos θ·I − i sin θ·P for a Pauli string P.
+    pub fn apply_pauli_rotation(&mut self, paulis: &[(usize, char)], theta: f64) -> Result<(), QuantumError> {
+        let masks = PauliMasks::from_paulis(paulis, self.num_qubits)?;
+        let cos = Complex64::new(theta.cos(), 0.0);
+        let minus_i_sin = Complex64::new(0.0, -theta.sin());
+        
+        let old = self.amplitudes.clone();
+        for (x, amp) in self.amplitudes.iter_mut().enumerate() {
+            let y = x ^ masks.x_mask;
+            *amp = cos * old[x] + minus_i_sin * masks.phase(y) * old[y];
+        }
+        self.gate_applied();
+        
+        Ok(())
+    }
+    
+    /// One Trotter step of exp(−iH·dt) for H = Σ cₖ Pₖ. Order 1 applies each
+    /// exp(−i cₖ dt Pₖ) in turn; order 2 is the symmetric (Strang) sequence.
+    pub fn apply_trotter_step(&mut self, terms: &[PauliTerm], dt: f64, order: usize) -> Result<(), QuantumError> {
+        for term in terms {
+            PauliMasks::from_paulis(&term.paulis, self.num_qubits)?;
+        }
+        
+        match order {
+            1 => {
+                for term in terms {
+                    self.apply_pauli_rotation(&term.paulis, term.coefficient * dt)?;
+                }
+            },
+            2 => {
+                for term in terms.iter().chain(terms.iter().rev()) {
+                    self.apply_pauli_rotation(&term.paulis, term.coefficient * dt / 2.0)?;
+                }
+            },
+            _ => {
+                return Err(QuantumError::InvalidArgument {
+                    reason: format!("Trotter order must be 1 or 2, got {}", order),
+                })
+            },
+        }
+        
+        Ok(())
+    }
+    
+    /// Projective Z measurement of one qubit: sample the outcome, zero the
+    /// inconsistent amplitudes and renormalize the rest.
+    pub fn measure_qubit_with_rng<R: rand::Rng>(&mut self, qubit: usize, rng: &mut R) -> Result<bool, QuantumError> {
+        self.check_qubit(qubit)?;
+        let bit = 1 << qubit;
+        
+        let p_one: f64 = self.probabilities()
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i & bit != 0)
+            .map(|(_, p)| p)
+            .sum();
+        let outcome = rng.gen::<f64>() < p_one;
+        let p_outcome = if outcome { p_one } else { 1.0 - p_one };
+        
+        let scale = Complex64::new(1.0 / p_outcome.sqrt(), 0.0);
+        for (i, amp) in self.amplitudes.iter_mut().enumerate() {
+            if (i & bit != 0) == outcome {
+                *amp *= scale;
+            } else {
+                *amp = Complex64::new(0.0, 0.0);
+            }
+        }
+        self.invalidate_cache();
+        
+        Ok(outcome)
+    }
+    
+    /// `measure_qubit_with_rng` on the thread-local RNG.
+    pub fn measure_qubit(&mut self, qubit: usize) -> Result<bool, QuantumError> {
+        self.measure_qubit_with_rng(qubit, &mut rand::thread_rng())
+    }
+    
+    /// Active reset: measure `qubit` and flip it back with X if it read 1.
+    /// Returns the measured outcome.
+    pub fn reset_qubit_with_rng<R: rand::Rng>(&mut self, qubit: usize, rng: &mut R) -> Result<bool, QuantumError> {
+        let outcome = self.measure_qubit_with_rng(qubit, rng)?;
+        if outcome {
+            self.apply_single_qubit_gate(&Gates::pauli_x(), qubit)?;
+        }
+        Ok(outcome)
+    }
+    
+    /// `reset_qubit_with_rng` on the thread-local RNG.
+    pub fn reset_qubit(&mut self, qubit: usize) -> Result<(), QuantumError> {
+        self.reset_qubit_with_rng(qubit, &mut rand::thread_rng()).map(|_| ())
+    }
+    
+    /// `reset_qubit_with_rng` on a seeded RNG, returning the measured outcome.
+    pub fn conditional_reset(&mut self, qubit: usize, seed: u64) -> Result<bool, QuantumError> {
+        use rand::SeedableRng;
+        
+        let since = self.revision;
+        let outcome = self.reset_qubit_with_rng(qubit, &mut rand::rngs::StdRng::seed_from_u64(seed))?;
+        self.record_gates(since, &[GateOp::new("RESET", vec![qubit], vec![])]);
+        Ok(outcome)
+    }
+    
+    /// Collapse each of `qubits` in turn from one seeded RNG stream and return
+    /// the outcomes in the order given.
+    pub fn measure_subset_collapse(&mut self, qubits: &[usize], seed: u64) -> Result<Vec<bool>, QuantumError> {
+        use rand::SeedableRng;
+        
+        self.check_qubits(qubits)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        qubits
+            .iter()
+            .map(|&qubit| self.measure_qubit_with_rng(qubit, &mut rng))
+            .collect()
+    }
+    
+    /// `measure` reporting only `qubits`, in the order given (first listed
+    /// qubit leftmost).
+    pub fn measure_subset(&self, qubits: &[usize], shots: usize) -> Result<HashMap<String, usize>, QuantumError> {
+        use rand::Rng;
+        
+        self.measure_subset_with_seed(qubits, shots, rand::thread_rng().gen())
+    }
+    
+    pub fn measure_subset_with_seed(&self, qubits: &[usize], shots: usize, seed: u64) -> Result<HashMap<String, usize>, QuantumError> {
+        self.check_qubits(qubits)?;
+        
+        let mut results = HashMap::new();
+        for state in self.sample_with_seed(shots, seed) {
+            let bitstring: String = qubits
+                .iter()
+                .map(|&q| if state & (1 << q) != 0 { '1' } else { '0' })
+                .collect();
+            *results.entry(bitstring).or_insert(0) += 1;
+        }
+        Ok(results)
     }
     
     pub fn measure(&self, shots: usize) -> HashMap<String, usize> {
         use rand::Rng;
         
+        self.measure_with_seed(shots, rand::thread_rng().gen())
+    }
+    
+    /// Bitstring histogram of `shots` samples from a seeded RNG.
+    pub fn measure_with_seed(&self, shots: usize, seed: u64) -> HashMap<String, usize> {
         let mut results = HashMap::new();
-        let mut rng = rand::thread_rng();
+        for state in self.sample_with_seed(shots, seed) {
+            let bitstring = format!("{:0width$b}", state, width = self.num_qubits);
+            *results.entry(bitstring).or_insert(0) += 1;
+        }
+        results
+    }
+    
+    /// Draw `shots` basis-state indices from a seeded RNG, in shot order.
+    pub fn sample_with_seed(&self, shots: usize, seed: u64) -> Vec<usize> {
+        self.sampler(seed).sample(shots)
+    }
+    
+    /// One basis index per shot, the compact form of `measure` for
+    /// per-shot post-processing.
+    pub fn sample_raw(&self, shots: usize) -> Vec<u32> {
+        use rand::Rng;
         
-        // Calculate probabilities
-        let probabilities: Vec<f64> = self.amplitudes
-            .iter()
-            .map(|amp| amp.norm_sqr())
-            .collect();
+        self.sample_raw_with_seed(shots, rand::thread_rng().gen())
+    }
+    
+    /// Seeded `sample_raw`; the same seed yields the shots `measure_with_seed`
+    /// aggregates.
+    pub fn sample_raw_with_seed(&self, shots: usize, seed: u64) -> Vec<u32> {
+        self.sample_with_seed(shots, seed).into_iter().map(|state| state as u32).collect()
+    }
+    
+    /// Snapshot the current distribution into a seeded sampler.
+    pub fn sampler(&self, seed: u64) -> Sampler {
+        use rand::SeedableRng;
         
-        // Cumulative distribution for sampling
-        let mut cumulative = Vec::with_capacity(probabilities.len());
+        Sampler {
+            cumulative: self.cumulative_distribution(),
+            rng: rand::rngs::StdRng::seed_from_u64(seed),
+        }
+    }
+    
+    /// Importance sampling: draw `shots` basis states from `bias` (normalised
+    /// internally) and weight each by p_true / p_bias. Dividing a bitstring's
+    /// weighted count by `shots` is an unbiased estimate of its probability.
+    pub fn measure_importance(&self, bias: &[f64], shots: usize, seed: u64) -> Result<HashMap<String, f64>, QuantumError> {
+        use rand::{Rng, SeedableRng};
+        
+        let probabilities = self.probabilities();
+        if bias.len() != probabilities.len() {
+            return Err(QuantumError::LengthMismatch { expected: probabilities.len(), found: bias.len() });
+        }
+        if let Some(index) = bias.iter().position(|&b| !b.is_finite() || b < 0.0) {
+            return Err(QuantumError::InvalidArgument {
+                reason: format!("bias weight {} at index {} must be finite and non-negative", bias[index], index),
+            });
+        }
+        if let Some(index) = (0..bias.len()).find(|&i| probabilities[i] > 0.0 && bias[i] == 0.0) {
+            return Err(QuantumError::InvalidArgument {
+                reason: format!("bias is zero at index {} where the state has support", index),
+            });
+        }
+        
+        let total: f64 = bias.iter().sum();
+        let mut cumulative = Vec::with_capacity(bias.len());
         let mut sum = 0.0;
-        for prob in probabilities {
-            sum += prob;
+        for b in bias {
+            sum += b / total;
             cumulative.push(sum);
         }
         
-        // Measurement sampling
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let mut results = HashMap::new();
         for _ in 0..shots {
-            let random: f64 = rng.gen();
-            let state = cumulative.iter().position(|&x| x > random).unwrap_or(0);
+            let state = sample_from_cumulative(&cumulative, rng.gen());
+            let weight = probabilities[state] * total / bias[state];
             let bitstring = format!("{:0width$b}", state, width = self.num_qubits);
-            *results.entry(bitstring).or_insert(0) += 1;
+            *results.entry(bitstring).or_insert(0.0) += weight;
         }
         
-        results
+        Ok(results)
+    }
+    
+    /// Sample `qubits` each shot and majority-vote them into one logical bit.
+    /// Returns the shot counts for logical 0 and logical 1.
+    pub fn measure_majority(&self, qubits: &[usize], shots: usize, seed: u64) -> Result<[usize; 2], QuantumError> {
+        self.check_qubits(qubits)?;
+        if qubits.len().is_multiple_of(2) {
+            return Err(QuantumError::InvalidArgument {
+                reason: "majority vote needs an odd number of qubits".to_string(),
+            });
+        }
+        
+        let mut counts = [0; 2];
+        for state in self.sample_with_seed(shots, seed) {
+            let ones = qubits.iter().filter(|&&q| state & (1 << q) != 0).count();
+            let logical = usize::from(2 * ones > qubits.len());
+            counts[logical] += 1;
+        }
+        
+        Ok(counts)
+    }
+    
+    fn cumulative_distribution(&self) -> Vec<f64> {
+        let probabilities = self.probabilities();
+        let mut cumulative = Vec::with_capacity(probabilities.len());
+        let mut sum = 0.0;
+        for prob in probabilities {
+            sum += prob;
+            cumulative.push(sum);
+        }
+        cumulative
+    }
+    
+    pub fn check_qubit(&self, qubit: usize) -> Result<(), QuantumError> {
+        if qubit >= self.num_qubits {
+            return Err(QuantumError::QubitOutOfRange {
+                qubit,
+                num_qubits: self.num_qubits,
+            });
+        }
+        Ok(())
+    }
+    
+    pub fn check_qubits(&self, qubits: &[usize]) -> Result<(), QuantumError> {
+        for (i, &qubit) in qubits.iter().enumerate() {
+            self.check_qubit(qubit)?;
+            if qubits[..i].contains(&qubit) {
+                return Err(QuantumError::IdenticalQubits { qubit });
+            }
+        }
+        Ok(())
     }
     
     pub fn get_probabilities(&self) -> Vec<f64> {
+        self.probabilities().to_vec()
+    }
+    
+    /// P(qubit = 1) for every qubit, read off the single-qubit RDM diagonals.
+    pub fn single_qubit_marginals(&self) -> Vec<f64> {
+        self.single_qubit_rdms().iter().map(|rho| rho[3].re).collect()
+    }
+    
+    /// Σᵢ pᵢ C(i) for a classical cost indexed by basis state.
+    pub fn exact_cost_expectation(&self, costs: &[f64]) -> Result<f64, QuantumError> {
+        let probabilities = self.probabilities();
+        if costs.len() != probabilities.len() {
+            return Err(QuantumError::LengthMismatch { expected: probabilities.len(), found: costs.len() });
+        }
+        Ok(probabilities.iter().zip(costs).map(|(p, c)| p * c).sum())
+    }
+    
+    /// ⟨Z⟩ on `qubit`: P(0) − P(1).
+    pub fn expectation_pauli_z(&self, qubit: usize) -> Result<f64, QuantumError> {
+        self.check_qubit(qubit)?;
+        let bit = 1 << qubit;
+        Ok(self.probabilities()
+            .iter()
+            .enumerate()
+            .map(|(i, p)| if i & bit == 0 { *p } else { -p })
+            .sum())
+    }
+    
+    /// ⟨X⟩ on `qubit`.
+    pub fn expectation_pauli_x(&self, qubit: usize) -> Result<f64, QuantumError> {
+        let masks = PauliMasks::from_paulis(&[(qubit, 'X')], self.num_qubits)?;
+        Ok(masks.expectation(self.amplitudes.as_slice()))
+    }
+    
+    /// ⟨Y⟩ on `qubit`.
+    pub fn expectation_pauli_y(&self, qubit: usize) -> Result<f64, QuantumError> {
+        let masks = PauliMasks::from_paulis(&[(qubit, 'Y')], self.num_qubits)?;
+        Ok(masks.expectation(self.amplitudes.as_slice()))
+    }
+    
+    /// ⟨ψ|P₀⊗P₁⊗…|ψ⟩ for a Pauli string given as (qubit, letter) pairs,
+    /// streamed over the amplitudes in O(2ⁿ) without building the operator.
+    pub fn expectation_pauli_string(&self, paulis: &[(usize, char)]) -> Result<f64, QuantumError> {
+        let masks = PauliMasks::from_paulis(paulis, self.num_qubits)?;
+        Ok(masks.expectation(self.amplitudes.as_slice()))
+    }
+    
+    /// The basis state with the largest probability and that probability,
+    /// exactly. Ties go to the lowest basis index.
+    pub fn most_likely(&self) -> (String, f64) {
+        let (index, &prob) = self.probabilities()
+            .iter()
+            .enumerate()
+            .fold((0, &f64::NEG_INFINITY), |best, candidate| if candidate.1 > best.1 { candidate } else { best });
+        (format!("{:0width$b}", index, width = self.num_qubits), prob)
+    }
+    
+    /// Probability of one basis state given as a bitstring (qubit 0 rightmost).
+    pub fn probability_of(&self, bitstring: &str) -> Result<f64, QuantumError> {
+        if bitstring.len() != self.num_qubits {
+            return Err(QuantumError::LengthMismatch { expected: self.num_qubits, found: bitstring.len() });
+        }
+        if self.num_qubits == 0 {
+            return Ok(self.probabilities()[0]);
+        }
+        let index = usize::from_str_radix(bitstring, 2)
+            .ok()
+            .filter(|_| bitstring.bytes().all(|b| b == b'0' || b == b'1'))
+            .ok_or_else(|| QuantumError::InvalidArgument { reason: format!("'{}' is not a bitstring", bitstring) })?;
+        Ok(self.probabilities()[index])
+    }
+    
+    /// Expected number of 1s in a measured bitstring.
+    pub fn expected_hamming_weight(&self) -> f64 {
+        self.single_qubit_marginals().iter().sum()
+    }
+    
+    /// Exact marginal distribution over `qubits`, summing out the rest. Keys
+    /// list the outcomes in the order the qubits were given; outcomes with
+    /// zero probability are omitted.
+    pub fn marginal_distribution(&self, qubits: &[usize]) -> Result<HashMap<String, f64>, QuantumError> {
+        self.check_qubits(qubits)?;
+        
+        let mut marginal = vec![0.0; 1 << qubits.len()];
+        for (i, &prob) in self.probabilities().iter().enumerate() {
+            let reduced: usize = qubits
+                .iter()
+                .enumerate()
+                .filter(|(_, &q)| i & (1 << q) != 0)
+                .map(|(j, _)| 1 << j)
+                .sum();
+            marginal[reduced] += prob;
+        }
+        
+        Ok(marginal
+            .into_iter()
+            .enumerate()
+            .filter(|(_, prob)| *prob > 0.0)
+            .map(|(reduced, prob)| {
+                let outcome = (0..qubits.len()).map(|j| if reduced & (1 << j) != 0 { '1' } else { '0' }).collect();
+                (outcome, prob)
+            })
+            .collect())
+    }
+    
+    /// Row-major 2×2 reduced density matrix of every qubit.
+    pub fn all_single_qubit_rdms(&self) -> Vec<[Complex64; 4]> {
+        self.single_qubit_rdms().to_vec()
+    }
+    
+    // All n blocks are accumulated in one traversal of the amplitudes and
+    // cached until the next mutation
+    fn single_qubit_rdms(&self) -> &[[Complex64; 4]] {
+        self.single_qubit_rdm_cache.get_or_init(|| {
+            let zero = Complex64::new(0.0, 0.0);
+            let mut rdms = vec![[zero; 4]; self.num_qubits];
+            
+            for (i, amp) in self.amplitudes.iter().enumerate() {
+                let prob = amp.norm_sqr();
+                for (qubit, rho) in rdms.iter_mut().enumerate() {
+                    let bit = 1 << qubit;
+                    if i & bit == 0 {
+                        rho[0] += prob;
+                        rho[1] += amp * self.amplitudes[i | bit].conj();
+                    } else {
+                        rho[3] += prob;
+                    }
+                }
+            }
+            
+            for rho in rdms.iter_mut() {
+                rho[2] = rho[1].conj();
+            }
+            rdms
+        })
+    }
+    
+    /// Qubits whose P(1) lies strictly between `tol` and 1 − `tol`, i.e. those
+    /// not effectively fixed to |0⟩ or |1⟩.
+    pub fn active_qubits(&self, tol: f64) -> Vec<usize> {
+        self.single_qubit_marginals()
+            .iter()
+            .enumerate()
+            .filter(|(_, &p)| p > tol && p < 1.0 - tol)
+            .map(|(qubit, _)| qubit)
+            .collect()
+    }
+    
+    /// ρ_keep = Tr_rest |ψ⟩⟨ψ| as a row-major 2^k × 2^k matrix. Bit j of a
+    /// reduced index corresponds to `keep[j]`.
+    pub fn reduced_density_matrix(&self, keep: &[usize]) -> Result<Vec<Complex64>, QuantumError> {
+        self.check_qubits(keep)?;
+        
+        let dim = 1 << keep.len();
+        let keep_mask: usize = keep.iter().map(|&q| 1 << q).sum();
+        let offsets: Vec<usize> = (0..dim)
+            .map(|r| {
+                keep.iter()
+                    .enumerate()
+                    .filter(|(j, _)| r & (1 << j) != 0)
+                    .map(|(_, &q)| 1 << q)
+                    .sum()
+            })
+            .collect();
+        
+        let mut rho = vec![Complex64::new(0.0, 0.0); dim * dim];
+        let mut block = vec![Complex64::new(0.0, 0.0); dim];
+        for env in (0..self.amplitudes.len()).filter(|x| x & keep_mask == 0) {
+            for (r, offset) in offsets.iter().enumerate() {
+                block[r] = self.amplitudes[env | offset];
+            }
+            for row in 0..dim {
+                for col in 0..dim {
+                    rho[row * dim + col] += block[row] * block[col].conj();
+                }
+            }
+        }
+        
+        Ok(rho)
+    }
+    
+    /// Whether ρ_AB differs from ρ_A ⊗ ρ_B by more than `tol` in any entry.
+    /// For a pure global state a non-product ρ_AB means the pair is
+    /// correlated through entanglement with each other or the environment.
+    pub fn are_entangled(&self, qubit_a: usize, qubit_b: usize, tol: f64) -> Result<bool, QuantumError> {
+        let rho_ab = self.reduced_density_matrix(&[qubit_a, qubit_b])?;
+        let rdms = self.single_qubit_rdms();
+        let (rho_a, rho_b) = (&rdms[qubit_a], &rdms[qubit_b]);
+        
+        // Reduced index r = a + 2b
+        for row in 0..4 {
+            for col in 0..4 {
+                let product = rho_a[(row & 1) * 2 + (col & 1)] * rho_b[(row >> 1) * 2 + (col >> 1)];
+                if (rho_ab[row * 4 + col] - product).norm() > tol {
+                    return Ok(true);
+                }
+            }
+        }
+        
+        Ok(false)
+    }
+    
+    /// Wootters concurrence of the pair: max(0, λ1 − λ2 − λ3 − λ4) where λi are
+    /// the decreasing square roots of the eigenvalues of ρ(σy⊗σy)ρ*(σy⊗σy).
+    /// Those eigenvalues equal the spectrum of the Hermitian √ρ ρ̃ √ρ, which
+    /// is what gets diagonalised here.
+    pub fn concurrence(&self, qubit_a: usize, qubit_b: usize) -> Result<f64, QuantumError> {
+        let rho = DMatrix::from_row_slice(4, 4, &self.reduced_density_matrix(&[qubit_a, qubit_b])?);
+        
+        // σy⊗σy is real: -1 on the anti-diagonal corners, +1 in the middle
+        let mut yy = DMatrix::<Complex64>::zeros(4, 4);
+        yy[(0, 3)] = Complex64::new(-1.0, 0.0);
+        yy[(1, 2)] = Complex64::new(1.0, 0.0);
+        yy[(2, 1)] = Complex64::new(1.0, 0.0);
+        yy[(3, 0)] = Complex64::new(-1.0, 0.0);
+        let rho_tilde = &yy * rho.conjugate() * &yy;
+        
+        let eigen = rho.clone().symmetric_eigen();
+        let sqrt_values = eigen.eigenvalues.map(|v| Complex64::new(v.max(0.0).sqrt(), 0.0));
+        let sqrt_rho = &eigen.eigenvectors * DMatrix::from_diagonal(&sqrt_values) * eigen.eigenvectors.adjoint();
+        
+        let m = &sqrt_rho * rho_tilde * &sqrt_rho;
+        let mut lambdas: Vec<f64> = m.symmetric_eigen().eigenvalues.iter().map(|v| v.max(0.0).sqrt()).collect();
+        lambdas.sort_by(|a, b| b.total_cmp(a));
+        
+        Ok((lambdas[0] - lambdas[1] - lambdas[2] - lambdas[3]).max(0.0))
+    }
+    
+    /// Von Neumann entropy −Σ λ log₂ λ of ρ over `partition`, in bits.
+    /// Eigenvalues below 1e-12 are treated as zero.
+    pub fn entanglement_entropy(&self, partition: &[usize]) -> Result<f64, QuantumError> {
+        let dim = 1 << partition.len();
+        let rho = DMatrix::from_row_slice(dim, dim, &self.reduced_density_matrix(partition)?);
+        
+        Ok(rho
+            .symmetric_eigenvalues()
+            .iter()
+            .filter(|&&lambda| lambda > 1e-12)
+            .map(|&lambda| -lambda * lambda.log2())
+            .sum())
+    }
+    
+    /// Real and imaginary parts of the amplitudes in basis-index order (the
+    /// order of `get_probabilities`), rounded by the output precision.
+    pub fn state_vector_parts(&self) -> (Vec<f64>, Vec<f64>) {
         self.amplitudes
             .iter()
-            .map(|amp| amp.norm_sqr())
+            .map(|amp| (self.present(amp.re), self.present(amp.im)))
+            .unzip()
+    }
+    
+    /// Amplitudes in QuTiP ket order. QuTiP's tensor convention makes the first
+    /// subsystem the most significant digit, so with subsystem j = qubit j the
+    /// QuTiP index is our basis index with its `num_qubits` bits reversed.
+    pub fn qutip_ket_data(&self) -> Vec<Complex64> {
+        if self.num_qubits == 0 {
+            return self.amplitudes.iter().copied().collect();
+        }
+        let shift = usize::BITS - self.num_qubits as u32;
+        (0..self.amplitudes.len())
+            .map(|k| self.amplitudes[k.reverse_bits() >> shift])
             .collect()
     }
     
-    pub fn get_fidelity(&self, target_state: &QuantumState) -> f64 {
+    /// Basis indices and probabilities of every entry above `tol`, in index order.
+    pub fn sparse_probabilities(&self, tol: f64) -> (Vec<u32>, Vec<f64>) {
+        self.probabilities()
+            .iter()
+            .enumerate()
+            .filter(|(_, &prob)| prob > tol)
+            .map(|(i, &prob)| (i as u32, prob))
+            .unzip()
+    }
+    
+    /// Borrow the cached probability vector, computing it on first access.
+    pub fn probabilities(&self) -> &[f64] {
+        self.probabilities_cache.get_or_init(|| {
+            self.amplitudes
+                .iter()
+                .map(|amp| amp.norm_sqr())
+                .collect()
+        })
+    }
+    
+    /// Pure-state fidelity |⟨ψ|φ⟩|². Registers of different sizes are an
+    /// error rather than a fidelity of 0, which would read as orthogonal.
+    pub fn get_fidelity(&self, target_state: &QuantumState) -> Result<f64, QuantumError> {
         if self.num_qubits != target_state.num_qubits {
-            return 0.0;
+            return Err(QuantumError::DimensionMismatch {
+                expected: self.num_qubits,
+                found: target_state.num_qubits,
+            });
         }
         
         let fidelity = self.amplitudes
@@ -123,13 +1306,242 @@ impl QuantumState {
             .sum::<Complex64>()
             .norm_sqr();
         
-        fidelity
+        Ok(fidelity)
+    }
+    
+    /// Inner product ⟨target|ψ⟩ against an external statevector.
+    pub fn overlap(&self, target: &[Complex64]) -> Result<Complex64, QuantumError> {
+        if target.len() != self.amplitudes.len() {
+            return Err(QuantumError::LengthMismatch {
+                expected: self.amplitudes.len(),
+                found: target.len(),
+            });
+        }
+        
+        Ok(target
+            .iter()
+            .zip(self.amplitudes.iter())
+            .map(|(t, a)| t.conj() * a)
+            .sum())
+    }
+    
+    /// |⟨haar|ψ⟩|² for `samples` Haar-random states drawn as normalised vectors
+    /// of i.i.d. complex Gaussians. For random ψ these follow Porter-Thomas,
+    /// Beta(1, 2ⁿ − 1) with mean 2⁻ⁿ.
+    pub fn haar_overlaps(&self, samples: usize, seed: u64) -> Vec<f64> {
+        use rand::{Rng, SeedableRng};
+        use rand_distr::StandardNormal;
+        
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let mut haar = vec![Complex64::new(0.0, 0.0); self.amplitudes.len()];
+        
+        (0..samples)
+            .map(|_| {
+                for amp in haar.iter_mut() {
+                    *amp = Complex64::new(rng.sample(StandardNormal), rng.sample(StandardNormal));
+                }
+                let norm_sqr: f64 = haar.iter().map(|a| a.norm_sqr()).sum();
+                // Same length by construction, so the overlap cannot fail
+                self.overlap(&haar).unwrap().norm_sqr() / norm_sqr
+            })
+            .collect()
+    }
+    
+    /// Expectation of the projector |target⟩⟨target|, i.e. |⟨target|ψ⟩|².
+    pub fn projector_expectation(&self, target: &[Complex64]) -> Result<f64, QuantumError> {
+        Ok(self.overlap(target)?.norm_sqr())
+    }
+    
+    /// |⟨refᵢ|ψ⟩|² for `count` reference statevectors stored back to back.
+    pub fn overlaps_with_references(&self, references: &[Complex64], count: usize) -> Result<Vec<f64>, QuantumError> {
+        let dim = self.amplitudes.len();
+        if references.len() != count * dim {
+            return Err(QuantumError::LengthMismatch {
+                expected: count * dim,
+                found: references.len(),
+            });
+        }
+        
+        references
+            .chunks_exact(dim)
+            .map(|reference| self.projector_expectation(reference))
+            .collect()
+    }
+    
+    /// Trace distance ½‖|ψ⟩⟨ψ| − |φ⟩⟨φ|‖₁ between two pure states, which
+    /// reduces to sqrt(1 - F). Mixed states use
+    /// `DensityMatrix::trace_distance`.
+    pub fn trace_distance(&self, other: &QuantumState) -> Result<f64, QuantumError> {
+        let fidelity = self.get_fidelity(other)?;
+        Ok((1.0 - fidelity).max(0.0).sqrt())
+    }
+    
+    /// Estimate |⟨ψ|φ⟩|² with the SWAP test: ψ on qubits 0..n, φ on n..2n
+    /// and an ancilla on qubit 2n go through H, controlled SWAPs and H, and
+    /// the ancilla is read `shots` times. P(0) = (1 + F)/2, so F ≈ 2·P̂(0) − 1.
+    pub fn swap_test(&self, other: &QuantumState, shots: usize, seed: u64) -> Result<f64, QuantumError> {
+        use rand::{Rng, SeedableRng};
+        
+        if self.num_qubits != other.num_qubits {
+            return Err(QuantumError::DimensionMismatch {
+                expected: self.num_qubits,
+                found: other.num_qubits,
+            });
+        }
+        if shots == 0 {
+            return Err(QuantumError::InvalidArgument { reason: "swap test needs at least one shot".to_string() });
+        }
+        
+        // Both registers plus the ancilla must fit before anything is allocated
+        let n = self.num_qubits;
+        check_num_qubits(2 * n + 1)?;
+        let mut combined = vec![Complex64::new(0.0, 0.0); 1 << (2 * n + 1)];
+        for (b, phi) in other.amplitudes.iter().enumerate() {
+            for (a, psi) in self.amplitudes.iter().enumerate() {
+                combined[a | (b << n)] = psi * phi;
+            }
+        }
+        let mut register = QuantumState::from_amplitudes(combined)?;
+        
+        let ancilla = 2 * n;
+        register.apply_single_qubit_gate(&Gates::hadamard(), ancilla)?;
+        for qubit in 0..n {
+            register.apply_three_qubit_gate(&Gates::fredkin(), ancilla, qubit, qubit + n)?;
+        }
+        register.apply_single_qubit_gate(&Gates::hadamard(), ancilla)?;
+        
+        let p_zero = 1.0 - register.single_qubit_marginals()[ancilla];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let zeros = (0..shots).filter(|_| rng.gen::<f64>() < p_zero).count();
+        
+        Ok((2.0 * zeros as f64 / shots as f64 - 1.0).max(0.0))
+    }
+    
+    /// Cleanup for display and exact assertions. Real parts and imaginary
+    /// parts are clustered separately: each cluster starts at the smallest
+    /// value not yet taken and admits everything within `tol` of it, then
+    /// snaps to its mean, or to ±1/√(2^k) (1, 1/√2, 1/2, 1/√8, …) or 0 when
+    /// the mean lies within `tol` of one. The state is renormalised only if
+    /// snapping moved the norm.
+    pub fn snap_to_rational(&mut self, tol: f64) -> Result<(), QuantumError> {
+        if tol.is_nan() || tol < 0.0 {
+            return Err(QuantumError::InvalidArgument { reason: format!("tolerance {} must be non-negative", tol) });
+        }
+        
+        let recognised: Vec<f64> = (0..=2 * self.num_qubits as u32).map(|k| 1.0 / 2f64.powi(k as i32).sqrt()).collect();
+        let snap = |mean: f64| {
+            if mean.abs() <= tol {
+                return 0.0;
+            }
+            recognised
+                .iter()
+                .find(|&&value| (mean.abs() - value).abs() <= tol)
+                .map_or(mean, |&value| value.copysign(mean))
+        };
+        
+        let mut re: Vec<f64> = self.amplitudes.iter().map(|amp| amp.re).collect();
+        let mut im: Vec<f64> = self.amplitudes.iter().map(|amp| amp.im).collect();
+        snap_clusters(&mut re, tol, snap);
+        snap_clusters(&mut im, tol, snap);
+        
+        for (amp, (re, im)) in self.amplitudes.iter_mut().zip(re.into_iter().zip(im)) {
+            *amp = Complex64::new(re, im);
+        }
+        self.invalidate_cache();
+        
+        if (self.amplitudes.norm() - 1.0).abs() > 1e-12 {
+            self.normalize();
+        }
+        Ok(())
+    }
+    
+    /// Complex-conjugate every amplitude: the anti-unitary time reversal for
+    /// real Hamiltonians in the computational basis.
+    pub fn conjugate(&mut self) {
+        self.amplitudes.iter_mut().for_each(|amp| *amp = amp.conj());
+        self.invalidate_cache();
+    }
+    
+    /// Hilbert-space dimension, 2^num_qubits.
+    pub fn dimension(&self) -> usize {
+        self.amplitudes.len()
+    }
+    
+    /// ‖ψ‖, which drifts from 1 only through rounding error.
+    pub fn norm(&self) -> f64 {
+        self.amplitudes.norm()
+    }
+    
+    pub fn is_normalized(&self, tol: f64) -> bool {
+        (self.norm() - 1.0).abs() <= tol
     }
     
     pub fn normalize(&mut self) {
         let norm = self.amplitudes.norm();
         if norm > 0.0 {
             self.amplitudes /= Complex64::new(norm, 0.0);
+            self.invalidate_cache();
+        }
+    }
+}
+
+// Replace every cluster of `values` by `snap` of its mean. Clusters are
+// seeded at their smallest member, so none spans more than `tol`
+fn snap_clusters(values: &mut [f64], tol: f64, snap: impl Fn(f64) -> f64) {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+    
+    let mut start = 0;
+    while start < order.len() {
+        let seed = values[order[start]];
+        let end = start + order[start..].iter().take_while(|&&i| values[i] - seed <= tol).count();
+        let group = &order[start..end];
+        let value = snap(group.iter().map(|&i| values[i]).sum::<f64>() / group.len() as f64);
+        for &i in group {
+            values[i] = value;
         }
+        start = end;
     }
-}
\ No newline at end of file
+}
+
+fn check_num_qubits(num_qubits: usize) -> Result<(), QuantumError> {
+    if num_qubits > MAX_QUBITS {
+        return Err(QuantumError::TooManyQubits { requested: num_qubits, max: MAX_QUBITS });
+    }
+    Ok(())
+}
+
+pub(crate) fn check_probability(p: f64) -> Result<(), QuantumError> {
+    if !(0.0..=1.0).contains(&p) {
+        return Err(QuantumError::InvalidArgument { reason: format!("probability {} outside [0, 1]", p) });
+    }
+    Ok(())
+}
+
+/// A frozen cumulative distribution plus its RNG stream. Successive draws
+/// continue the stream, so two batches of n equal one batch of 2n.
+pub struct Sampler {
+    cumulative: Vec<f64>,
+    rng: rand::rngs::StdRng,
+}
+
+impl Sampler {
+    pub fn sample(&mut self, count: usize) -> Vec<usize> {
+        use rand::Rng;
+        
+        (0..count)
+            .map(|_| sample_from_cumulative(&self.cumulative, self.rng.gen()))
+            .collect()
+    }
+}
+
+// Binary search for the first bucket whose running total exceeds `random`
+// (uniform in [0, 1)). Scaling by the final total keeps a sum that rounds
+// below 1 from leaving a gap at the top, and the clamp guards the last bucket.
+pub(crate) fn sample_from_cumulative(cumulative: &[f64], random: f64) -> usize {
+    let total = cumulative.last().copied().unwrap_or(0.0);
+    let target = random * total;
+    cumulative
+        .partition_point(|&x| x <= target)
+        .min(cumulative.len().saturating_sub(1))
+}
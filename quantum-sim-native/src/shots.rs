@@ -0,0 +1,90 @@
+use std::str::FromStr;
+
+use crate::error::QuantumError;
+use crate::quantum_state::QuantumState;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShotStrategy {
+    Uniform,
+    Weighted,
+    Optimal,
+}
+
+impl FromStr for ShotStrategy {
+    type Err = QuantumError;
+    
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "uniform" => Ok(ShotStrategy::Uniform),
+            "weighted" => Ok(ShotStrategy::Weighted),
+            "optimal" => Ok(ShotStrategy::Optimal),
+            _ => Err(QuantumError::InvalidArgument {
+                reason: format!("unknown shot strategy {}", name),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShotTerm {
+    pub coefficient: f64,
+    // Required by the optimal strategy; see `pauli_variance` to estimate it
+    pub variance: Option<f64>,
+}
+
+/// Split `total_shots` across terms in proportion to the strategy's weight:
+/// 1 (uniform), |c| (weighted) or |c|·√Var (optimal). Rounding uses the
+/// largest-remainder method so the allocations always sum to the budget.
+/// The optimal strategy fails for a term without a variance.
+pub fn allocate_shots(terms: &[ShotTerm], total_shots: usize, strategy: ShotStrategy) -> Result<Vec<usize>, QuantumError> {
+    if terms.is_empty() {
+        return Ok(Vec::new());
+    }
+    
+    let mut weights: Vec<f64> = terms
+        .iter()
+        .enumerate()
+        .map(|(index, term)| match strategy {
+            ShotStrategy::Uniform => Ok(1.0),
+            ShotStrategy::Weighted => Ok(term.coefficient.abs()),
+            ShotStrategy::Optimal => match term.variance {
+                Some(variance) => Ok(term.coefficient.abs() * variance.max(0.0).sqrt()),
+                None => Err(QuantumError::InvalidArgument {
+                    reason: format!("term {} needs a variance for the optimal strategy", index),
+                }),
+            },
+        })
+        .collect::<Result<_, _>>()?;
+    
+    let mut weight_sum: f64 = weights.iter().sum();
+    if weight_sum <= 0.0 {
+        weights = vec![1.0; terms.len()];
+        weight_sum = terms.len() as f64;
+    }
+    
+    let ideal: Vec<f64> = weights
+        .iter()
+        .map(|w| w / weight_sum * total_shots as f64)
+        .collect();
+    let mut allocation: Vec<usize> = ideal.iter().map(|x| x.floor() as usize).collect();
+    
+    // Hand the leftover shots to the largest fractional remainders
+    let assigned: usize = allocation.iter().sum();
+    let mut order: Vec<usize> = (0..terms.len()).collect();
+    order.sort_by(|&a, &b| {
+        let ra = ideal[a] - ideal[a].floor();
+        let rb = ideal[b] - ideal[b].floor();
+        rb.partial_cmp(&ra).unwrap_or(std::cmp::Ordering::Equal)
+    });
+    for &index in order.iter().cycle().take(total_shots.saturating_sub(assigned)) {
+        allocation[index] += 1;
+    }
+    
+    Ok(allocation)
+}
+
+/// Var(P) = 1 − ⟨P⟩² for a Pauli string on `state`, since P² = I.
+pub fn pauli_variance(state: &QuantumState, paulis: &[(usize, char)]) -> Result<f64, QuantumError> {
+    let mean = state.expectation_pauli_string(paulis)?;
+    Ok((1.0 - mean * mean).max(0.0))
+}